@@ -619,7 +619,7 @@ async fn fetch_repo_data_records_with_progress(
     match tokio::task::spawn_blocking(move || {
         SparseRepoData::new(
             channel,
-            platform.to_string(),
+            platform,
             repo_data_json_path,
             Some(|record: &mut PackageRecord| {
                 if record.name.as_normalized() == "python" {
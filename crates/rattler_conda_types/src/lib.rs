@@ -34,7 +34,7 @@ pub use match_spec::parse::ParseMatchSpecError;
 pub use match_spec::{MatchSpec, NamelessMatchSpec};
 pub use no_arch_type::{NoArchKind, NoArchType};
 pub use package_name::{InvalidPackageNameError, PackageName};
-pub use platform::{Arch, ParseArchError, ParsePlatformError, Platform};
+pub use platform::{Arch, ParseArchError, ParsePlatformError, ParseSubdirError, Platform, Subdir};
 pub use prefix_record::PrefixRecord;
 pub use repo_data::patches::{PackageRecordPatch, PatchInstructions, RepoDataPatch};
 pub use repo_data::{
@@ -421,6 +421,111 @@ impl From<Arch> for &'static str {
     }
 }
 
+/// The validated name of a subdirectory of a channel (e.g. `linux-64` or `noarch`).
+///
+/// Every [`Platform`] is a valid subdir. Other subdirs are accepted as long as they follow the
+/// `<os>-<arch>` naming scheme used by conda, so that channels with platforms that are not yet
+/// known to rattler can still be used.
+#[derive(Debug, Clone, Eq, PartialEq, Hash, PartialOrd, Ord)]
+pub struct Subdir(String);
+
+impl Subdir {
+    /// Returns the string representation of the subdir.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Returns the [`Platform`] this subdir refers to or `None` if it is not a known platform.
+    pub fn platform(&self) -> Option<Platform> {
+        Platform::from_str(&self.0).ok()
+    }
+}
+
+/// An error that can occur when parsing a [`Subdir`] from a string.
+#[derive(Debug, Error, Clone, Eq, PartialEq)]
+#[error("'{string}' is not a valid subdir, expected a platform like 'linux-64' or 'noarch'")]
+pub struct ParseSubdirError {
+    /// The subdir string that could not be parsed.
+    pub string: String,
+}
+
+impl FromStr for Subdir {
+    type Err = ParseSubdirError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let is_valid_part = |part: &str| {
+            !part.is_empty()
+                && part
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+        };
+        let is_valid = Platform::from_str(s).is_ok()
+            || s.split_once('-')
+                .is_some_and(|(os, arch)| is_valid_part(os) && is_valid_part(arch));
+        if is_valid {
+            Ok(Subdir(s.to_owned()))
+        } else {
+            Err(ParseSubdirError {
+                string: s.to_owned(),
+            })
+        }
+    }
+}
+
+impl TryFrom<&str> for Subdir {
+    type Error = ParseSubdirError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl TryFrom<String> for Subdir {
+    type Error = ParseSubdirError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Platform> for Subdir {
+    fn from(platform: Platform) -> Self {
+        Subdir(platform.as_str().to_owned())
+    }
+}
+
+impl AsRef<str> for Subdir {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for Subdir {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl serde::Serialize for Subdir {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&self.0)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Subdir {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
 impl fmt::Display for Arch {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         write!(f, "{}", self.as_str())
@@ -451,6 +556,24 @@ impl<'de> serde::Deserialize<'de> for Arch {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_parse_subdir() {
+        assert_eq!(
+            Subdir::from_str("linux-64").unwrap().platform(),
+            Some(Platform::Linux64)
+        );
+        assert_eq!(
+            Subdir::from_str("noarch").unwrap().platform(),
+            Some(Platform::NoArch)
+        );
+        assert_eq!(Subdir::from_str("zos-z").unwrap().platform(), None);
+        assert_eq!(Subdir::from(Platform::OsxArm64).as_str(), "osx-arm64");
+
+        for invalid in ["linux64", "", "-64", "linux-", "Linux-64", "linux 64"] {
+            assert!(Subdir::from_str(invalid).is_err(), "{invalid}");
+        }
+    }
+
     #[test]
     fn test_parse_platform() {
         assert_eq!("linux-64".parse::<Platform>().unwrap(), Platform::Linux64);
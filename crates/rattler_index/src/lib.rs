@@ -1,32 +1,57 @@
 //! Indexing of packages in a output folder to create up to date repodata.json files
 #![deny(missing_docs)]
 
+use rattler_conda_types::package::AboutJson;
 use rattler_conda_types::package::ArchiveType;
 use rattler_conda_types::package::IndexJson;
 use rattler_conda_types::package::PackageFile;
 use rattler_conda_types::ChannelInfo;
 use rattler_conda_types::PackageRecord;
+use rattler_conda_types::PackageUrl;
+use rattler_conda_types::PatchInstructions;
 use rattler_conda_types::Platform;
 use rattler_conda_types::RepoData;
+use rattler_digest::{HashingReader, Md5, Md5Hash, Sha256, Sha256Hash};
 use rattler_package_streaming::read;
 use rattler_package_streaming::seek;
 
+use flate2::{write::GzEncoder, Compression};
 use fs_err::File;
 use std::ffi::OsStr;
 use std::io::Read;
 use std::io::Write;
 use std::path::Path;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use walkdir::WalkDir;
 
-fn package_record_from_index_json<T: Read>(
+/// The hashes of a package file as computed by [`hash_package_file`].
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct PackageHashes {
+    /// The SHA256 hash of the file
+    pub sha256: Sha256Hash,
+
+    /// The MD5 hash of the file
+    pub md5: Md5Hash,
+}
+
+/// Computes the SHA256 and MD5 hashes of the file at `path`. The file is only read once, both
+/// hashes are computed while streaming its contents.
+pub fn hash_package_file(path: &Path) -> Result<PackageHashes, std::io::Error> {
+    let file = File::open(path)?;
+    let mut reader = HashingReader::<_, Md5>::new(HashingReader::<_, Sha256>::new(file));
+    std::io::copy(&mut reader, &mut std::io::sink())?;
+    let (reader, md5) = reader.finalize();
+    let (_, sha256) = reader.finalize();
+    Ok(PackageHashes { sha256, md5 })
+}
+
+fn package_record_from_index_json(
     file: &Path,
-    index_json_reader: &mut T,
+    index: IndexJson,
 ) -> Result<PackageRecord, std::io::Error> {
-    let index = IndexJson::from_reader(index_json_reader)?;
-
-    let sha256_result = rattler_digest::compute_file_digest::<rattler_digest::Sha256>(file)?;
-    let md5_result = rattler_digest::compute_file_digest::<rattler_digest::Md5>(file)?;
+    let hashes = hash_package_file(file)?;
+    // The size is always taken from the archive on disk, clients use it to validate downloads.
     let size = std::fs::metadata(file)?.len();
 
     let package_record = PackageRecord {
@@ -35,11 +60,12 @@ fn package_record_from_index_json<T: Read>(
         build: index.build,
         build_number: index.build_number,
         subdir: index.subdir.unwrap_or_else(|| "unknown".to_string()),
-        md5: Some(md5_result),
-        sha256: Some(sha256_result),
+        md5: Some(hashes.md5),
+        sha256: Some(hashes.sha256),
         size: Some(size),
         arch: index.arch,
         platform: index.platform,
+        // Copied verbatim, the match specs are not parsed so they cannot be reformatted.
         depends: index.depends,
         constrains: index.constrains,
         track_features: index.track_features,
@@ -55,56 +81,331 @@ fn package_record_from_index_json<T: Read>(
     Ok(package_record)
 }
 
-fn package_record_from_tar_bz2(file: &Path) -> Result<PackageRecord, std::io::Error> {
-    let reader = std::fs::File::open(file)?;
-    let mut archive = read::stream_tar_bz2(reader);
+/// Reads the [`PackageRecord`] of `file` from the `info/index.json` in the given archive. If
+/// `read_about_json` is `true` the metadata from `info/about.json` is folded into the record as
+/// well, see [`IndexOptions::read_about_json`].
+fn package_record_from_archive(
+    file: &Path,
+    mut archive: tar::Archive<impl Read>,
+    read_about_json: bool,
+) -> Result<PackageRecord, std::io::Error> {
+    let mut index_json = None;
+    let mut about_json = None;
     for entry in archive.entries()?.flatten() {
         let mut entry = entry;
         let path = entry.path()?;
         if path.as_os_str().eq("info/index.json") {
-            return package_record_from_index_json(file, &mut entry);
+            index_json = Some(IndexJson::from_reader(&mut entry)?);
+        } else if read_about_json && path.as_os_str().eq("info/about.json") {
+            match AboutJson::from_reader(&mut entry) {
+                Ok(about) => about_json = Some(about),
+                Err(e) => tracing::warn!("Ignoring invalid about.json in {:?}: {}", file, e),
+            }
         }
+
+        if index_json.is_some() && (!read_about_json || about_json.is_some()) {
+            break;
+        }
+    }
+
+    let Some(index_json) = index_json else {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            "No index.json found",
+        ));
+    };
+    let mut record = package_record_from_index_json(file, index_json)?;
+    if let Some(about_json) = about_json {
+        record.license = about_json.license.or(record.license);
+        record.license_family = about_json.license_family.or(record.license_family);
     }
-    Err(std::io::Error::new(
-        std::io::ErrorKind::Other,
-        "No index.json found",
-    ))
+    Ok(record)
 }
 
-fn package_record_from_conda(file: &Path) -> Result<PackageRecord, std::io::Error> {
+fn package_record_from_tar_bz2(
+    file: &Path,
+    read_about_json: bool,
+) -> Result<PackageRecord, std::io::Error> {
     let reader = std::fs::File::open(file)?;
-    let mut archive = seek::stream_conda_info(reader).expect("Could not open conda file");
+    package_record_from_archive(file, read::stream_tar_bz2(reader), read_about_json)
+}
 
-    for entry in archive.entries()?.flatten() {
-        let mut entry = entry;
-        let path = entry.path()?;
-        if path.as_os_str().eq("info/index.json") {
-            return package_record_from_index_json(file, &mut entry);
-        }
+fn package_record_from_conda(
+    file: &Path,
+    read_about_json: bool,
+) -> Result<PackageRecord, std::io::Error> {
+    let reader = std::fs::File::open(file)?;
+    let archive = seek::stream_conda_info(reader).expect("Could not open conda file");
+    package_record_from_archive(file, archive, read_about_json)
+}
+
+/// Options that influence how a channel is indexed with [`index_with_options`].
+#[derive(Debug, Clone, Default)]
+pub struct IndexOptions {
+    /// When `true`, every extracted [`PackageRecord`] is validated before it is added to the
+    /// repodata. A record is considered invalid if its name is empty, its version is empty or its
+    /// `subdir` does not match the directory the package was found in. Invalid packages are not
+    /// added to the repodata, instead their paths and the reason they were rejected are returned in
+    /// [`IndexReport::rejected`].
+    pub validate: bool,
+
+    /// When `Some`, a gzip compressed `repodata.json.gz` is written next to every `repodata.json`
+    /// using the specified compression level (0-9). This is useful for static mirrors that serve
+    /// the compressed file with a matching `Content-Encoding`.
+    ///
+    /// All formats are written to temporary files before any of them is replaced. The plain
+    /// `repodata.json` is then replaced before the compressed variants, so a client that observes
+    /// an updated compressed file is guaranteed to find the same update in the plain file.
+    pub gzip_compression_level: Option<u32>,
+
+    /// When `Some`, the conda style `patch_instructions.json` file at this path is applied to the
+    /// repodata of every indexed subdirectory before it is written. This can be used to correct the
+    /// metadata of already published packages (e.g. fix a wrong dependency) or to remove packages
+    /// from the index. See [`RepoData::apply_patches`] for details.
+    pub patch_instructions: Option<PathBuf>,
+
+    /// When `Some`, the value is written to `info.base_url` of every created `repodata.json`.
+    /// Clients compute the URLs of packages relative to this url which is useful when packages are
+    /// served from a different location than the repodata (e.g. a CDN). When `None`, the
+    /// `base_url` of an existing `repodata.json` is preserved.
+    pub base_url: Option<String>,
+
+    /// When `true`, the `info/about.json` file of every package is read as well and its
+    /// `license` and `license_family` take precedence over the ones from `info/index.json`.
+    /// Packages without an `about.json` keep the values from their `index.json`.
+    ///
+    /// Other fields of the `about.json` (like `home` and `summary`) have no counterpart in a
+    /// [`PackageRecord`] and are therefore not written to the `repodata.json`.
+    pub read_about_json: bool,
+
+    /// When `true`, a package URL following the conda purl convention (e.g.
+    /// `pkg:conda/numpy@1.26.0?build=py311h64a7726_0&subdir=linux-64&type=conda`) is added to the
+    /// `purls` of every record. This is useful for supply-chain tooling like SBOM generators.
+    ///
+    /// The `channel` qualifier is omitted because the location the channel is served from is not
+    /// known while indexing.
+    pub emit_purl: bool,
+
+    /// The maximum number of package files that are read and hashed at the same time. `0` and `1`
+    /// read the packages one after another, which is the default.
+    ///
+    /// Higher values overlap reading and hashing which speeds up indexing large channels on fast
+    /// storage. On spinning disks reading many files at once can be slower than reading them one
+    /// by one.
+    pub max_parallel_reads: usize,
+}
+
+/// The outcome of [`index_with_options`] and [`index_flat_with_options`].
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct IndexReport {
+    /// The packages that were not added to the repodata because they failed validation, see
+    /// [`IndexOptions::validate`].
+    pub rejected: Vec<RejectedPackage>,
+}
+
+/// A package that was excluded from the repodata because it failed validation.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct RejectedPackage {
+    /// The path of the package file.
+    pub path: PathBuf,
+    /// A human readable description of why the package was rejected.
+    pub reason: String,
+}
+
+/// Constructs the conda package URL of `record`, which is stored in a file of the given archive
+/// type. Returns `None` if the fields of the record cannot be represented in a package URL.
+fn conda_purl(record: &PackageRecord, archive_type: ArchiveType) -> Option<PackageUrl> {
+    let archive_type = match archive_type {
+        ArchiveType::TarBz2 => "tar.bz2",
+        ArchiveType::Conda => "conda",
+    };
+    PackageUrl::builder(String::from("conda"), record.name.as_normalized())
+        .with_version(record.version.as_str())
+        .with_qualifier("build", record.build.as_str())
+        .ok()?
+        .with_qualifier("subdir", record.subdir.as_str())
+        .ok()?
+        .with_qualifier("type", archive_type)
+        .ok()?
+        .build()
+        .ok()
+}
+
+/// Reads `info.base_url` from an existing `repodata.json` file. Returns `None` if the file does
+/// not exist, cannot be parsed or does not specify a `base_url`.
+fn read_existing_base_url(repodata_path: &Path) -> Option<String> {
+    let repodata: serde_json::Value =
+        serde_json::from_reader(std::io::BufReader::new(File::open(repodata_path).ok()?)).ok()?;
+    repodata
+        .get("info")?
+        .get("base_url")?
+        .as_str()
+        .map(ToOwned::to_owned)
+}
+
+/// Writes the contents of a file to a temporary file in the same directory as `path` and returns
+/// the path of the temporary file. Renaming the temporary file to `path` afterwards ensures that
+/// readers never observe a partially written file.
+fn write_temp_file(
+    path: &Path,
+    write: impl FnOnce(&mut File) -> Result<(), std::io::Error>,
+) -> Result<PathBuf, std::io::Error> {
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let temp_path = path.with_file_name(format!(".{file_name}.tmp"));
+    let mut file = File::create(&temp_path)?;
+    write(&mut file)?;
+    file.flush()?;
+    Ok(temp_path)
+}
+
+/// Returns true if `file_name` is one of the json files that are written by an indexer (e.g.
+/// `repodata.json.zst` or `channeldata.json`) and should therefore never be treated as a package.
+fn is_index_output(file_name: &str) -> bool {
+    ["repodata", "current_repodata", "run_exports", "channeldata"]
+        .iter()
+        .any(|prefix| file_name.starts_with(prefix))
+        && file_name.contains(".json")
+}
+
+/// Performs basic structural validation of a [`PackageRecord`] that was extracted from a package
+/// found in the `subdir` directory of a channel.
+fn validate_package_record(record: &PackageRecord, subdir: &str) -> Result<(), String> {
+    if record.name.as_source().is_empty() {
+        return Err("the package name is empty".to_string());
+    }
+    if record.version.as_str().is_empty() {
+        return Err("the package version is empty".to_string());
     }
-    Err(std::io::Error::new(
-        std::io::ErrorKind::Other,
-        "No index.json found",
-    ))
+    if record.subdir != subdir {
+        return Err(format!(
+            "the subdir '{}' does not match the directory '{}'",
+            record.subdir, subdir
+        ));
+    }
+    Ok(())
 }
 
 /// Create a new `repodata.json` for all packages in the given output folder. If `target_platform` is
 /// `Some`, only that specific subdir is indexed. Otherwise indexes all subdirs and creates a
 /// `repodata.json` for each.
+///
+/// The output is reproducible: indexing the same packages always yields a byte-identical
+/// `repodata.json`, regardless of the order in which the packages are found on disk or the machine
+/// that performs the indexing. The `packages`, `packages.conda` and `removed` entries are sorted by
+/// filename and all other fields (including those of `info` and of every record) are written in a
+/// fixed order.
+///
+/// The repodata is always rebuilt from the packages that are currently on disk, the contents of an
+/// existing `repodata.json` are never merged into the output (only its `info.base_url` is
+/// preserved, see [`IndexOptions::base_url`]). Records of packages whose file has been deleted are
+/// therefore removed when the channel is re-indexed.
 pub fn index(
     output_folder: &Path,
     target_platform: Option<&Platform>,
 ) -> Result<(), std::io::Error> {
-    let entries = WalkDir::new(output_folder).into_iter();
-    let entries: Vec<(PathBuf, ArchiveType)> = entries
-        .filter_entry(|e| e.depth() <= 2)
-        .filter_map(|e| e.ok())
-        .filter_map(|e| {
-            ArchiveType::split_str(e.path().to_string_lossy().as_ref())
-                .map(|(p, t)| (PathBuf::from(format!("{}{}", p, t.extension())), t))
-        })
-        .collect();
+    index_with_options(output_folder, target_platform, &IndexOptions::default())?;
+    Ok(())
+}
+
+/// Same as [`index`] but applies the conda style `patch_instructions.json` file at
+/// `patch_instructions` to the created repodata. See [`IndexOptions::patch_instructions`].
+pub fn index_with_patches(
+    output_folder: &Path,
+    target_platform: Option<&Platform>,
+    patch_instructions: &Path,
+) -> Result<(), std::io::Error> {
+    index_with_options(
+        output_folder,
+        target_platform,
+        &IndexOptions {
+            patch_instructions: Some(patch_instructions.to_path_buf()),
+            ..Default::default()
+        },
+    )?;
+    Ok(())
+}
 
+/// Same as [`index`] but allows customizing the indexing process through [`IndexOptions`].
+/// Returns an [`IndexReport`] that lists the packages that were rejected.
+pub fn index_with_options(
+    output_folder: &Path,
+    target_platform: Option<&Platform>,
+    options: &IndexOptions,
+) -> Result<IndexReport, std::io::Error> {
+    let patch_instructions = read_patch_instructions(options)?;
+    let entries = find_packages(output_folder, 2);
+
+    let mut report = IndexReport::default();
+    for platform in subdirs_to_index(output_folder, &entries, target_platform) {
+        // Always create the noarch and target platform subdirs
+        fs_err::create_dir_all(output_folder.join(&platform))?;
+
+        let mut rejected = index_subdir(
+            &output_folder.join(&platform),
+            &platform,
+            packages_in_subdir(&entries, &platform),
+            options,
+            patch_instructions.as_ref(),
+        )?;
+        report.rejected.append(&mut rejected);
+    }
+
+    Ok(report)
+}
+
+/// Checks whether the `repodata.json` files in the given output folder are up to date without
+/// writing anything. The repodata that [`index`] would write is computed in memory and compared to
+/// the existing files. Returns `false` if any of the files differs or does not exist yet, the
+/// subdirs that are out of date are logged.
+///
+/// This is useful to verify that committed repodata matches the packages in a channel.
+pub fn index_check(
+    output_folder: &Path,
+    target_platform: Option<&Platform>,
+) -> Result<bool, std::io::Error> {
+    let options = IndexOptions::default();
+    let entries = find_packages(output_folder, 2);
+
+    let mut up_to_date = true;
+    for platform in subdirs_to_index(output_folder, &entries, target_platform) {
+        let out_file = output_folder.join(&platform).join("repodata.json");
+        let (expected, _) = render_repodata(
+            &out_file,
+            &platform,
+            packages_in_subdir(&entries, &platform),
+            &options,
+            None,
+        )?;
+        let existing = match fs_err::read_to_string(&out_file) {
+            Ok(existing) => Some(existing),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+        match existing {
+            None => tracing::info!("{} does not exist", out_file.display()),
+            Some(existing) if existing != expected => {
+                tracing::info!("{} is out of date", out_file.display());
+            }
+            Some(_) => continue,
+        }
+        up_to_date = false;
+    }
+
+    Ok(up_to_date)
+}
+
+/// Determines the subdirs of the output folder that [`index`] writes a `repodata.json` for. These
+/// are all subdirs that contain packages, the noarch subdir and the subdir of the target platform.
+/// If a target platform is given only that subdir and noarch (if it has not been indexed yet) are
+/// returned.
+fn subdirs_to_index(
+    output_folder: &Path,
+    entries: &[(PathBuf, ArchiveType)],
+    target_platform: Option<&Platform>,
+) -> std::collections::HashSet<String> {
     // find all subdirs
     let mut platforms = entries
         .iter()
@@ -122,76 +423,332 @@ pub fn index(
         })
         .collect::<std::collections::HashSet<_>>();
 
-    // Always create noarch subdir
+    // Always index the noarch subdir
     if !output_folder.join("noarch").exists() {
-        std::fs::create_dir(output_folder.join("noarch"))?;
         platforms.insert("noarch".to_string());
     }
 
-    // Create target platform dir if needed
     if let Some(target_platform) = target_platform {
         let platform_str = target_platform.to_string();
         if !output_folder.join(&platform_str).exists() {
-            std::fs::create_dir(output_folder.join(&platform_str))?;
-            platforms.insert(platform_str);
+            platforms.insert(platform_str.clone());
         }
+
+        platforms.retain(|platform| {
+            // only index noarch if it is not the target platform when it is not indexed yet
+            *platform == platform_str
+                || (platform == "noarch" && !output_folder.join("noarch/repodata.json").exists())
+        });
     }
 
-    for platform in platforms {
-        if let Some(target_platform) = target_platform {
-            if platform != target_platform.to_string() {
-                if platform != "noarch" {
-                    continue;
+    platforms
+}
+
+/// Returns the packages in `entries` that are located in the subdir with the given name.
+fn packages_in_subdir<'p>(
+    entries: &'p [(PathBuf, ArchiveType)],
+    platform: &'p str,
+) -> impl Iterator<Item = (&'p PathBuf, &'p ArchiveType)> + 'p {
+    entries.iter().filter_map(move |(p, t)| {
+        p.parent().and_then(|parent| {
+            parent.file_name().and_then(|file_name| {
+                if file_name == OsStr::new(platform) {
+                    // If the file_name is the platform we're looking for, return Some((p, t))
+                    Some((p, t))
                 } else {
-                    // check that noarch is already indexed if it is not the target platform
-                    if output_folder.join("noarch/repodata.json").exists() {
-                        continue;
-                    }
+                    // Otherwise, we return None to filter out this item
+                    None
+                }
+            })
+        })
+    })
+}
+
+/// Create a new `repodata.json` for a flat directory of packages. Unlike [`index`] the packages
+/// are not expected in a subdirectory per platform, instead `dir` itself is treated as the subdir
+/// of `platform`. The `repodata.json` is written to `dir`.
+pub fn index_flat(dir: &Path, platform: &Platform) -> Result<(), std::io::Error> {
+    index_flat_with_options(dir, platform, &IndexOptions::default())?;
+    Ok(())
+}
+
+/// Same as [`index_flat`] but allows customizing the indexing process through [`IndexOptions`].
+/// The options are applied the same way as by [`index_with_options`].
+pub fn index_flat_with_options(
+    dir: &Path,
+    platform: &Platform,
+    options: &IndexOptions,
+) -> Result<IndexReport, std::io::Error> {
+    let patch_instructions = read_patch_instructions(options)?;
+    let entries = find_packages(dir, 1);
+    let rejected = index_subdir(
+        dir,
+        platform.as_str(),
+        entries.iter().map(|(p, t)| (p, t)),
+        options,
+        patch_instructions.as_ref(),
+    )?;
+    Ok(IndexReport { rejected })
+}
+
+/// The outcome of [`merge_repodata`].
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct MergeReport {
+    /// The filenames of the packages that are present in more than one of the inputs, sorted
+    /// alphabetically. The merged file contains the record of the last input that contains the
+    /// package.
+    pub collisions: Vec<String>,
+}
+
+/// Merges multiple `repodata.json` files of the same subdir into a single `repodata.json` at
+/// `output`. This can be used to aggregate the packages of several channels without downloading
+/// the packages themselves.
+///
+/// The `packages`, `packages.conda` and `removed` entries of all inputs are combined. If a
+/// filename is present in more than one input the record of the later input is used and the
+/// filename is reported in [`MergeReport::collisions`]. The `info` of the last input that has one
+/// is written to the output.
+pub fn merge_repodata(inputs: &[&Path], output: &Path) -> Result<MergeReport, std::io::Error> {
+    let mut merged = RepoData {
+        info: None,
+        packages: Default::default(),
+        conda_packages: Default::default(),
+        removed: Default::default(),
+        version: None,
+    };
+    let mut collisions = std::collections::BTreeSet::new();
+
+    for input in inputs {
+        let repodata = RepoData::from_path(input)?;
+        for (packages, merged_packages) in [
+            (repodata.packages, &mut merged.packages),
+            (repodata.conda_packages, &mut merged.conda_packages),
+        ] {
+            for (file_name, record) in packages {
+                if merged_packages.insert(file_name.clone(), record).is_some() {
+                    collisions.insert(file_name);
                 }
             }
         }
+        merged.removed.extend(repodata.removed);
+        merged.info = repodata.info.or(merged.info);
+        merged.version = merged.version.max(repodata.version);
+    }
 
-        let mut repodata = RepoData {
-            info: Some(ChannelInfo {
-                subdir: platform.clone(),
-                base_url: None,
-            }),
-            packages: Default::default(),
-            conda_packages: Default::default(),
-            removed: Default::default(),
-            version: Some(2),
-        };
+    let repodata_json = serde_json::to_string_pretty(&merged)?;
+    let temp_path = write_temp_file(output, |file| file.write_all(repodata_json.as_bytes()))?;
+    fs_err::rename(&temp_path, output)?;
 
-        for (p, t) in entries.iter().filter_map(|(p, t)| {
-            p.parent().and_then(|parent| {
-                parent.file_name().and_then(|file_name| {
-                    if file_name == OsStr::new(&platform) {
-                        // If the file_name is the platform we're looking for, return Some((p, t))
-                        Some((p, t))
-                    } else {
-                        // Otherwise, we return None to filter out this item
-                        None
+    Ok(MergeReport {
+        collisions: collisions.into_iter().collect(),
+    })
+}
+
+/// Reads the patch instructions referenced by [`IndexOptions::patch_instructions`] if any.
+fn read_patch_instructions(
+    options: &IndexOptions,
+) -> Result<Option<PatchInstructions>, std::io::Error> {
+    options
+        .patch_instructions
+        .as_deref()
+        .map(|path| -> Result<_, std::io::Error> {
+            Ok(serde_json::from_reader(std::io::BufReader::new(
+                File::open(path)?,
+            ))?)
+        })
+        .transpose()
+}
+
+/// Calls `f` for every item in `items` and returns the results in the same order. At most
+/// `max_parallel` items are processed at the same time, each on its own scoped thread. If
+/// `max_parallel` is `0` or `1` the items are processed on the current thread.
+fn map_bounded<T: Sync, R: Send>(
+    items: &[T],
+    max_parallel: usize,
+    f: impl Fn(&T) -> R + Sync,
+) -> Vec<R> {
+    let workers = max_parallel.min(items.len());
+    if workers <= 1 {
+        return items.iter().map(f).collect();
+    }
+
+    // Every worker takes the next unprocessed item until all items are processed.
+    let next = AtomicUsize::new(0);
+    let mut results = std::thread::scope(|scope| {
+        let workers = (0..workers)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut results = Vec::new();
+                    loop {
+                        let index = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(item) = items.get(index) else {
+                            break;
+                        };
+                        results.push((index, f(item)));
                     }
+                    results
                 })
             })
-        }) {
-            let record = match t {
-                ArchiveType::TarBz2 => package_record_from_tar_bz2(p),
-                ArchiveType::Conda => package_record_from_conda(p),
-            };
-            let (Ok(record), Some(file_name)) = (record, p.file_name()) else {
-                tracing::info!("Could not read package record from {:?}", p);
+            .collect::<Vec<_>>();
+        workers
+            .into_iter()
+            .flat_map(|worker| match worker.join() {
+                Ok(results) => results,
+                Err(panic) => std::panic::resume_unwind(panic),
+            })
+            .collect::<Vec<_>>()
+    });
+    results.sort_by_key(|(index, _)| *index);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+/// Finds all packages in `dir` that are at most `max_depth` levels deep.
+fn find_packages(dir: &Path, max_depth: usize) -> Vec<(PathBuf, ArchiveType)> {
+    WalkDir::new(dir)
+        .into_iter()
+        .filter_entry(|e| e.depth() <= max_depth)
+        .filter_map(|e| e.ok())
+        // Outputs of a previous run are not packages, even if their name looks like one
+        .filter(|e| !is_index_output(&e.file_name().to_string_lossy()))
+        .filter_map(|e| {
+            ArchiveType::split_str(e.path().to_string_lossy().as_ref())
+                .map(|(p, t)| (PathBuf::from(format!("{}{}", p, t.extension())), t))
+        })
+        .collect()
+}
+
+/// Writes the `repodata.json` for the `packages` of a single `subdir` to `subdir_dir`. Returns the
+/// packages that were rejected.
+fn index_subdir<'p>(
+    subdir_dir: &Path,
+    subdir: &str,
+    packages: impl Iterator<Item = (&'p PathBuf, &'p ArchiveType)>,
+    options: &IndexOptions,
+    patch_instructions: Option<&PatchInstructions>,
+) -> Result<Vec<RejectedPackage>, std::io::Error> {
+    let out_file = subdir_dir.join("repodata.json");
+    let (repodata_json, rejected) =
+        render_repodata(&out_file, subdir, packages, options, patch_instructions)?;
+
+    // All formats are first written to temporary files and only then moved into place. The plain
+    // `repodata.json` is replaced first and the compressed variants last, see
+    // `IndexOptions::gzip_compression_level`.
+    let mut outputs = vec![(
+        out_file.clone(),
+        write_temp_file(&out_file, |file| file.write_all(repodata_json.as_bytes()))?,
+    )];
+
+    if let Some(level) = options.gzip_compression_level {
+        let gz_file = out_file.with_extension("json.gz");
+        let temp_path = write_temp_file(&gz_file, |file| {
+            let mut encoder = GzEncoder::new(file, Compression::new(level));
+            encoder.write_all(repodata_json.as_bytes())?;
+            encoder.finish()?;
+            Ok(())
+        })?;
+        outputs.push((gz_file, temp_path));
+    }
+
+    for (path, temp_path) in outputs {
+        fs_err::rename(&temp_path, &path)?;
+    }
+
+    Ok(rejected)
+}
+
+/// Computes the contents of the `repodata.json` at `out_file` for the `packages` of a single
+/// `subdir`. Also returns the packages that were rejected by validation.
+fn render_repodata<'p>(
+    out_file: &Path,
+    subdir: &str,
+    packages: impl Iterator<Item = (&'p PathBuf, &'p ArchiveType)>,
+    options: &IndexOptions,
+    patch_instructions: Option<&PatchInstructions>,
+) -> Result<(String, Vec<RejectedPackage>), std::io::Error> {
+    let base_url = options
+        .base_url
+        .clone()
+        .or_else(|| read_existing_base_url(out_file));
+
+    let mut repodata = RepoData {
+        info: Some(ChannelInfo {
+            subdir: subdir.to_owned(),
+            base_url,
+        }),
+        packages: Default::default(),
+        conda_packages: Default::default(),
+        removed: Default::default(),
+        version: Some(2),
+    };
+
+    // Reading and hashing the packages is the expensive part, it is done up front so it can be
+    // spread over multiple threads, see `IndexOptions::max_parallel_reads`.
+    let packages = packages.collect::<Vec<_>>();
+    let records = map_bounded(&packages, options.max_parallel_reads, |(p, t)| match t {
+        ArchiveType::TarBz2 => package_record_from_tar_bz2(p, options.read_about_json),
+        ArchiveType::Conda => package_record_from_conda(p, options.read_about_json),
+    });
+
+    let mut rejected = Vec::new();
+    for ((p, t), record) in packages.into_iter().zip(records) {
+        let (Ok(mut record), Some(file_name)) = (record, p.file_name()) else {
+            tracing::info!("Could not read package record from {:?}", p);
+            continue;
+        };
+        if options.emit_purl {
+            match conda_purl(&record, *t) {
+                Some(purl) => record.purls.push(purl),
+                None => tracing::warn!("Could not construct a package URL for {:?}", p),
+            }
+        }
+        if options.validate {
+            if let Err(reason) = validate_package_record(&record, subdir) {
+                tracing::warn!("Excluding invalid package {:?}: {}", p, reason);
+                rejected.push(RejectedPackage {
+                    path: p.clone(),
+                    reason,
+                });
                 continue;
-            };
-            repodata
-                .conda_packages
-                .insert(file_name.to_string_lossy().to_string(), record);
+            }
         }
-        let out_file = output_folder.join(platform).join("repodata.json");
-        File::create(&out_file)?.write_all(serde_json::to_string_pretty(&repodata)?.as_bytes())?;
+        repodata
+            .conda_packages
+            .insert(file_name.to_string_lossy().to_string(), record);
+    }
+    if let Some(patch_instructions) = patch_instructions {
+        repodata.apply_patches(patch_instructions);
     }
 
-    Ok(())
+    Ok((serde_json::to_string_pretty(&repodata)?, rejected))
 }
 
 // TODO: write proper unit tests for above functions
+
+#[cfg(test)]
+mod test {
+    use super::map_bounded;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[test]
+    fn test_map_bounded() {
+        let items = (0..16).collect::<Vec<_>>();
+        for max_parallel in [0, 1, 3] {
+            let running = AtomicUsize::new(0);
+            let max_running = AtomicUsize::new(0);
+            let results = map_bounded(&items, max_parallel, |item| {
+                let now_running = running.fetch_add(1, Ordering::SeqCst) + 1;
+                max_running.fetch_max(now_running, Ordering::SeqCst);
+                std::thread::sleep(Duration::from_millis(5));
+                running.fetch_sub(1, Ordering::SeqCst);
+                item * 2
+            });
+
+            assert_eq!(
+                results,
+                items.iter().map(|item| item * 2).collect::<Vec<_>>()
+            );
+            assert!(max_running.load(Ordering::SeqCst) <= max_parallel.max(1));
+        }
+    }
+}
@@ -1,14 +1,51 @@
 use rattler_conda_types::Platform;
-use rattler_index::index;
+use rattler_index::{
+    hash_package_file, index, index_check, index_flat, index_flat_with_options, index_with_options,
+    index_with_patches, merge_repodata, IndexOptions,
+};
+use rattler_package_streaming::write::{write_conda_package, CompressionLevel};
 use serde_json::Value;
 use std::fs;
 use std::fs::File;
+use std::io::Read;
 use std::path::{Path, PathBuf};
 
 fn test_data_dir() -> PathBuf {
     Path::new(env!("CARGO_MANIFEST_DIR")).join("../../test-data")
 }
 
+/// Writes a minimal `.conda` package with the given `index.json` contents to `path`.
+fn write_package(path: &Path, index_json: &str) {
+    write_package_with_files(path, &[("info/index.json", index_json)]);
+}
+
+/// Writes a `.conda` package that contains the given files (relative path and contents) to `path`.
+fn write_package_with_files(path: &Path, files: &[(&str, &str)]) {
+    let package_dir = tempfile::tempdir().unwrap();
+    let paths = files
+        .iter()
+        .map(|(relative_path, contents)| {
+            let file_path = package_dir.path().join(relative_path);
+            fs::create_dir_all(file_path.parent().unwrap()).unwrap();
+            fs::write(&file_path, contents).unwrap();
+            file_path
+        })
+        .collect::<Vec<_>>();
+
+    write_conda_package(
+        File::create(path).unwrap(),
+        package_dir.path(),
+        &paths,
+        CompressionLevel::Default,
+        path.file_name()
+            .unwrap()
+            .to_string_lossy()
+            .trim_end_matches(".conda"),
+        None,
+    )
+    .unwrap();
+}
+
 #[test]
 fn test_index() {
     let temp_dir = tempfile::tempdir().unwrap();
@@ -59,3 +96,637 @@ fn test_index_empty_directory() {
     assert_eq!(res.is_ok(), true);
     assert_eq!(fs::read_dir(temp_dir).unwrap().count(), 0);
 }
+
+#[test]
+fn test_index_validate() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let subdir = temp_dir.path().join("linux-64");
+    fs::create_dir(&subdir).unwrap();
+
+    write_package(
+        &subdir.join("valid-1.0-0.conda"),
+        r#"{"name": "valid", "version": "1.0", "build": "0", "build_number": 0, "subdir": "linux-64"}"#,
+    );
+    write_package(
+        &subdir.join("-1.0-0.conda"),
+        r#"{"name": "", "version": "1.0", "build": "0", "build_number": 0, "subdir": "linux-64"}"#,
+    );
+    write_package(
+        &subdir.join("wrong-1.0-0.conda"),
+        r#"{"name": "wrong", "version": "1.0", "build": "0", "build_number": 0, "subdir": "osx-64"}"#,
+    );
+
+    // Without validation all packages end up in the repodata.
+    index(temp_dir.path(), Some(&Platform::Linux64)).unwrap();
+    let repodata_json: Value =
+        serde_json::from_reader(File::open(subdir.join("repodata.json")).unwrap()).unwrap();
+    assert_eq!(
+        repodata_json
+            .get("packages.conda")
+            .unwrap()
+            .as_object()
+            .unwrap()
+            .len(),
+        3
+    );
+
+    // With validation the invalid packages are excluded and reported to the caller.
+    let report = index_with_options(
+        temp_dir.path(),
+        Some(&Platform::Linux64),
+        &IndexOptions {
+            validate: true,
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    let repodata_json: Value =
+        serde_json::from_reader(File::open(subdir.join("repodata.json")).unwrap()).unwrap();
+    let packages = repodata_json
+        .get("packages.conda")
+        .unwrap()
+        .as_object()
+        .unwrap();
+    assert_eq!(packages.len(), 1);
+    assert!(packages.contains_key("valid-1.0-0.conda"));
+
+    // The `removed` set of the repodata is not used to record the rejected packages.
+    assert!(repodata_json.get("removed").is_none());
+
+    let mut rejected = report
+        .rejected
+        .iter()
+        .map(|package| package.path.clone())
+        .collect::<Vec<_>>();
+    rejected.sort();
+    assert_eq!(
+        rejected,
+        vec![
+            subdir.join("-1.0-0.conda"),
+            subdir.join("wrong-1.0-0.conda")
+        ]
+    );
+    assert!(report
+        .rejected
+        .iter()
+        .all(|package| !package.reason.is_empty()));
+}
+
+#[test]
+fn test_index_gzip() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let subdir = temp_dir.path().join("linux-64");
+    fs::create_dir(&subdir).unwrap();
+    write_package(
+        &subdir.join("foo-1.0-0.conda"),
+        r#"{"name": "foo", "version": "1.0", "build": "0", "build_number": 0, "subdir": "linux-64"}"#,
+    );
+
+    index_with_options(
+        temp_dir.path(),
+        Some(&Platform::Linux64),
+        &IndexOptions {
+            gzip_compression_level: Some(9),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+
+    let plain = fs::read(subdir.join("repodata.json")).unwrap();
+    let mut decompressed = Vec::new();
+    flate2::read::GzDecoder::new(File::open(subdir.join("repodata.json.gz")).unwrap())
+        .read_to_end(&mut decompressed)
+        .unwrap();
+    assert_eq!(plain, decompressed);
+
+    // No temporary files should be left behind
+    assert_eq!(
+        fs::read_dir(&subdir)
+            .unwrap()
+            .filter(|entry| entry
+                .as_ref()
+                .unwrap()
+                .file_name()
+                .to_string_lossy()
+                .ends_with(".tmp"))
+            .count(),
+        0
+    );
+}
+
+#[test]
+fn test_index_with_patches() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let subdir = temp_dir.path().join("linux-64");
+    fs::create_dir(&subdir).unwrap();
+    write_package(
+        &subdir.join("foo-1.0-0.conda"),
+        r#"{"name": "foo", "version": "1.0", "build": "0", "build_number": 0, "depends": ["bar >=1"], "subdir": "linux-64"}"#,
+    );
+    write_package(
+        &subdir.join("bar-1.0-0.conda"),
+        r#"{"name": "bar", "version": "1.0", "build": "0", "build_number": 0, "subdir": "linux-64"}"#,
+    );
+
+    let patch_instructions = temp_dir.path().join("patch_instructions.json");
+    fs::write(
+        &patch_instructions,
+        r#"{
+            "packages.conda": { "foo-1.0-0.conda": { "depends": ["bar >=1,<2"] } },
+            "remove": ["bar-1.0-0.conda"]
+        }"#,
+    )
+    .unwrap();
+
+    index_with_patches(
+        temp_dir.path(),
+        Some(&Platform::Linux64),
+        &patch_instructions,
+    )
+    .unwrap();
+
+    let repodata_json: Value =
+        serde_json::from_reader(File::open(subdir.join("repodata.json")).unwrap()).unwrap();
+    let packages = repodata_json
+        .get("packages.conda")
+        .unwrap()
+        .as_object()
+        .unwrap();
+    assert_eq!(packages.len(), 1);
+    assert_eq!(
+        packages["foo-1.0-0.conda"]["depends"],
+        Value::from(vec!["bar >=1,<2"])
+    );
+    assert_eq!(
+        repodata_json.get("removed").unwrap(),
+        &Value::from(vec!["bar-1.0-0.conda"])
+    );
+}
+
+#[test]
+fn test_index_base_url() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let subdir = temp_dir.path().join("linux-64");
+    fs::create_dir(&subdir).unwrap();
+    write_package(
+        &subdir.join("foo-1.0-0.conda"),
+        r#"{"name": "foo", "version": "1.0", "build": "0", "build_number": 0, "subdir": "linux-64"}"#,
+    );
+    let read_base_url = || {
+        let repodata_json: Value =
+            serde_json::from_reader(File::open(subdir.join("repodata.json")).unwrap()).unwrap();
+        repodata_json["info"].get("base_url").cloned()
+    };
+
+    index_with_options(
+        temp_dir.path(),
+        Some(&Platform::Linux64),
+        &IndexOptions {
+            base_url: Some("https://cdn.example.com/channel/linux-64/".to_string()),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    assert_eq!(
+        read_base_url(),
+        Some(Value::from("https://cdn.example.com/channel/linux-64/"))
+    );
+
+    // Re-indexing without a base_url preserves the previously set value
+    index(temp_dir.path(), Some(&Platform::Linux64)).unwrap();
+    assert_eq!(
+        read_base_url(),
+        Some(Value::from("https://cdn.example.com/channel/linux-64/"))
+    );
+}
+
+#[test]
+fn test_index_reproducible() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let subdir = temp_dir.path().join("linux-64");
+    fs::create_dir(&subdir).unwrap();
+    let file_names = ["foo-1.0-0.conda", "bar-2.0-0.conda", "baz-3.0-0.conda"];
+    for file_name in file_names {
+        let (name, rest) = file_name.split_once('-').unwrap();
+        let version = rest.split_once('-').unwrap().0;
+        write_package(
+            &subdir.join(file_name),
+            &format!(
+                r#"{{"name": "{name}", "version": "{version}", "build": "0", "build_number": 0, "depends": ["python >=3.8", "numpy"], "subdir": "linux-64"}}"#
+            ),
+        );
+    }
+
+    index(temp_dir.path(), Some(&Platform::Linux64)).unwrap();
+    let first = fs::read(subdir.join("repodata.json")).unwrap();
+    index(temp_dir.path(), Some(&Platform::Linux64)).unwrap();
+    assert_eq!(first, fs::read(subdir.join("repodata.json")).unwrap());
+
+    // Copy the same packages to another channel in a different order
+    let other_dir = tempfile::tempdir().unwrap();
+    let other_subdir = other_dir.path().join("linux-64");
+    fs::create_dir(&other_subdir).unwrap();
+    for file_name in file_names.iter().rev() {
+        fs::copy(subdir.join(file_name), other_subdir.join(file_name)).unwrap();
+    }
+    index(other_dir.path(), Some(&Platform::Linux64)).unwrap();
+    assert_eq!(first, fs::read(other_subdir.join("repodata.json")).unwrap());
+}
+
+#[test]
+fn test_index_read_about_json() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let subdir = temp_dir.path().join("linux-64");
+    fs::create_dir(&subdir).unwrap();
+    write_package_with_files(
+        &subdir.join("foo-1.0-0.conda"),
+        &[
+            (
+                "info/index.json",
+                r#"{"name": "foo", "version": "1.0", "build": "0", "build_number": 0, "subdir": "linux-64"}"#,
+            ),
+            (
+                "info/about.json",
+                r#"{"license": "BSD-3-Clause", "home": "https://example.com", "summary": "Foo"}"#,
+            ),
+        ],
+    );
+    write_package(
+        &subdir.join("bar-1.0-0.conda"),
+        r#"{"name": "bar", "version": "1.0", "build": "0", "build_number": 0, "subdir": "linux-64"}"#,
+    );
+
+    index_with_options(
+        temp_dir.path(),
+        Some(&Platform::Linux64),
+        &IndexOptions {
+            read_about_json: true,
+            ..Default::default()
+        },
+    )
+    .unwrap();
+
+    let repodata_json: Value =
+        serde_json::from_reader(File::open(subdir.join("repodata.json")).unwrap()).unwrap();
+    let packages = &repodata_json["packages.conda"];
+    assert_eq!(
+        packages["foo-1.0-0.conda"]["license"],
+        Value::from("BSD-3-Clause")
+    );
+    assert!(packages["bar-1.0-0.conda"].get("license").is_none());
+}
+
+#[test]
+fn test_index_ignores_previous_outputs() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let subdir = temp_dir.path().join("linux-64");
+    fs::create_dir(&subdir).unwrap();
+    write_package(
+        &subdir.join("foo-1.0-0.conda"),
+        r#"{"name": "foo", "version": "1.0", "build": "0", "build_number": 0, "subdir": "linux-64"}"#,
+    );
+    fs::write(subdir.join("repodata.json"), "{}").unwrap();
+    fs::write(subdir.join("repodata.json.zst"), "not zstd").unwrap();
+    // Outputs whose name happens to end with a package extension are ignored as well
+    fs::write(subdir.join("current_repodata.json.conda"), "not conda").unwrap();
+
+    index(temp_dir.path(), Some(&Platform::Linux64)).unwrap();
+
+    let repodata_json: Value =
+        serde_json::from_reader(File::open(subdir.join("repodata.json")).unwrap()).unwrap();
+    let packages = repodata_json
+        .get("packages.conda")
+        .unwrap()
+        .as_object()
+        .unwrap();
+    assert_eq!(packages.keys().collect::<Vec<_>>(), vec!["foo-1.0-0.conda"]);
+    assert!(repodata_json["packages"].as_object().unwrap().is_empty());
+}
+
+#[test]
+fn test_index_emit_purl() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let subdir = temp_dir.path().join("linux-64");
+    fs::create_dir(&subdir).unwrap();
+    write_package(
+        &subdir.join("numpy-1.26.0-py311h64a7726_0.conda"),
+        r#"{"name": "numpy", "version": "1.26.0", "build": "py311h64a7726_0", "build_number": 0, "subdir": "linux-64"}"#,
+    );
+
+    index_with_options(
+        temp_dir.path(),
+        Some(&Platform::Linux64),
+        &IndexOptions {
+            emit_purl: true,
+            ..Default::default()
+        },
+    )
+    .unwrap();
+
+    let repodata_json: Value =
+        serde_json::from_reader(File::open(subdir.join("repodata.json")).unwrap()).unwrap();
+    assert_eq!(
+        repodata_json["packages.conda"]["numpy-1.26.0-py311h64a7726_0.conda"]["purls"],
+        Value::from(vec![
+            "pkg:conda/numpy@1.26.0?build=py311h64a7726_0&subdir=linux-64&type=conda"
+        ])
+    );
+}
+
+#[test]
+fn test_index_size() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let subdir = temp_dir.path().join("linux-64");
+    fs::create_dir(&subdir).unwrap();
+    let package_path = subdir.join("foo-1.0-0.conda");
+    // A `size` in the `index.json` must not end up in the repodata
+    write_package(
+        &package_path,
+        r#"{"name": "foo", "version": "1.0", "build": "0", "build_number": 0, "size": 1, "subdir": "linux-64"}"#,
+    );
+
+    index(temp_dir.path(), Some(&Platform::Linux64)).unwrap();
+
+    let repodata_json: Value =
+        serde_json::from_reader(File::open(subdir.join("repodata.json")).unwrap()).unwrap();
+    assert_eq!(
+        repodata_json["packages.conda"]["foo-1.0-0.conda"]["size"],
+        Value::from(fs::metadata(&package_path).unwrap().len())
+    );
+}
+
+#[test]
+fn test_index_depends_verbatim() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let subdir = temp_dir.path().join("linux-64");
+    fs::create_dir(&subdir).unwrap();
+    let index_json = r#"{"name": "foo", "version": "1.0", "build": "0", "build_number": 0, "depends": ["zlib", "python  >=3.8,<4", "bar 1.0.*", "bar 1.0.*"], "constrains": ["qux >=2", "baz"], "subdir": "linux-64"}"#;
+    write_package(&subdir.join("foo-1.0-0.conda"), index_json);
+
+    index(temp_dir.path(), Some(&Platform::Linux64)).unwrap();
+
+    let index_json: Value = serde_json::from_str(index_json).unwrap();
+    let repodata_json: Value =
+        serde_json::from_reader(File::open(subdir.join("repodata.json")).unwrap()).unwrap();
+    let entry = &repodata_json["packages.conda"]["foo-1.0-0.conda"];
+    assert_eq!(entry["depends"], index_json["depends"]);
+    assert_eq!(entry["constrains"], index_json["constrains"]);
+}
+
+#[test]
+fn test_index_removes_deleted_packages() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let subdir = temp_dir.path().join("linux-64");
+    fs::create_dir(&subdir).unwrap();
+    write_package(
+        &subdir.join("foo-1.0-0.conda"),
+        r#"{"name": "foo", "version": "1.0", "build": "0", "build_number": 0, "subdir": "linux-64"}"#,
+    );
+    write_package(
+        &subdir.join("bar-1.0-0.conda"),
+        r#"{"name": "bar", "version": "1.0", "build": "0", "build_number": 0, "subdir": "linux-64"}"#,
+    );
+
+    index(temp_dir.path(), Some(&Platform::Linux64)).unwrap();
+    fs::remove_file(subdir.join("foo-1.0-0.conda")).unwrap();
+    index(temp_dir.path(), Some(&Platform::Linux64)).unwrap();
+
+    let repodata_json: Value =
+        serde_json::from_reader(File::open(subdir.join("repodata.json")).unwrap()).unwrap();
+    let packages = repodata_json["packages.conda"].as_object().unwrap();
+    assert!(!packages.contains_key("foo-1.0-0.conda"));
+    assert!(packages.contains_key("bar-1.0-0.conda"));
+}
+
+#[test]
+fn test_merge_repodata() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let record = |name: &str, build_number: u64| {
+        serde_json::json!({
+            "name": name,
+            "version": "1.0",
+            "build": "0",
+            "build_number": build_number,
+            "depends": [],
+            "subdir": "linux-64",
+        })
+    };
+    let first = temp_dir.path().join("first.json");
+    fs::write(
+        &first,
+        serde_json::json!({
+            "info": {"subdir": "linux-64"},
+            "packages": {},
+            "packages.conda": {
+                "foo-1.0-0.conda": record("foo", 1),
+                "bar-1.0-0.conda": record("bar", 1),
+            },
+        })
+        .to_string(),
+    )
+    .unwrap();
+    let second = temp_dir.path().join("second.json");
+    fs::write(
+        &second,
+        serde_json::json!({
+            "info": {"subdir": "linux-64"},
+            "packages": {},
+            "packages.conda": {
+                "foo-1.0-0.conda": record("foo", 2),
+                "baz-1.0-0.conda": record("baz", 2),
+            },
+        })
+        .to_string(),
+    )
+    .unwrap();
+
+    let output = temp_dir.path().join("repodata.json");
+    let report = merge_repodata(&[&first, &second], &output).unwrap();
+    assert_eq!(report.collisions, vec![String::from("foo-1.0-0.conda")]);
+
+    let repodata_json: Value = serde_json::from_reader(File::open(&output).unwrap()).unwrap();
+    let packages = repodata_json["packages.conda"].as_object().unwrap();
+    assert_eq!(
+        packages.keys().collect::<Vec<_>>(),
+        ["bar-1.0-0.conda", "baz-1.0-0.conda", "foo-1.0-0.conda"]
+    );
+    // The record of the later input wins
+    assert_eq!(packages["foo-1.0-0.conda"]["build_number"], Value::from(2));
+    assert_eq!(repodata_json["info"]["subdir"], Value::from("linux-64"));
+}
+
+#[test]
+fn test_index_max_parallel_reads() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let subdir = temp_dir.path().join("linux-64");
+    fs::create_dir(&subdir).unwrap();
+    for name in ["a", "b", "c", "d", "e"] {
+        write_package(
+            &subdir.join(format!("{name}-1.0-0.conda")),
+            &format!(
+                r#"{{"name": "{name}", "version": "1.0", "build": "0", "build_number": 0, "subdir": "linux-64"}}"#
+            ),
+        );
+    }
+
+    index(temp_dir.path(), Some(&Platform::Linux64)).unwrap();
+    let sequential = fs::read_to_string(subdir.join("repodata.json")).unwrap();
+
+    for max_parallel_reads in [1, 3] {
+        index_with_options(
+            temp_dir.path(),
+            Some(&Platform::Linux64),
+            &IndexOptions {
+                max_parallel_reads,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            fs::read_to_string(subdir.join("repodata.json")).unwrap(),
+            sequential
+        );
+    }
+    let repodata_json: Value = serde_json::from_str(&sequential).unwrap();
+    assert_eq!(
+        repodata_json["packages.conda"].as_object().unwrap().len(),
+        5
+    );
+}
+
+#[test]
+fn test_hash_package_file() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let file_path = temp_dir.path().join("hello.txt");
+    fs::write(&file_path, "hello world").unwrap();
+
+    let hashes = hash_package_file(&file_path).unwrap();
+    assert_eq!(
+        format!("{:x}", hashes.sha256),
+        "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9"
+    );
+    assert_eq!(
+        format!("{:x}", hashes.md5),
+        "5eb63bbbe01eeed093cb22bb8f5acdc3"
+    );
+
+    // The hashes in the index are computed with the same function
+    let subdir = temp_dir.path().join("noarch");
+    fs::create_dir_all(&subdir).unwrap();
+    let package_path = subdir.join("foo-1.0-0.conda");
+    write_package(
+        &package_path,
+        r#"{"name": "foo", "version": "1.0", "build": "0", "build_number": 0, "subdir": "noarch"}"#,
+    );
+    index(temp_dir.path(), Some(&Platform::NoArch)).unwrap();
+
+    let repodata: Value =
+        serde_json::from_slice(&fs::read(subdir.join("repodata.json")).unwrap()).unwrap();
+    let record = &repodata["packages.conda"]["foo-1.0-0.conda"];
+    let hashes = hash_package_file(&package_path).unwrap();
+    assert_eq!(record["sha256"], format!("{:x}", hashes.sha256));
+    assert_eq!(record["md5"], format!("{:x}", hashes.md5));
+}
+
+#[test]
+fn test_index_flat() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    write_package(
+        &temp_dir.path().join("foo-1.0-0.conda"),
+        r#"{"name": "foo", "version": "1.0", "build": "0", "build_number": 0, "subdir": "win-64"}"#,
+    );
+
+    index_flat(temp_dir.path(), &Platform::Win64).unwrap();
+
+    let repodata: Value =
+        serde_json::from_slice(&fs::read(temp_dir.path().join("repodata.json")).unwrap()).unwrap();
+    assert_eq!(repodata["info"]["subdir"], "win-64");
+    assert_eq!(
+        repodata["packages.conda"]["foo-1.0-0.conda"]["name"],
+        Value::from("foo")
+    );
+
+    // No platform subdirectories are created
+    assert!(!temp_dir.path().join("noarch").exists());
+    assert!(!temp_dir.path().join("win-64").exists());
+
+    // Options are applied to flat directories as well
+    index_flat_with_options(
+        temp_dir.path(),
+        &Platform::Win64,
+        &IndexOptions {
+            base_url: Some("https://cdn.example.com/channel/win-64/".to_string()),
+            gzip_compression_level: Some(9),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    let repodata: Value =
+        serde_json::from_slice(&fs::read(temp_dir.path().join("repodata.json")).unwrap()).unwrap();
+    assert_eq!(
+        repodata["info"]["base_url"],
+        "https://cdn.example.com/channel/win-64/"
+    );
+    assert!(temp_dir.path().join("repodata.json.gz").exists());
+}
+
+#[test]
+fn test_index_gzip_reindex() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let subdir = temp_dir.path().join("linux-64");
+    fs::create_dir(&subdir).unwrap();
+    let options = IndexOptions {
+        gzip_compression_level: Some(9),
+        ..Default::default()
+    };
+
+    let mut previous = Vec::new();
+    for name in ["foo", "bar"] {
+        write_package(
+            &subdir.join(format!("{name}-1.0-0.conda")),
+            &format!(
+                r#"{{"name": "{name}", "version": "1.0", "build": "0", "build_number": 0, "subdir": "linux-64"}}"#
+            ),
+        );
+        index_with_options(temp_dir.path(), Some(&Platform::Linux64), &options).unwrap();
+
+        // Both formats must describe the same update
+        let plain = fs::read(subdir.join("repodata.json")).unwrap();
+        let mut decompressed = Vec::new();
+        flate2::read::GzDecoder::new(File::open(subdir.join("repodata.json.gz")).unwrap())
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(plain, decompressed);
+        assert_ne!(plain, previous);
+        previous = plain;
+    }
+}
+
+#[test]
+fn test_index_check() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let subdir = temp_dir.path().join("linux-64");
+    fs::create_dir(&subdir).unwrap();
+    let package_path = subdir.join("foo-1.0-0.conda");
+    write_package(
+        &package_path,
+        r#"{"name": "foo", "version": "1.0", "build": "0", "build_number": 0, "subdir": "linux-64"}"#,
+    );
+
+    // Nothing has been indexed yet
+    assert!(!index_check(temp_dir.path(), Some(&Platform::Linux64)).unwrap());
+    assert!(!subdir.join("repodata.json").exists());
+
+    index(temp_dir.path(), Some(&Platform::Linux64)).unwrap();
+    assert!(index_check(temp_dir.path(), Some(&Platform::Linux64)).unwrap());
+    assert!(index_check(temp_dir.path(), None).unwrap());
+
+    // Modify the package, the repodata is now out of date
+    let repodata = fs::read(subdir.join("repodata.json")).unwrap();
+    write_package(
+        &package_path,
+        r#"{"name": "foo", "version": "1.0", "build": "0", "build_number": 0, "depends": ["bar"], "subdir": "linux-64"}"#,
+    );
+    assert!(!index_check(temp_dir.path(), Some(&Platform::Linux64)).unwrap());
+    assert_eq!(fs::read(subdir.join("repodata.json")).unwrap(), repodata);
+}
@@ -161,6 +161,7 @@ impl LockedPackagesBuilder {
                         dependencies: locked_package.dependency_list,
                         url: locked_package.url,
                         hash: locked_package.package_hashes,
+                        blake2b: None,
                         source: None,
                         build: Some(locked_package.build),
                         arch: self.platform.arch().map(|arch| arch.to_string()),
@@ -191,6 +192,8 @@ impl LockedPackagesBuilder {
                         hash: locked_package.hash,
                         source: locked_package.source,
                         build: locked_package.build,
+                        is_sdist: locked_package.is_sdist,
+                        build_backend: locked_package.build_backend,
                     }
                     .into(),
                 },
@@ -440,6 +443,12 @@ pub struct PypiLockedDependencyBuilder {
 
     /// Build string
     pub build: Option<String>,
+
+    /// True if `url` points to a source distribution instead of a wheel.
+    pub is_sdist: bool,
+
+    /// The build backend that should be used to build the source distribution.
+    pub build_backend: Option<String>,
 }
 
 #[cfg(test)]
@@ -1,11 +1,9 @@
-use crate::{
-    LockedDependency, LockedDependencyKind, PackageHashes,
-    PackageHashes::{Md5, Md5Sha256, Sha256},
-};
+use crate::{LockedDependency, LockedDependencyKind, PackageHashes};
 use rattler_conda_types::{
     InvalidPackageNameError, NoArchType, PackageName, PackageRecord, PackageUrl,
     ParseMatchSpecError, ParseVersionError, RepoDataRecord,
 };
+use rattler_digest::{compute_bytes_digest, serde::SerializableHash, Blake2b256, Blake2b256Hash};
 use serde::{Deserialize, Serialize};
 use serde_with::{serde_as, skip_serializing_none, OneOrMany};
 use url::Url;
@@ -23,6 +21,12 @@ pub struct CondaLockedDependency {
     pub url: Url,
     /// Hashes of the package
     pub hash: PackageHashes,
+
+    /// Experimental: an additional Blake2b hash of the package. When present it is verified next
+    /// to the hash in `hash`, see [`Self::verify`].
+    #[serde(default)]
+    #[serde_as(as = "Option<SerializableHash::<rattler_digest::Blake2b256>>")]
+    pub blake2b: Option<Blake2b256Hash>,
     /// ???
     pub source: Option<Url>,
 
@@ -73,6 +77,20 @@ pub struct CondaLockedDependency {
     pub purls: Vec<PackageUrl>,
 }
 
+impl CondaLockedDependency {
+    /// Verifies that the given bytes match the hashes of the package. The strongest hash in
+    /// [`Self::hash`] is always verified (see [`PackageHashes::verify`]), if a Blake2b hash is
+    /// present the bytes must also match that hash.
+    pub fn verify(&self, bytes: impl AsRef<[u8]>) -> bool {
+        let bytes = bytes.as_ref();
+        let blake2b_matches = match &self.blake2b {
+            Some(blake2b) => blake2b == &compute_bytes_digest::<Blake2b256>(bytes),
+            None => true,
+        };
+        blake2b_matches && self.hash.verify(bytes)
+    }
+}
+
 impl TryFrom<&LockedDependency> for RepoDataRecord {
     type Error = ConversionError;
 
@@ -93,20 +111,12 @@ impl TryFrom<LockedDependency> for RepoDataRecord {
             ..
         } = value;
         let LockedDependencyKind::Conda(value) = specific else {
-            return Err(ConversionError::NotACondaRecord)
+            return Err(ConversionError::NotACondaRecord);
         };
 
         let version = version.parse()?;
-        let md5 = match value.hash {
-            Md5(md5) => Some(md5),
-            Md5Sha256(md5, _) => Some(md5),
-            _ => None,
-        };
-        let sha256 = match value.hash {
-            Sha256(sha256) => Some(sha256),
-            Md5Sha256(_, sha256) => Some(sha256),
-            _ => None,
-        };
+        let md5 = value.hash.md5().copied();
+        let sha256 = value.hash.sha256().copied();
         let channel = channel_from_url(&value.url)
             .ok_or_else(|| ConversionError::Missing("channel in url".to_string()))?
             .to_string();
@@ -204,6 +214,50 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_blake2b_round_trip() {
+        let bytes = b"Hello, world!";
+        let sha256 = compute_bytes_digest::<rattler_digest::Sha256>(bytes);
+        let blake2b = compute_bytes_digest::<Blake2b256>(bytes);
+
+        let dependency = CondaLockedDependency {
+            dependencies: Vec::new(),
+            url: "https://conda.anaconda.org/conda-forge/noarch/foo-1-0.conda"
+                .parse()
+                .unwrap(),
+            hash: PackageHashes::Sha256(sha256),
+            blake2b: Some(blake2b),
+            source: None,
+            build: Some(String::from("0")),
+            arch: None,
+            subdir: None,
+            build_number: None,
+            constrains: Vec::new(),
+            features: None,
+            track_features: Vec::new(),
+            license: None,
+            license_family: None,
+            noarch: NoArchType::none(),
+            size: None,
+            timestamp: None,
+            purls: Vec::new(),
+        };
+
+        let yaml = serde_yaml::to_string(&dependency).unwrap();
+        assert!(yaml.contains("blake2b:"));
+        let result: CondaLockedDependency = serde_yaml::from_str(&yaml).unwrap();
+        assert_eq!(result, dependency);
+        assert!(result.verify(bytes));
+        assert!(!result.verify(b"Goodbye, world!"));
+
+        // A mismatching blake2b hash is not masked by a matching sha256 hash
+        let other = CondaLockedDependency {
+            blake2b: Some(compute_bytes_digest::<Blake2b256>(b"Goodbye, world!")),
+            ..dependency
+        };
+        assert!(!other.verify(bytes));
+    }
+
     #[test]
     fn test_file_name_from_url() {
         assert_eq!(file_name_from_url(&Url::parse("https://conda.anaconda.org/conda-forge/osx-64/python-3.11.0-h4150a38_1_cpython.conda").unwrap()), Some("python-3.11.0-h4150a38_1_cpython.conda"));
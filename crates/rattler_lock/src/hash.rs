@@ -1,4 +1,6 @@
-use rattler_digest::{serde::SerializableHash, Md5Hash, Sha256Hash};
+use rattler_digest::{
+    compute_bytes_digest, serde::SerializableHash, Md5, Md5Hash, Sha256, Sha256Hash,
+};
 use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
 
 /// This implementation of the `Deserialize` trait for the `PackageHashes` struct
@@ -18,6 +20,15 @@ pub enum PackageHashes {
     Md5Sha256(Md5Hash, Sha256Hash),
 }
 
+/// The kind of hash stored in a [`PackageHashes`].
+#[derive(Eq, PartialEq, Hash, Clone, Copy, Debug)]
+pub enum HashKind {
+    /// An MD5 hash
+    Md5,
+    /// A Sha256 hash
+    Sha256,
+}
+
 impl PackageHashes {
     /// Create correct enum from hashes
     pub fn from_hashes(md5: Option<Md5Hash>, sha256: Option<Sha256Hash>) -> Option<PackageHashes> {
@@ -45,6 +56,26 @@ impl PackageHashes {
             PackageHashes::Md5(md5) | PackageHashes::Md5Sha256(md5, _) => Some(md5),
         }
     }
+
+    /// Returns the kind of the strongest hash that is available. Sha256 is preferred over MD5.
+    pub fn strongest(&self) -> Option<HashKind> {
+        match self {
+            PackageHashes::Sha256(_) | PackageHashes::Md5Sha256(_, _) => Some(HashKind::Sha256),
+            PackageHashes::Md5(_) => Some(HashKind::Md5),
+        }
+    }
+
+    /// Verifies that the given bytes match the strongest available hash (see [`Self::strongest`]).
+    ///
+    /// Note that packages that only carry an MD5 hash are verified using that hash. MD5 is a lot
+    /// weaker than Sha256 and should not be relied upon to detect deliberate tampering.
+    pub fn verify(&self, bytes: impl AsRef<[u8]>) -> bool {
+        match self.strongest() {
+            Some(HashKind::Sha256) => self.sha256() == Some(&compute_bytes_digest::<Sha256>(bytes)),
+            Some(HashKind::Md5) => self.md5() == Some(&compute_bytes_digest::<Md5>(bytes)),
+            None => false,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -133,4 +164,28 @@ mod test {
         let result: PackageHashes = from_str(yaml).unwrap();
         assert!(matches!(result, PackageHashes::Sha256(_)));
     }
+
+    #[test]
+    fn test_verify() {
+        let bytes = b"Hello, world!";
+        let md5 = rattler_digest::compute_bytes_digest::<rattler_digest::Md5>(bytes);
+        let sha256 = rattler_digest::compute_bytes_digest::<rattler_digest::Sha256>(bytes);
+
+        // MD5 only packages are verified using MD5
+        let hashes = PackageHashes::Md5(md5);
+        assert_eq!(hashes.strongest(), Some(HashKind::Md5));
+        assert!(hashes.verify(bytes));
+        assert!(!hashes.verify(b"Goodbye, world!"));
+
+        // If both hashes are available sha256 is preferred
+        let hashes = PackageHashes::Md5Sha256(md5, sha256);
+        assert_eq!(hashes.strongest(), Some(HashKind::Sha256));
+        assert!(hashes.verify(bytes));
+
+        // A mismatching sha256 is not masked by a matching md5
+        let other_sha256 =
+            rattler_digest::compute_bytes_digest::<rattler_digest::Sha256>(b"Goodbye, world!");
+        let hashes = PackageHashes::Md5Sha256(md5, other_sha256);
+        assert!(!hashes.verify(bytes));
+    }
 }
@@ -14,13 +14,15 @@ pub mod builder;
 mod conda;
 mod content_hash;
 mod hash;
+pub mod lock_file;
 mod pypi;
 mod serde;
 mod utils;
 
 use crate::conda::ConversionError;
 pub use conda::CondaLockedDependency;
-pub use hash::PackageHashes;
+pub use hash::{HashKind, PackageHashes};
+pub use lock_file::{Environment, LockFile};
 pub use pypi::PypiLockedDependency;
 
 pub use self::serde::ParseCondaLockError;
@@ -363,4 +365,26 @@ mod test {
 
         insta::assert_yaml_snapshot!(repodata_record);
     }
+
+    #[test]
+    fn test_pypi_locked_dependency_name() {
+        let yaml = r#"
+        name: flask
+        version: 3.0.0
+        manager: pip
+        platform: linux-64
+        url: https://files.pythonhosted.org/packages/py3/f/flask/Flask-3.0.0-py3-none-any.whl
+        category: main"#;
+
+        let result: LockedDependency = from_str(yaml).unwrap();
+        let pypi = result.as_pypi().unwrap();
+        assert_eq!(pypi.name().as_deref(), Some("flask"));
+        assert_eq!(pypi.version().as_deref(), Some("3.0.0"));
+
+        // The name and version are not repeated in the serialized form.
+        let serialized = serde_yaml::to_string(&result).unwrap();
+        assert_eq!(serialized.matches("name:").count(), 1);
+        assert_eq!(serialized.matches("version:").count(), 1);
+        assert_eq!(from_str::<LockedDependency>(&serialized).unwrap(), result);
+    }
 }
@@ -0,0 +1,229 @@
+//! Defines [`LockFile`], a lock file that holds the locked packages of multiple named
+//! environments, and [`LockFileBuilder`] to construct one.
+
+use crate::builder::{CondaLockedDependencyBuilder, LockedPackagesBuilder};
+use crate::conda::ConversionError;
+use crate::LockedDependency;
+use indexmap::IndexMap;
+use rattler_conda_types::{Platform, RepoDataRecord};
+use serde::{Deserialize, Serialize, Serializer};
+use std::collections::{BTreeMap, HashMap};
+
+/// A lock file that contains the locked packages of multiple named environments (e.g. `default`
+/// and `test`). Every environment can be locked for multiple platforms.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, Eq, PartialEq)]
+pub struct LockFile {
+    /// The environments in the lock file, in the order in which they were added.
+    pub environments: IndexMap<String, Environment>,
+}
+
+/// The locked packages of a single environment in a [`LockFile`].
+#[derive(Serialize, Deserialize, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Environment {
+    /// The locked packages per platform.
+    #[serde(serialize_with = "sort_map_by_platform")]
+    pub packages: HashMap<Platform, Vec<LockedDependency>>,
+}
+
+impl LockFile {
+    /// Returns the environment with the given name.
+    pub fn environment(&self, name: &str) -> Option<&Environment> {
+        self.environments.get(name)
+    }
+
+    /// Constructs a lock file with a single environment that contains the given conda `records`
+    /// (e.g. the result of a solve) for `platform`.
+    ///
+    /// Returns an error if one of the records cannot be converted to a locked dependency, e.g.
+    /// because it has no hashes.
+    pub fn from_conda_records(
+        environment: &str,
+        platform: Platform,
+        records: &[RepoDataRecord],
+    ) -> Result<Self, ConversionError> {
+        let mut locked_packages = LockedPackagesBuilder::new(platform);
+        for record in records {
+            locked_packages.add_locked_package(CondaLockedDependencyBuilder::try_from(record)?);
+        }
+        Ok(LockFileBuilder::new()
+            .add_locked_packages(environment, locked_packages)
+            .build())
+    }
+}
+
+impl Environment {
+    /// Returns the locked packages of this environment for the specified platform. Returns an
+    /// empty slice if the environment has not been locked for the platform.
+    pub fn packages_for_platform(&self, platform: Platform) -> &[LockedDependency] {
+        self.packages.get(&platform).map_or(&[], Vec::as_slice)
+    }
+}
+
+/// Struct used to build a [`LockFile`] with multiple environments.
+#[derive(Default)]
+pub struct LockFileBuilder {
+    /// The environments added so far
+    environments: IndexMap<String, Environment>,
+}
+
+impl LockFileBuilder {
+    /// Constructs a new builder without any environments.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the locked packages for a single platform to the environment with the given name. The
+    /// environment is created if it doesn't exist yet. Packages that are added for the same
+    /// environment and platform multiple times are appended.
+    pub fn add_locked_packages(
+        mut self,
+        environment: impl Into<String>,
+        locked_packages: LockedPackagesBuilder,
+    ) -> Self {
+        let platform = locked_packages.platform;
+        self.environments
+            .entry(environment.into())
+            .or_default()
+            .packages
+            .entry(platform)
+            .or_default()
+            .extend(locked_packages.build());
+        self
+    }
+
+    /// Build the lock file
+    pub fn build(self) -> LockFile {
+        LockFile {
+            environments: self.environments,
+        }
+    }
+}
+
+/// Serializes the packages ordered by platform so the output does not depend on the iteration
+/// order of the [`HashMap`].
+fn sort_map_by_platform<S: Serializer>(
+    value: &HashMap<Platform, Vec<LockedDependency>>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    BTreeMap::from_iter(value.iter()).serialize(serializer)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{LockFile, LockFileBuilder};
+    use crate::builder::{LockedPackagesBuilder, PypiLockedDependencyBuilder};
+    use rattler_conda_types::{Platform, RepoDataRecord};
+
+    fn pypi_package(name: &str, version: &str) -> PypiLockedDependencyBuilder {
+        PypiLockedDependencyBuilder {
+            name: name.to_string(),
+            version: version.to_string(),
+            requires_dist: Vec::new(),
+            requires_python: None,
+            extras: Default::default(),
+            url: format!(
+                "https://files.pythonhosted.org/packages/{name}-{version}-py3-none-any.whl"
+            )
+            .parse()
+            .unwrap(),
+            hash: None,
+            source: None,
+            build: None,
+            is_sdist: false,
+            build_backend: None,
+        }
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let mut builder = LockFileBuilder::new();
+        for platform in [Platform::Linux64, Platform::Win64] {
+            builder = builder
+                .add_locked_packages(
+                    "default",
+                    LockedPackagesBuilder::new(platform)
+                        .with_locked_package(pypi_package("flask", "3.0.0")),
+                )
+                .add_locked_packages(
+                    "test",
+                    LockedPackagesBuilder::new(platform)
+                        .with_locked_package(pypi_package("flask", "3.0.0"))
+                        .with_locked_package(pypi_package("pytest", "7.4.3")),
+                );
+        }
+        let lock_file = builder.build();
+
+        let test_environment = lock_file.environment("test").unwrap();
+        assert_eq!(
+            test_environment
+                .packages_for_platform(Platform::Win64)
+                .len(),
+            2
+        );
+        assert!(test_environment
+            .packages_for_platform(Platform::Osx64)
+            .is_empty());
+
+        let serialized = serde_yaml::to_string(&lock_file).unwrap();
+        let roundtripped: LockFile = serde_yaml::from_str(&serialized).unwrap();
+        assert_eq!(roundtripped, lock_file);
+        assert_eq!(serde_yaml::to_string(&roundtripped).unwrap(), serialized);
+    }
+
+    #[test]
+    fn test_from_conda_records() {
+        let records: Vec<RepoDataRecord> = serde_json::from_str(
+            r#"[
+            {
+                "name": "ncurses", "version": "6.4", "build": "hcb278e6_0", "build_number": 0,
+                "subdir": "linux-64", "depends": ["libgcc-ng >=12"],
+                "md5": "681105bccc2a3f7f1a837d47d39c9179",
+                "sha256": "ccf61e61d58a8a7b2d66822d5568e2dc9387883dd9b2da61e1d787ece4c4979a",
+                "fn": "ncurses-6.4-hcb278e6_0.conda",
+                "url": "https://conda.anaconda.org/conda-forge/linux-64/ncurses-6.4-hcb278e6_0.conda",
+                "channel": "https://conda.anaconda.org/conda-forge/"
+            },
+            {
+                "name": "libgcc-ng", "version": "13.2.0", "build": "h807b86a_2", "build_number": 2,
+                "subdir": "linux-64",
+                "md5": "c28003b0be0494f9a7664389146716ff",
+                "sha256": "d361d3c87c376642b99c1fc25cddec4b9905a3d43d8fb95bb7e5e8d1d3a6d3d1",
+                "fn": "libgcc-ng-13.2.0-h807b86a_2.conda",
+                "url": "https://conda.anaconda.org/conda-forge/linux-64/libgcc-ng-13.2.0-h807b86a_2.conda",
+                "channel": "https://conda.anaconda.org/conda-forge/"
+            }
+            ]"#,
+        )
+        .unwrap();
+
+        let lock_file =
+            LockFile::from_conda_records("default", Platform::Linux64, &records).unwrap();
+        let packages = lock_file
+            .environment("default")
+            .unwrap()
+            .packages_for_platform(Platform::Linux64);
+        assert_eq!(packages.len(), 2);
+        for (package, record) in packages.iter().zip(&records) {
+            let conda = package.as_conda().unwrap();
+            assert_eq!(package.name, record.package_record.name.as_normalized());
+            assert_eq!(conda.url, record.url);
+            assert_eq!(conda.hash.md5(), record.package_record.md5.as_ref());
+            assert_eq!(conda.hash.sha256(), record.package_record.sha256.as_ref());
+        }
+
+        let serialized = serde_yaml::to_string(&lock_file).unwrap();
+        assert!(serialized.contains(
+            "url: https://conda.anaconda.org/conda-forge/linux-64/ncurses-6.4-hcb278e6_0.conda"
+        ));
+        assert!(serialized
+            .contains("sha256: ccf61e61d58a8a7b2d66822d5568e2dc9387883dd9b2da61e1d787ece4c4979a"));
+        let roundtripped: LockFile = serde_yaml::from_str(&serialized).unwrap();
+        assert_eq!(roundtripped, lock_file);
+
+        // Records without hashes cannot be locked
+        let mut record = records[0].clone();
+        record.package_record.md5 = None;
+        record.package_record.sha256 = None;
+        assert!(LockFile::from_conda_records("default", Platform::Linux64, &[record]).is_err());
+    }
+}
@@ -32,4 +32,160 @@ pub struct PypiLockedDependency {
 
     /// Build string
     pub build: Option<String>,
+
+    /// True if `url` points to a source distribution instead of a wheel. Source distributions
+    /// have to be built before they can be installed.
+    #[serde(default, skip_serializing_if = "is_sdist_default")]
+    pub is_sdist: bool,
+
+    /// The build backend (e.g. `setuptools.build_meta`) that should be used to build the source
+    /// distribution. Only meaningful if `is_sdist` is true.
+    pub build_backend: Option<String>,
+}
+
+impl PypiLockedDependency {
+    /// Returns the normalized name of the package parsed from the filename of the wheel or source
+    /// distribution that `url` points to. This makes the dependency usable without the enclosing
+    /// [`crate::LockedDependency`].
+    pub fn name(&self) -> Option<String> {
+        let filename = self.filename()?;
+        let (name, _) = split_distribution_filename(&filename)?;
+        Some(normalize_name(name))
+    }
+
+    /// Returns the version of the package parsed from the filename of the wheel or source
+    /// distribution that `url` points to.
+    pub fn version(&self) -> Option<String> {
+        let filename = self.filename()?;
+        let (_, version) = split_distribution_filename(&filename)?;
+        Some(version.to_owned())
+    }
+
+    /// Returns the filename of the wheel or source distribution that `url` points to. This is the
+    /// percent-decoded last segment of the path of the url (e.g. `%2B` becomes `+`). Returns `None`
+    /// if the url has no path segments or the last segment is empty.
+    pub fn filename(&self) -> Option<String> {
+        let last_segment = self.url.path_segments()?.next_back()?;
+        if last_segment.is_empty() {
+            return None;
+        }
+        Some(
+            percent_encoding::percent_decode_str(last_segment)
+                .decode_utf8_lossy()
+                .into_owned(),
+        )
+    }
+}
+
+/// Splits the filename of a wheel (`{name}-{version}(-{build})?-{python}-{abi}-{platform}.whl`) or
+/// source distribution (`{name}-{version}.tar.gz`) into its name and version.
+fn split_distribution_filename(filename: &str) -> Option<(&str, &str)> {
+    if let Some(stem) = filename.strip_suffix(".whl") {
+        let mut parts = stem.split('-');
+        return Some((parts.next()?, parts.next()?));
+    }
+
+    let stem = [".tar.gz", ".tar.bz2", ".tgz", ".zip"]
+        .into_iter()
+        .find_map(|extension| filename.strip_suffix(extension))?;
+
+    // Names of source distributions can contain dashes but versions cannot.
+    stem.rsplit_once('-')
+}
+
+/// Normalizes a package name as described in PEP 503: the name is lowercased and runs of `-`, `_`
+/// and `.` are replaced with a single `-`.
+fn normalize_name(name: &str) -> String {
+    name.split(['-', '_', '.'])
+        .filter(|part| !part.is_empty())
+        .map(str::to_lowercase)
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// Returns true if the value is equal to the default value for the "is_sdist" value of a
+/// [`PypiLockedDependency`]
+fn is_sdist_default(value: &bool) -> bool {
+    !*value
+}
+
+#[cfg(test)]
+mod test {
+    use super::PypiLockedDependency;
+
+    #[test]
+    fn test_sdist_roundtrip() {
+        let yaml = r#"
+        url: https://files.pythonhosted.org/packages/source/f/flask/Flask-3.0.0.tar.gz
+        requires_dist:
+          - Werkzeug>=3.0.0
+        is_sdist: true
+        build_backend: flit_core.buildapi
+        "#;
+
+        let dependency: PypiLockedDependency = serde_yaml::from_str(yaml).unwrap();
+        assert!(dependency.is_sdist);
+        assert_eq!(
+            dependency.build_backend.as_deref(),
+            Some("flit_core.buildapi")
+        );
+
+        let serialized = serde_yaml::to_string(&dependency).unwrap();
+        let roundtripped: PypiLockedDependency = serde_yaml::from_str(&serialized).unwrap();
+        assert_eq!(dependency, roundtripped);
+    }
+
+    #[test]
+    fn test_is_sdist_defaults_to_false() {
+        let yaml = r#"
+        url: https://files.pythonhosted.org/packages/py3/f/flask/Flask-3.0.0-py3-none-any.whl
+        "#;
+
+        let dependency: PypiLockedDependency = serde_yaml::from_str(yaml).unwrap();
+        assert!(!dependency.is_sdist);
+        assert!(dependency.build_backend.is_none());
+
+        let serialized = serde_yaml::to_string(&dependency).unwrap();
+        assert!(!serialized.contains("is_sdist"));
+    }
+
+    #[test]
+    fn test_name_and_version() {
+        let yaml = r#"
+        url: https://files.pythonhosted.org/packages/py3/z/zope.interface/zope.interface-6.1-cp311-cp311-manylinux_2_17_x86_64.whl
+        "#;
+
+        let dependency: PypiLockedDependency = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(dependency.name().as_deref(), Some("zope-interface"));
+        assert_eq!(dependency.version().as_deref(), Some("6.1"));
+
+        let yaml = r#"
+        url: https://files.pythonhosted.org/packages/source/f/flask/Flask-3.0.0.tar.gz
+        is_sdist: true
+        "#;
+
+        let dependency: PypiLockedDependency = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(dependency.name().as_deref(), Some("flask"));
+        assert_eq!(dependency.version().as_deref(), Some("3.0.0"));
+
+        let yaml = r#"
+        url: https://files.pythonhosted.org/packages/source/r/ruamel-yaml/ruamel-yaml-0.18.5.tar.gz
+        "#;
+        let dependency: PypiLockedDependency = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(dependency.name().as_deref(), Some("ruamel-yaml"));
+        assert_eq!(dependency.version().as_deref(), Some("0.18.5"));
+    }
+
+    #[test]
+    fn test_filename() {
+        let yaml = r#"
+        url: https://download.pytorch.org/whl/cpu/torch-2.1.0%2Bcpu-cp311-cp311-linux_x86_64.whl
+        "#;
+
+        let dependency: PypiLockedDependency = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(
+            dependency.filename().as_deref(),
+            Some("torch-2.1.0+cpu-cp311-cp311-linux_x86_64.whl")
+        );
+    }
 }
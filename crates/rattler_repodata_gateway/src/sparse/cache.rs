@@ -0,0 +1,211 @@
+//! Defines [`SharedRecordCache`], a bounded cache of parsed records that can be shared between
+//! multiple [`super::SparseRepoData`] instances.
+
+use rattler_conda_types::{PackageName, RepoDataRecord};
+use rattler_digest::Sha256Hash;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+/// The key of an entry in the cache. Next to the channel and subdir the key contains the hash of
+/// the content of the repodata (see [`super::SparseRepoData::content_hash`]) so instances that
+/// refer to different versions of the same subdir never share records.
+#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+pub(super) struct CacheKey {
+    pub channel: String,
+    pub subdir: String,
+    pub content_hash: Sha256Hash,
+    pub package_name: PackageName,
+}
+
+/// A thread-safe least-recently-used cache of parsed records keyed by channel, subdir, content of
+/// the repodata and package name. The cache is cheap to clone, all clones share the same storage. Attach it to
+/// [`super::SparseRepoData`] instances with [`super::SparseRepoData::with_shared_cache`] to avoid
+/// parsing the same records multiple times.
+///
+/// The number of cached packages is bounded, when the cache is full the least recently used entry
+/// is evicted.
+#[derive(Clone)]
+pub struct SharedRecordCache {
+    inner: Arc<SharedRecordCacheInner>,
+}
+
+struct SharedRecordCacheInner {
+    capacity: usize,
+    state: Mutex<LruState>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Default)]
+struct LruState {
+    /// The cached records together with the tick at which they were last used.
+    entries: HashMap<CacheKey, (Arc<Vec<RepoDataRecord>>, u64)>,
+
+    /// The keys of the entries ordered by the tick at which they were last used.
+    usage: BTreeMap<u64, CacheKey>,
+
+    /// Monotonically increasing counter used to order the entries by usage.
+    tick: u64,
+}
+
+impl LruState {
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    fn get(&mut self, key: &CacheKey) -> Option<Arc<Vec<RepoDataRecord>>> {
+        let tick = self.next_tick();
+        let (records, last_used) = self.entries.get_mut(key)?;
+        let key = self
+            .usage
+            .remove(last_used)
+            .expect("every entry must have a usage");
+        *last_used = tick;
+        self.usage.insert(tick, key);
+        Some(records.clone())
+    }
+
+    fn insert(&mut self, key: CacheKey, records: Arc<Vec<RepoDataRecord>>, capacity: usize) {
+        let tick = self.next_tick();
+        if let Some((_, last_used)) = self.entries.insert(key.clone(), (records, tick)) {
+            self.usage.remove(&last_used);
+        }
+        self.usage.insert(tick, key);
+
+        while self.entries.len() > capacity {
+            let Some((_, evicted)) = self.usage.pop_first() else {
+                break;
+            };
+            self.entries.remove(&evicted);
+        }
+    }
+}
+
+impl SharedRecordCache {
+    /// Constructs a new cache that holds the records of at most `capacity` packages.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            inner: Arc::new(SharedRecordCacheInner {
+                capacity,
+                state: Mutex::default(),
+                hits: AtomicU64::new(0),
+                misses: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// Returns the maximum number of packages stored in the cache.
+    pub fn capacity(&self) -> usize {
+        self.inner.capacity
+    }
+
+    /// Returns the number of packages currently stored in the cache.
+    pub fn len(&self) -> usize {
+        self.lock().entries.len()
+    }
+
+    /// Returns true if the cache does not contain any records.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the number of lookups that were answered from the cache.
+    pub fn hits(&self) -> u64 {
+        self.inner.hits.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of lookups that were not answered from the cache and required the
+    /// records to be parsed.
+    pub fn misses(&self) -> u64 {
+        self.inner.misses.load(Ordering::Relaxed)
+    }
+
+    /// Returns the cached records for `key` or parses them with `parse` and stores them in the
+    /// cache.
+    ///
+    /// The lock is not held while parsing, so concurrent lookups of the same key might both parse
+    /// the records. This is preferred over serializing all parsing through the cache.
+    pub(super) fn get_or_try_insert<E>(
+        &self,
+        key: CacheKey,
+        parse: impl FnOnce() -> Result<Vec<RepoDataRecord>, E>,
+    ) -> Result<Arc<Vec<RepoDataRecord>>, E> {
+        if let Some(records) = self.lock().get(&key) {
+            self.inner.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(records);
+        }
+
+        self.inner.misses.fetch_add(1, Ordering::Relaxed);
+        let records = Arc::new(parse()?);
+        self.lock()
+            .insert(key, records.clone(), self.inner.capacity);
+        Ok(records)
+    }
+
+    /// Removes the records of all packages of the given channel and subdir from the cache.
+    pub(super) fn remove_subdir(&self, channel: &str, subdir: &str) {
+        let mut state = self.lock();
+        let LruState { entries, usage, .. } = &mut *state;
+        entries.retain(|key, (_, last_used)| {
+            let remove = key.channel == channel && key.subdir == subdir;
+            if remove {
+                usage.remove(last_used);
+            }
+            !remove
+        });
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, LruState> {
+        // The state is always consistent, even if another thread panicked while holding the lock.
+        self.inner
+            .state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{CacheKey, SharedRecordCache};
+    use rattler_conda_types::PackageName;
+    use rattler_digest::Sha256Hash;
+    use std::convert::Infallible;
+
+    fn key(name: &str) -> CacheKey {
+        CacheKey {
+            channel: String::from("https://conda.anaconda.org/conda-forge/"),
+            subdir: String::from("linux-64"),
+            content_hash: Sha256Hash::default(),
+            package_name: PackageName::new_unchecked(name),
+        }
+    }
+
+    #[test]
+    fn test_eviction() {
+        let cache = SharedRecordCache::new(2);
+        let insert = |name: &str| {
+            cache
+                .get_or_try_insert(key(name), || Ok::<_, Infallible>(Vec::new()))
+                .unwrap();
+        };
+
+        insert("a");
+        insert("b");
+        insert("a");
+        insert("c");
+        assert_eq!(cache.len(), 2);
+        assert_eq!((cache.hits(), cache.misses()), (1, 3));
+
+        // `b` was the least recently used entry so it should have been evicted
+        insert("a");
+        insert("b");
+        assert_eq!((cache.hits(), cache.misses()), (2, 4));
+        assert_eq!(cache.len(), 2);
+    }
+}
@@ -2,45 +2,229 @@
 //! from a `repodata.json` file.
 
 use futures::{stream, StreamExt, TryFutureExt, TryStreamExt};
-use itertools::Itertools;
+use itertools::{Either, EitherOrBoth, Itertools};
 use rattler_conda_types::{
-    compute_package_url, Channel, ChannelInfo, PackageName, PackageRecord, RepoDataRecord,
+    compute_package_url, package::ArchiveType, version_spec::EqualityOperator, Channel,
+    ChannelInfo, MatchSpec, PackageName, PackageRecord, Platform, RepoData, RepoDataRecord,
+    StringMatcher, Subdir, Version, VersionSpec, VersionWithSource,
 };
+use rattler_digest::{compute_bytes_digest, digest::Digest, Sha256, Sha256Hash};
+use rayon::prelude::*;
 use serde::{
     de::{Error, MapAccess, Visitor},
     Deserialize, Deserializer,
 };
 use serde_json::value::RawValue;
 use std::{
-    collections::{HashSet, VecDeque},
+    collections::{hash_map::Entry, BTreeMap, HashMap, HashSet, VecDeque},
     fmt, io,
     marker::PhantomData,
-    path::Path,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, OnceLock,
+    },
+    time::SystemTime,
 };
 use superslice::Ext;
+use url::Url;
+
+mod cache;
+mod owned;
+
+use cache::CacheKey;
+pub use cache::SharedRecordCache;
+pub use owned::OwnedRepoDataIndex;
+
+/// A function that can be used to rewrite the URL of a record after it has been computed. See
+/// [`SparseRepoData::with_url_rewrite_fn`].
+pub type UrlRewriteFn = Arc<dyn Fn(&mut Url, &PackageRecord) + Send + Sync>;
+
+/// Determines the order in which [`SparseRepoData::load_records`] returns records.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub enum RecordSortOrder {
+    /// Records are returned in the order in which they appear in the repodata.
+    #[default]
+    FileOrder,
+
+    /// Records are sorted by version and build number, the newest record first.
+    NewestFirst,
+
+    /// Records are sorted by version and build number, the oldest record first.
+    OldestFirst,
+}
+
+/// Hints that are passed to the operating system about how the memory mapped `repodata.json` of a
+/// [`SparseRepoData`] is accessed, see [`SparseRepoData::new_with_mmap_hints`]. The hints are only
+/// applied on unix platforms, on other platforms they are ignored.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub struct MmapHints {
+    /// Advise the operating system that the file is read sequentially (`MADV_SEQUENTIAL`) so it
+    /// reads ahead aggressively during the initial parse of the file.
+    pub sequential: bool,
+
+    /// Advise the operating system that the whole file will be needed soon (`MADV_WILLNEED`) so it
+    /// starts reading the file into memory immediately.
+    pub will_need: bool,
+}
+
+/// An error that can occur when loading a [`SparseRepoData`] from a file, see
+/// [`SparseRepoData::try_from_path`].
+#[derive(Debug, thiserror::Error)]
+pub enum SparseRepoDataError {
+    /// The file is empty or only contains whitespace. This is a common symptom of an interrupted
+    /// download.
+    #[error("the repodata file {} is empty", .path.display())]
+    EmptyFile {
+        /// The path of the empty file
+        path: PathBuf,
+    },
+
+    /// The file could not be read or parsed.
+    #[error(transparent)]
+    IoError(#[from] io::Error),
+}
+
+impl From<SparseRepoDataError> for io::Error {
+    fn from(err: SparseRepoDataError) -> Self {
+        match err {
+            SparseRepoDataError::IoError(err) => err,
+            err => io::Error::new(io::ErrorKind::InvalidData, err),
+        }
+    }
+}
+
+/// Determines how the URLs of the records in a [`SparseRepoData`] are derived from the channel.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub enum UrlLayout {
+    /// Packages are stored in a subdirectory of the channel named after their subdir
+    /// (`<channel>/<subdir>/<filename>`). This is the layout of regular conda channels.
+    #[default]
+    Subdir,
+
+    /// Packages are stored directly in the channel (`<channel>/<filename>`). This is the layout of
+    /// flat channels and some mirrors.
+    Flat,
+}
+
+/// The filenames that differ between two [`SparseRepoData`] snapshots, see [`SparseRepoData::diff`].
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct RepoDataDiff {
+    /// Filenames that are only present in the other snapshot, sorted alphabetically.
+    pub added: Vec<String>,
+
+    /// Filenames that are only present in this snapshot, sorted alphabetically.
+    pub removed: Vec<String>,
+}
+
+/// The package names that changed when a [`SparseRepoData`] was reloaded, see
+/// [`SparseRepoData::reload`].
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct NameDelta {
+    /// Package names that are only present after reloading, sorted alphabetically.
+    pub added: Vec<String>,
+
+    /// Package names that are only present before reloading, sorted alphabetically.
+    pub removed: Vec<String>,
+}
 
 /// A struct to enable loading records from a `repodata.json` file on demand. Since most of the time you
 /// don't need all the records from the `repodata.json` this can help provide some significant speedups.
+///
+/// Cloning is cheap, clones share the underlying memory map and index.
+#[derive(Clone)]
 pub struct SparseRepoData {
     /// Data structure that holds a memory mapped repodata.json file and an index into the the records
     /// store in that data.
-    inner: SparseRepoDataInner,
+    inner: Arc<SparseRepoDataInner>,
 
     /// The channel from which this data was downloaded.
     channel: Channel,
 
     /// The subdirectory from where the repodata is downloaded
-    subdir: String,
+    subdir: Subdir,
 
     /// A function that can be used to patch the package record after it has been parsed.
     /// This is mainly used to add `pip` to `python` if desired
     patch_record_fn: Option<fn(&mut PackageRecord)>,
+
+    /// A function that determines for which records the patch function is called. If `None` the
+    /// patch function is called for all records.
+    patch_filter: Option<fn(&PackageRecord) -> bool>,
+
+    /// A function that is called to rewrite the URL of every record after it has been computed.
+    url_rewrite_fn: Option<UrlRewriteFn>,
+
+    /// The order in which records are returned.
+    sort_order: RecordSortOrder,
+
+    /// How the URLs of the records are derived from the channel.
+    url_layout: UrlLayout,
+
+    /// An explicit URL of the subdir that overrides the URL derived from the channel.
+    subdir_url: Option<Url>,
+
+    /// A cache of parsed records that might be shared with other instances.
+    shared_cache: Option<SharedRecordCache>,
+
+    /// The hash of the content of the repodata, computed on first use.
+    content_hash: Arc<OnceLock<Sha256Hash>>,
+
+    /// The path of the file the repodata was loaded from, used to reload it.
+    source_path: Option<PathBuf>,
+
+    /// The hints passed to the operating system when the file is memory mapped.
+    mmap_hints: MmapHints,
+}
+
+/// A reverse index of the dependencies of the records in a [`SparseRepoData`]. See
+/// [`SparseRepoData::build_dependency_index`].
+#[derive(Debug, Default, Clone)]
+pub struct DependencyIndex {
+    dependents: HashMap<PackageName, Vec<String>>,
+}
+
+impl DependencyIndex {
+    /// Returns the filenames of all records that depend on the package with the given name.
+    pub fn dependents(&self, package_name: &PackageName) -> &[String] {
+        self.dependents.get(package_name).map_or(&[], Vec::as_slice)
+    }
+
+    /// Records that the record stored under `filename` depends on the packages in `depends`.
+    fn insert(&mut self, filename: &str, depends: &[String]) {
+        let dependency_names = depends
+            .iter()
+            .map(|dependency| dependency_package_name(dependency))
+            .collect::<HashSet<_>>();
+        for dependency_name in dependency_names {
+            self.dependents
+                .entry(dependency_name)
+                .or_default()
+                .push(filename.to_owned());
+        }
+    }
+}
+
+/// Only the dependencies of a record
+#[derive(Deserialize)]
+struct Depends {
+    #[serde(default)]
+    depends: Vec<String>,
+}
+
+/// The source of the records of a [`SparseRepoData`].
+enum SparseRepoDataInner {
+    /// The records are lazily parsed from a memory mapped `repodata.json` file.
+    Memmapped(MemmappedSparseRepoDataInner),
+
+    /// The records are taken from an already parsed [`RepoData`].
+    Parsed(ParsedRepoData),
 }
 
 /// A struct that holds a memory map of a `repodata.json` file and also a self-referential field which
 /// indexes the data in the memory map with a sparsely parsed json struct. See [`LazyRepoData`].
 #[ouroboros::self_referencing]
-struct SparseRepoDataInner {
+struct MemmappedSparseRepoDataInner {
     /// Memory map of the `repodata.json` file
     memory_map: memmap2::Mmap,
 
@@ -55,64 +239,656 @@ impl SparseRepoData {
     /// Construct an instance of self from a file on disk and a [`Channel`].
     /// The `patch_function` can be used to patch the package record after it has been parsed
     /// (e.g. to add `pip` to `python`).
+    ///
+    /// Repodata that some proxies wrap in an object with a single `repodata` key is unwrapped
+    /// automatically.
     pub fn new(
         channel: Channel,
-        subdir: impl Into<String>,
+        subdir: impl Into<Subdir>,
         path: impl AsRef<Path>,
         patch_function: Option<fn(&mut PackageRecord)>,
     ) -> Result<Self, io::Error> {
-        let file = std::fs::File::open(path)?;
-        let memory_map = unsafe { memmap2::Mmap::map(&file) }?;
+        Self::new_with_mmap_hints(channel, subdir, path, patch_function, MmapHints::default())
+    }
+
+    /// Same as [`SparseRepoData::new`] but passes the given [`MmapHints`] to the operating system
+    /// after the file has been memory mapped. Advising sequential access can speed up the initial
+    /// parse of large files which otherwise triggers many small page faults.
+    pub fn new_with_mmap_hints(
+        channel: Channel,
+        subdir: impl Into<Subdir>,
+        path: impl AsRef<Path>,
+        patch_function: Option<fn(&mut PackageRecord)>,
+        hints: MmapHints,
+    ) -> Result<Self, io::Error> {
+        Ok(Self::from_file(
+            channel,
+            subdir,
+            path,
+            patch_function,
+            hints,
+        )?)
+    }
+
+    /// Same as [`SparseRepoData::new`] but returns a [`SparseRepoDataError`] which provides a
+    /// clearer diagnostic if the file is empty, e.g. because a download was interrupted.
+    pub fn try_from_path(
+        channel: Channel,
+        subdir: impl Into<Subdir>,
+        path: impl AsRef<Path>,
+        patch_function: Option<fn(&mut PackageRecord)>,
+    ) -> Result<Self, SparseRepoDataError> {
+        Self::from_file(channel, subdir, path, patch_function, MmapHints::default())
+    }
+
+    fn from_file(
+        channel: Channel,
+        subdir: impl Into<Subdir>,
+        path: impl AsRef<Path>,
+        patch_function: Option<fn(&mut PackageRecord)>,
+        hints: MmapHints,
+    ) -> Result<Self, SparseRepoDataError> {
+        let inner = map_repo_data(&mut FsRepoDataFile {
+            path: path.as_ref(),
+            hints,
+        })?;
         Ok(SparseRepoData {
-            inner: SparseRepoDataInnerTryBuilder {
-                memory_map,
-                repo_data_builder: |memory_map| serde_json::from_slice(memory_map.as_ref()),
-            }
-            .try_build()?,
+            inner: Arc::new(SparseRepoDataInner::Memmapped(inner)),
             subdir: subdir.into(),
             channel,
             patch_record_fn: patch_function,
+            patch_filter: None,
+            url_rewrite_fn: None,
+            sort_order: RecordSortOrder::default(),
+            url_layout: UrlLayout::default(),
+            subdir_url: None,
+            shared_cache: None,
+            content_hash: Arc::default(),
+            source_path: Some(path.as_ref().to_path_buf()),
+            mmap_hints: hints,
         })
     }
 
+    /// Construct an instance of self from an already parsed [`RepoData`] and a [`Channel`]. This
+    /// provides the same API as [`SparseRepoData::new`] without having to store the repodata on
+    /// disk first.
+    /// The `patch_function` can be used to patch the package record after it has been parsed
+    /// (e.g. to add `pip` to `python`).
+    pub fn from_repo_data(
+        channel: Channel,
+        subdir: impl Into<Subdir>,
+        repo_data: RepoData,
+        patch_function: Option<fn(&mut PackageRecord)>,
+    ) -> Self {
+        SparseRepoData {
+            inner: Arc::new(SparseRepoDataInner::Parsed(ParsedRepoData::from(repo_data))),
+            subdir: subdir.into(),
+            channel,
+            patch_record_fn: patch_function,
+            patch_filter: None,
+            url_rewrite_fn: None,
+            sort_order: RecordSortOrder::default(),
+            url_layout: UrlLayout::default(),
+            subdir_url: None,
+            shared_cache: None,
+            content_hash: Arc::default(),
+            source_path: None,
+            mmap_hints: MmapHints::default(),
+        }
+    }
+
+    /// Sets a function that is called for every loaded record to rewrite its URL after it has been
+    /// computed. This can for instance be used to point clients to a mirror of the channel without
+    /// modifying the repodata itself.
+    pub fn with_url_rewrite_fn(
+        mut self,
+        url_rewrite_fn: impl Fn(&mut Url, &PackageRecord) + Send + Sync + 'static,
+    ) -> Self {
+        self.url_rewrite_fn = Some(Arc::new(url_rewrite_fn));
+        self
+    }
+
+    /// Sets a function that determines for which records the patch function is called. The patch
+    /// function is only called for records for which `patch_filter` returns `true` (e.g. only for
+    /// `python` when `pip` is added to its dependencies). By default the patch function is called
+    /// for all records.
+    pub fn with_patch_filter(mut self, patch_filter: fn(&PackageRecord) -> bool) -> Self {
+        self.patch_filter = Some(patch_filter);
+        self
+    }
+
+    /// Sets a cache that is consulted before records are parsed from the `repodata.json` file and
+    /// that is populated with the parsed records. The same cache can be shared by multiple
+    /// instances (e.g. for different channels and subdirs) to reduce redundant parsing when the
+    /// same packages are requested repeatedly.
+    pub fn with_shared_cache(mut self, cache: SharedRecordCache) -> Self {
+        self.shared_cache = Some(cache);
+        self
+    }
+
+    /// Sets the order in which records are returned from [`SparseRepoData::load_records`] and
+    /// friends. By default records are returned in the order in which they appear in the file.
+    pub fn with_sort_order(mut self, sort_order: RecordSortOrder) -> Self {
+        self.sort_order = sort_order;
+        self
+    }
+
+    /// Sets how the URLs of the loaded records are derived from the channel. By default packages
+    /// are expected in a subdirectory of the channel named after their subdir, use
+    /// [`UrlLayout::Flat`] for channels that store all packages at the root.
+    pub fn with_url_layout(mut self, url_layout: UrlLayout) -> Self {
+        self.url_layout = url_layout;
+        self
+    }
+
+    /// Sets the URL of the subdir from which the packages are downloaded. The URLs of the loaded
+    /// records are computed relative to this URL instead of the URL derived from the channel and
+    /// the subdir. This takes precedence over [`Self::with_url_layout`] and is useful for mirrors
+    /// that do not follow any common layout.
+    pub fn with_subdir_url(mut self, subdir_url: Url) -> Self {
+        self.subdir_url = Some(subdir_url);
+        self
+    }
+
     /// Returns an iterator over all package names in this repodata file.
     ///
     /// This works by iterating over all elements in the `packages` and `conda_packages` fields of
     /// the repodata and returning the unique package names.
     pub fn package_names(&self) -> impl Iterator<Item = &'_ str> + '_ {
-        let repo_data = self.inner.borrow_repo_data();
-        repo_data
-            .packages
-            .iter()
-            .chain(repo_data.conda_packages.iter())
-            .map(|(name, _)| name.package)
-            .dedup()
+        match self.inner.as_ref() {
+            SparseRepoDataInner::Memmapped(inner) => {
+                let repo_data = inner.borrow_repo_data();
+                Either::Left(
+                    repo_data
+                        .packages
+                        .iter()
+                        .chain(repo_data.conda_packages.iter())
+                        .map(|(name, _)| name.package)
+                        .dedup(),
+                )
+            }
+            SparseRepoDataInner::Parsed(repo_data) => Either::Right(
+                repo_data
+                    .packages
+                    .iter()
+                    .chain(repo_data.conda_packages.iter())
+                    .map(|(name, _, _)| name.as_str())
+                    .dedup(),
+            ),
+        }
+    }
+
+    /// Maps the file this instance was loaded from again and replaces the index with the new
+    /// contents. Returns the package names that were added or removed compared to the previous
+    /// contents, which allows callers to only update the parts of their caches that changed.
+    ///
+    /// Records of this subdir that are stored in the shared cache (see
+    /// [`Self::with_shared_cache`]) are invalidated. Clones of this instance keep referring to the
+    /// previous contents.
+    ///
+    /// Returns an error of kind [`io::ErrorKind::Unsupported`] if this instance was not loaded from
+    /// a file (e.g. with [`Self::from_repo_data`]).
+    pub fn reload(&mut self) -> io::Result<NameDelta> {
+        let Some(path) = &self.source_path else {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "repodata that was not loaded from a file cannot be reloaded",
+            ));
+        };
+        let inner = map_repo_data(&mut FsRepoDataFile {
+            path,
+            hints: self.mmap_hints,
+        })?;
+
+        let previous_names = self.sorted_package_names();
+        self.inner = Arc::new(SparseRepoDataInner::Memmapped(inner));
+        self.content_hash = Arc::default();
+        if let Some(cache) = &self.shared_cache {
+            cache.remove_subdir(self.channel.base_url.as_str(), self.subdir.as_ref());
+        }
+
+        let mut delta = NameDelta::default();
+        for entry in previous_names
+            .into_iter()
+            .merge_join_by(self.sorted_package_names(), |a, b| a.cmp(b))
+        {
+            match entry {
+                EitherOrBoth::Left(name) => delta.removed.push(name),
+                EitherOrBoth::Right(name) => delta.added.push(name),
+                EitherOrBoth::Both(_, _) => {}
+            }
+        }
+        Ok(delta)
+    }
+
+    /// Returns all the package names in this repodata file, sorted and without duplicates.
+    fn sorted_package_names(&self) -> Vec<String> {
+        let mut names = self.package_names().map(ToOwned::to_owned).collect_vec();
+        names.sort_unstable();
+        names.dedup();
+        names
+    }
+
+    /// Returns the number of records per package name in this repodata file.
+    ///
+    /// This only walks the index once and does not parse any records, which makes it much cheaper
+    /// than loading the records of every package.
+    pub fn record_counts(&self) -> HashMap<String, usize> {
+        let mut counts = HashMap::new();
+        let mut add_counts = |names: &mut dyn Iterator<Item = &str>| {
+            for (count, name) in names.dedup_with_count() {
+                *counts.entry(name.to_owned()).or_default() += count;
+            }
+        };
+        match self.inner.as_ref() {
+            SparseRepoDataInner::Memmapped(inner) => {
+                let repo_data = inner.borrow_repo_data();
+                for packages in [&repo_data.packages, &repo_data.conda_packages] {
+                    add_counts(&mut packages.iter().map(|(name, _)| name.package));
+                }
+            }
+            SparseRepoDataInner::Parsed(repo_data) => {
+                for packages in [&repo_data.packages, &repo_data.conda_packages] {
+                    add_counts(&mut packages.iter().map(|(name, _, _)| name.as_str()));
+                }
+            }
+        }
+        counts
+    }
+
+    /// Returns the filenames that were added and removed in `other` compared to this repodata.
+    ///
+    /// Only the filenames are compared, the records themselves are not parsed. A record whose
+    /// contents changed while keeping its filename is therefore not reported.
+    pub fn diff(&self, other: &SparseRepoData) -> RepoDataDiff {
+        let ours = self.sorted_filenames();
+        let theirs = other.sorted_filenames();
+        let mut diff = RepoDataDiff::default();
+        for entry in ours.into_iter().merge_join_by(theirs, |a, b| a.cmp(b)) {
+            match entry {
+                EitherOrBoth::Left(filename) => diff.removed.push(filename.to_owned()),
+                EitherOrBoth::Right(filename) => diff.added.push(filename.to_owned()),
+                EitherOrBoth::Both(_, _) => {}
+            }
+        }
+        diff
+    }
+
+    /// Returns all the filenames in this repodata file, sorted and without duplicates.
+    fn sorted_filenames(&self) -> Vec<&str> {
+        let mut filenames = match self.inner.as_ref() {
+            SparseRepoDataInner::Memmapped(inner) => {
+                let repo_data = inner.borrow_repo_data();
+                repo_data
+                    .packages
+                    .iter()
+                    .chain(repo_data.conda_packages.iter())
+                    .map(|(name, _)| name.filename)
+                    .collect_vec()
+            }
+            SparseRepoDataInner::Parsed(repo_data) => repo_data
+                .packages
+                .iter()
+                .chain(repo_data.conda_packages.iter())
+                .map(|(_, filename, _)| filename.as_str())
+                .collect_vec(),
+        };
+        filenames.sort_unstable();
+        filenames.dedup();
+        filenames
     }
 
     /// Returns all the records for the specified package name.
     pub fn load_records(&self, package_name: &PackageName) -> io::Result<Vec<RepoDataRecord>> {
-        let repo_data = self.inner.borrow_repo_data();
-        let base_url = repo_data.info.as_ref().and_then(|i| i.base_url.as_deref());
-        let mut records = parse_records(
-            package_name,
-            &repo_data.packages,
-            base_url,
-            &self.channel,
-            &self.subdir,
-            self.patch_record_fn,
-        )?;
-        let mut conda_records = parse_records(
-            package_name,
-            &repo_data.conda_packages,
-            base_url,
-            &self.channel,
-            &self.subdir,
-            self.patch_record_fn,
-        )?;
-        records.append(&mut conda_records);
+        self.load_records_with_patch(package_name, self.patch_record_fn)
+    }
+
+    /// Returns at most `n` records for the specified package name, the ones with the highest
+    /// version and build number. The records are ordered from newest to oldest regardless of the
+    /// configured [`RecordSortOrder`].
+    ///
+    /// This can be used to limit the number of candidates of packages with many builds.
+    pub fn load_records_top_n(
+        &self,
+        package_name: &PackageName,
+        n: usize,
+    ) -> io::Result<Vec<RepoDataRecord>> {
+        let mut records = self.load_records(package_name)?;
+        records.sort_by(|a, b| {
+            record_version_key(&b.package_record).cmp(&record_version_key(&a.package_record))
+        });
+        records.truncate(n);
+        Ok(records)
+    }
+
+    /// Returns the record with the highest version and build number that matches `spec`, or
+    /// `None` if no record matches. Only the records of the package named in the spec are
+    /// searched, a spec without a name never matches.
+    ///
+    /// Dependencies of the record are not taken into account, this is not a replacement for a
+    /// solver.
+    pub fn find_best_match(&self, spec: &MatchSpec) -> io::Result<Option<RepoDataRecord>> {
+        let Some(package_name) = &spec.name else {
+            return Ok(None);
+        };
+        Ok(self
+            .load_records(package_name)?
+            .into_iter()
+            .filter(|record| spec.matches(&record.package_record))
+            .max_by(|a, b| {
+                record_version_key(&a.package_record).cmp(&record_version_key(&b.package_record))
+            }))
+    }
+
+    /// Returns the record that is stored under the exact `filename` (e.g.
+    /// `python-3.11.5-h47c9636_0_cpython.conda`) or `None` if there is no such record.
+    ///
+    /// The package name is derived from the filename to find the records of that package, only the
+    /// record that matches the filename is parsed.
+    pub fn load_record_by_filename(&self, filename: &str) -> io::Result<Option<RepoDataRecord>> {
+        let Ok(PackageFilename { package, .. }) = PackageFilename::try_from(filename) else {
+            return Ok(None);
+        };
+
+        let record = match self.inner.as_ref() {
+            SparseRepoDataInner::Memmapped(inner) => {
+                let repo_data = inner.borrow_repo_data();
+                [&repo_data.packages, &repo_data.conda_packages]
+                    .into_iter()
+                    .find_map(|packages| {
+                        let package_indices =
+                            packages.equal_range_by(|(key, _)| key.package.cmp(package));
+                        packages[package_indices]
+                            .iter()
+                            .find(|(key, _)| key.filename == filename)
+                    })
+                    .map(|(key, raw_json)| -> io::Result<_> {
+                        let package_record: PackageRecord = serde_json::from_str(raw_json.get())?;
+                        Ok(to_repo_data_record(
+                            key.filename,
+                            package_record,
+                            repo_data.info.as_ref().and_then(|i| i.base_url.as_deref()),
+                            &self.channel,
+                            &self.channel.canonical_name(),
+                            self.subdir.as_str(),
+                        ))
+                    })
+                    .transpose()?
+            }
+            SparseRepoDataInner::Parsed(repo_data) => {
+                [&repo_data.packages, &repo_data.conda_packages]
+                    .into_iter()
+                    .find_map(|packages| {
+                        let package_indices =
+                            packages.equal_range_by(|(name, _, _)| name.as_str().cmp(package));
+                        packages[package_indices]
+                            .iter()
+                            .find(|(_, record_filename, _)| record_filename == filename)
+                    })
+                    .map(|(_, filename, package_record)| {
+                        to_repo_data_record(
+                            filename,
+                            package_record.clone(),
+                            repo_data.info.as_ref().and_then(|i| i.base_url.as_deref()),
+                            &self.channel,
+                            &self.channel.canonical_name(),
+                            self.subdir.as_str(),
+                        )
+                    })
+            }
+        };
+
+        Ok(record.map(|mut record| {
+            self.post_process_records(std::slice::from_mut(&mut record), self.patch_record_fn);
+            record
+        }))
+    }
+
+    /// Calls `f` for every record of the specified package name. This is the visitor counterpart
+    /// of [`Self::load_records`]: the records are parsed and patched one at a time and handed to
+    /// `f` without collecting them into a `Vec` first.
+    ///
+    /// Records are visited in the order in which they appear in the repodata, the configured
+    /// [`RecordSortOrder`] is not applied and the shared record cache is bypassed.
+    pub fn for_each_record(
+        &self,
+        package_name: &PackageName,
+        mut f: impl FnMut(&RepoDataRecord),
+    ) -> io::Result<()> {
+        let channel_name = self.channel.canonical_name();
+        let mut visit = |mut record: RepoDataRecord| {
+            self.post_process_records(std::slice::from_mut(&mut record), self.patch_record_fn);
+            f(&record);
+        };
+
+        match self.inner.as_ref() {
+            SparseRepoDataInner::Memmapped(inner) => {
+                let repo_data = inner.borrow_repo_data();
+                let base_url = repo_data.info.as_ref().and_then(|i| i.base_url.as_deref());
+                for packages in [&repo_data.packages, &repo_data.conda_packages] {
+                    let package_indices = packages
+                        .equal_range_by(|(key, _)| key.package.cmp(package_name.as_normalized()));
+                    for (key, raw_json) in &packages[package_indices] {
+                        let package_record: PackageRecord = serde_json::from_str(raw_json.get())?;
+                        visit(to_repo_data_record(
+                            key.filename,
+                            package_record,
+                            base_url,
+                            &self.channel,
+                            &channel_name,
+                            self.subdir.as_str(),
+                        ));
+                    }
+                }
+            }
+            SparseRepoDataInner::Parsed(repo_data) => {
+                let base_url = repo_data.info.as_ref().and_then(|i| i.base_url.as_deref());
+                for packages in [&repo_data.packages, &repo_data.conda_packages] {
+                    let package_indices = packages.equal_range_by(|(name, _, _)| {
+                        name.as_str().cmp(package_name.as_normalized())
+                    });
+                    for (_, filename, package_record) in &packages[package_indices] {
+                        visit(to_repo_data_record(
+                            filename,
+                            package_record.clone(),
+                            base_url,
+                            &self.channel,
+                            &channel_name,
+                            self.subdir.as_str(),
+                        ));
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the records of all the specified package names. The records of the different
+    /// packages are parsed in parallel on the rayon thread pool. The returned records are in the
+    /// same order as `package_names`.
+    pub fn par_load_records(
+        &self,
+        package_names: &[PackageName],
+    ) -> io::Result<Vec<Vec<RepoDataRecord>>> {
+        package_names
+            .par_iter()
+            .map(|package_name| self.load_records(package_name))
+            .collect()
+    }
+
+    /// Returns all the records for the specified package name using the specified patch function
+    /// instead of the one stored in this instance.
+    fn load_records_with_patch(
+        &self,
+        package_name: &PackageName,
+        patch_function: Option<fn(&mut PackageRecord)>,
+    ) -> io::Result<Vec<RepoDataRecord>> {
+        let mut records = match (self.inner.as_ref(), &self.shared_cache) {
+            (SparseRepoDataInner::Memmapped(_), Some(cache)) => {
+                let key = CacheKey {
+                    channel: self.channel.base_url.to_string(),
+                    subdir: self.subdir.to_string(),
+                    content_hash: self.content_hash(),
+                    package_name: package_name.clone(),
+                };
+                cache
+                    .get_or_try_insert(key, || self.read_records(package_name))?
+                    .as_ref()
+                    .clone()
+            }
+            _ => self.read_records(package_name)?,
+        };
+
+        self.post_process_records(&mut records, patch_function);
+
+        // Sort the records if requested
+        match self.sort_order {
+            RecordSortOrder::FileOrder => {}
+            RecordSortOrder::NewestFirst => records.sort_by(|a, b| {
+                record_version_key(&b.package_record).cmp(&record_version_key(&a.package_record))
+            }),
+            RecordSortOrder::OldestFirst => records.sort_by(|a, b| {
+                record_version_key(&a.package_record).cmp(&record_version_key(&b.package_record))
+            }),
+        }
+
+        Ok(records)
+    }
+
+    /// Reads the records for the specified package name without any post processing.
+    fn read_records(&self, package_name: &PackageName) -> io::Result<Vec<RepoDataRecord>> {
+        let records = match self.inner.as_ref() {
+            SparseRepoDataInner::Memmapped(inner) => {
+                let repo_data = inner.borrow_repo_data();
+                let base_url = repo_data.info.as_ref().and_then(|i| i.base_url.as_deref());
+                let mut records = parse_records(
+                    package_name,
+                    &repo_data.packages,
+                    base_url,
+                    &self.channel,
+                    self.subdir.as_str(),
+                )?;
+                let mut conda_records = parse_records(
+                    package_name,
+                    &repo_data.conda_packages,
+                    base_url,
+                    &self.channel,
+                    self.subdir.as_str(),
+                )?;
+                records.append(&mut conda_records);
+                records
+            }
+            SparseRepoDataInner::Parsed(repo_data) => {
+                repo_data.read_records(package_name, &self.channel, self.subdir.as_str())
+            }
+        };
         Ok(records)
     }
 
+    /// Rewrites the urls and applies the patch function to freshly loaded records.
+    fn post_process_records(
+        &self,
+        records: &mut [RepoDataRecord],
+        patch_function: Option<fn(&mut PackageRecord)>,
+    ) {
+        // Recompute the urls if the packages are not stored in `<channel>/<subdir>/`
+        let repo_data_base_url = match (&self.subdir_url, self.url_layout) {
+            (Some(subdir_url), _) => Some(subdir_url),
+            (None, UrlLayout::Flat) => Some(&self.channel.base_url),
+            (None, UrlLayout::Subdir) => None,
+        };
+        if let Some(repo_data_base_url) = repo_data_base_url {
+            let base_url = self.base_url();
+            for record in records.iter_mut() {
+                record.url = compute_package_url(repo_data_base_url, base_url, &record.file_name);
+            }
+        }
+
+        // Rewrite the urls if requested
+        if let Some(url_rewrite_fn) = &self.url_rewrite_fn {
+            for record in records.iter_mut() {
+                url_rewrite_fn(&mut record.url, &record.package_record);
+            }
+        }
+
+        // Apply the patch function if one was specified
+        if let Some(patch_fn) = patch_function {
+            for record in records.iter_mut() {
+                if self
+                    .patch_filter
+                    .is_some_and(|patch_filter| !patch_filter(&record.package_record))
+                {
+                    continue;
+                }
+                patch_fn(&mut record.package_record);
+            }
+        }
+    }
+
+    /// Returns the `base_url` from the channel information in the repodata if any.
+    fn base_url(&self) -> Option<&str> {
+        let info = match self.inner.as_ref() {
+            SparseRepoDataInner::Memmapped(inner) => inner.borrow_repo_data().info.as_ref(),
+            SparseRepoDataInner::Parsed(repo_data) => repo_data.info.as_ref(),
+        };
+        info.and_then(|i| i.base_url.as_deref())
+    }
+
+    /// Loads all the records in this repodata on a best effort basis. Records that fail to parse
+    /// do not abort loading, instead the filenames of these records are returned together with
+    /// the error that occurred. This is useful to ingest the repodata of imperfect mirrors.
+    ///
+    /// Records are grouped by package name in alphabetical order and the records of `packages`
+    /// (`.tar.bz2`) come before those of `packages.conda`. This is not necessarily the order in
+    /// which they appear in the repodata file. Errors are only reported for memory mapped
+    /// repodata, the records of an instance constructed with [`Self::from_repo_data`] have already
+    /// been parsed.
+    pub fn load_all_records_lenient(
+        &self,
+    ) -> (Vec<RepoDataRecord>, Vec<(String, serde_json::Error)>) {
+        let channel_name = self.channel.canonical_name();
+        let mut records = Vec::new();
+        let mut errors = Vec::new();
+        match self.inner.as_ref() {
+            SparseRepoDataInner::Memmapped(inner) => {
+                let repo_data = inner.borrow_repo_data();
+                let base_url = repo_data.info.as_ref().and_then(|i| i.base_url.as_deref());
+                for (key, raw_json) in repo_data.packages.iter().chain(&repo_data.conda_packages) {
+                    match serde_json::from_str(raw_json.get()) {
+                        Ok(package_record) => records.push(to_repo_data_record(
+                            key.filename,
+                            package_record,
+                            base_url,
+                            &self.channel,
+                            &channel_name,
+                            self.subdir.as_str(),
+                        )),
+                        Err(err) => errors.push((key.filename.to_owned(), err)),
+                    }
+                }
+            }
+            SparseRepoDataInner::Parsed(repo_data) => {
+                let base_url = repo_data.info.as_ref().and_then(|i| i.base_url.as_deref());
+                for (_, filename, record) in
+                    repo_data.packages.iter().chain(&repo_data.conda_packages)
+                {
+                    records.push(to_repo_data_record(
+                        filename,
+                        record.clone(),
+                        base_url,
+                        &self.channel,
+                        &channel_name,
+                        self.subdir.as_str(),
+                    ));
+                }
+            }
+        }
+
+        self.post_process_records(&mut records, self.patch_record_fn);
+        (records, errors)
+    }
+
     /// Given a set of [`SparseRepoData`]s load all the records for the packages with the specified
     /// names and all the packages these records depend on.
     ///
@@ -123,11 +899,115 @@ impl SparseRepoData {
         repo_data: impl IntoIterator<Item = &'a SparseRepoData>,
         package_names: impl IntoIterator<Item = PackageName>,
         patch_function: Option<fn(&mut PackageRecord)>,
+    ) -> io::Result<Vec<Vec<RepoDataRecord>>> {
+        Self::load_records_recursive_excluding(
+            repo_data,
+            package_names,
+            &HashSet::new(),
+            patch_function,
+        )
+    }
+
+    /// Same as [`Self::load_records_recursive`] but the packages in `exclude` are not part of the
+    /// result. Their records are not loaded and their dependencies are not walked, unless they
+    /// are reachable through other packages. This is useful to prune packages from the closure
+    /// that are known to be present already.
+    ///
+    /// The packages in `package_names` are always loaded, even if they are part of `exclude`.
+    pub fn load_records_recursive_excluding<'a>(
+        repo_data: impl IntoIterator<Item = &'a SparseRepoData>,
+        package_names: impl IntoIterator<Item = PackageName>,
+        exclude: &HashSet<PackageName>,
+        patch_function: Option<fn(&mut PackageRecord)>,
+    ) -> io::Result<Vec<Vec<RepoDataRecord>>> {
+        let repo_data: Vec<_> = repo_data.into_iter().collect();
+        collect_records_recursive(
+            repo_data.len(),
+            package_names,
+            exclude,
+            None,
+            |i, package_name| repo_data[i].load_records_with_patch(package_name, patch_function),
+        )
+    }
+
+    /// Same as [`Self::load_records_recursive`] but the walk over the dependencies can be aborted
+    /// by setting `cancelled` to `true` from another thread (e.g. from a UI). The flag is checked
+    /// before the records of every package are loaded.
+    ///
+    /// When the load is cancelled an error of kind [`io::ErrorKind::Interrupted`] is returned and
+    /// the records that have been loaded so far are discarded.
+    pub fn load_records_recursive_cancellable<'a>(
+        repo_data: impl IntoIterator<Item = &'a SparseRepoData>,
+        package_names: impl IntoIterator<Item = PackageName>,
+        patch_function: Option<fn(&mut PackageRecord)>,
+        cancelled: &AtomicBool,
     ) -> io::Result<Vec<Vec<RepoDataRecord>>> {
         let repo_data: Vec<_> = repo_data.into_iter().collect();
+        collect_records_recursive(
+            repo_data.len(),
+            package_names,
+            &HashSet::new(),
+            Some(cancelled),
+            |i, package_name| repo_data[i].load_records_with_patch(package_name, patch_function),
+        )
+    }
+
+    /// Given a set of [`SparseRepoData`]s load the records for several independent sets of seed
+    /// packages and all the packages these records depend on.
+    ///
+    /// This is equivalent to calling [`Self::load_records_recursive`] for every seed set but the
+    /// records of packages that are part of multiple closures are only parsed once. The result
+    /// contains, for every seed set, the records per repodata in the same order as `repo_data`.
+    pub fn load_records_recursive_multi<'a>(
+        repo_data: impl IntoIterator<Item = &'a SparseRepoData>,
+        seed_sets: &[Vec<PackageName>],
+        patch_function: Option<fn(&mut PackageRecord)>,
+    ) -> io::Result<Vec<Vec<Vec<RepoDataRecord>>>> {
+        let repo_data: Vec<_> = repo_data.into_iter().collect();
+
+        // Cache of the records that have already been parsed, per package per repodata.
+        let mut cache: HashMap<PackageName, Vec<Vec<RepoDataRecord>>> = HashMap::new();
 
-        // Construct the result map
-        let mut result = Vec::from_iter((0..repo_data.len()).map(|_| Vec::new()));
+        seed_sets
+            .iter()
+            .map(|seed_set| {
+                collect_records_recursive(
+                    repo_data.len(),
+                    seed_set.iter().cloned(),
+                    &HashSet::new(),
+                    None,
+                    |i, package_name| {
+                        let records = match cache.entry(package_name.clone()) {
+                            Entry::Occupied(entry) => entry.into_mut(),
+                            Entry::Vacant(entry) => entry.insert(
+                                repo_data
+                                    .iter()
+                                    .map(|repo_data| {
+                                        repo_data
+                                            .load_records_with_patch(package_name, patch_function)
+                                    })
+                                    .collect::<io::Result<_>>()?,
+                            ),
+                        };
+                        Ok(records[i].clone())
+                    },
+                )
+            })
+            .collect()
+    }
+
+    /// Given a set of [`SparseRepoData`]s determine the names of all packages that are part of the
+    /// dependency closure of the packages with the specified names.
+    ///
+    /// This performs the same walk over the dependencies as [`Self::load_records_recursive`] (without
+    /// a patch function) but only parses the dependencies of the records instead of constructing
+    /// complete [`RepoDataRecord`]s. This can be used to cheaply estimate the size of the closure
+    /// before loading it.
+    pub fn closure_package_names<'a>(
+        repo_data: impl IntoIterator<Item = &'a SparseRepoData>,
+        package_names: impl IntoIterator<Item = PackageName>,
+    ) -> io::Result<HashSet<PackageName>> {
+        let repo_data: Vec<_> = repo_data.into_iter().collect();
 
         // Construct a set of packages that we have seen and have been added to the pending list.
         let mut seen: HashSet<PackageName> = HashSet::from_iter(package_names);
@@ -137,57 +1017,284 @@ impl SparseRepoData {
 
         // Iterate over the list of packages that still need to be processed.
         while let Some(next_package) = pending.pop_front() {
-            for (i, repo_data) in repo_data.iter().enumerate() {
-                let repo_data_packages = repo_data.inner.borrow_repo_data();
-                let base_url = repo_data_packages
-                    .info
-                    .as_ref()
-                    .and_then(|i| i.base_url.as_deref());
-
-                // Get all records from the repodata
-                let mut records = parse_records(
-                    &next_package,
-                    &repo_data_packages.packages,
-                    base_url,
-                    &repo_data.channel,
-                    &repo_data.subdir,
-                    patch_function,
-                )?;
-                let mut conda_records = parse_records(
-                    &next_package,
-                    &repo_data_packages.conda_packages,
-                    base_url,
-                    &repo_data.channel,
-                    &repo_data.subdir,
-                    patch_function,
-                )?;
-                records.append(&mut conda_records);
-
-                // Iterate over all packages to find recursive dependencies.
-                for record in records.iter() {
-                    for dependency in &record.package_record.depends {
-                        let dependency_name = PackageName::new_unchecked(
-                            dependency.split_once(' ').unwrap_or((dependency, "")).0,
-                        );
-                        if !seen.contains(&dependency_name) {
-                            pending.push_back(dependency_name.clone());
-                            seen.insert(dependency_name);
-                        }
+            for repo_data in repo_data.iter() {
+                for dependency_name in repo_data.dependency_names(&next_package)? {
+                    if !seen.contains(&dependency_name) {
+                        pending.push_back(dependency_name.clone());
+                        seen.insert(dependency_name);
                     }
                 }
-
-                result[i].append(&mut records);
             }
         }
 
-        Ok(result)
+        Ok(seen)
     }
 
-    /// Returns the subdirectory from which this repodata was loaded
-    pub fn subdir(&self) -> &str {
-        &self.subdir
-    }
-}
+    /// Returns true if any record of the specified package has a version that matches `range`. Only
+    /// the versions of the records are parsed and this returns as soon as a matching version is
+    /// found, which makes this a cheap existence check compared to loading the records.
+    pub fn has_version_in_range(
+        &self,
+        package_name: &PackageName,
+        range: &VersionSpec,
+    ) -> io::Result<bool> {
+        match self.inner.as_ref() {
+            SparseRepoDataInner::Memmapped(inner) => {
+                /// Only the version of a record
+                #[derive(Deserialize)]
+                struct VersionOnly {
+                    version: Version,
+                }
+
+                let repo_data = inner.borrow_repo_data();
+                for packages in [&repo_data.packages, &repo_data.conda_packages] {
+                    let package_indices = packages.equal_range_by(|(package, _)| {
+                        package.package.cmp(package_name.as_normalized())
+                    });
+                    for (_, raw_json) in &packages[package_indices] {
+                        let record: VersionOnly = serde_json::from_str(raw_json.get())?;
+                        if range.matches(&record.version) {
+                            return Ok(true);
+                        }
+                    }
+                }
+            }
+            SparseRepoDataInner::Parsed(repo_data) => {
+                for packages in [&repo_data.packages, &repo_data.conda_packages] {
+                    let package_indices = packages.equal_range_by(|(package, _, _)| {
+                        package.as_str().cmp(package_name.as_normalized())
+                    });
+                    if packages[package_indices]
+                        .iter()
+                        .any(|(_, _, record)| range.matches(record.version.version()))
+                    {
+                        return Ok(true);
+                    }
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    /// Returns the distinct `(version, build)` pairs that are available for the specified package
+    /// sorted newest first, by version and then by build number. Only the fields that are required
+    /// for this are parsed which makes this cheaper than loading the complete records.
+    pub fn versions(&self, package_name: &PackageName) -> io::Result<Vec<(Version, String)>> {
+        let mut versions: Vec<(Version, u64, String)> = Vec::new();
+        match self.inner.as_ref() {
+            SparseRepoDataInner::Memmapped(inner) => {
+                /// Only the fields of a record that identify its version and build
+                #[derive(Deserialize)]
+                struct VersionAndBuild {
+                    version: Version,
+                    build: String,
+                    #[serde(default)]
+                    build_number: u64,
+                }
+
+                let repo_data = inner.borrow_repo_data();
+                for packages in [&repo_data.packages, &repo_data.conda_packages] {
+                    let package_indices = packages.equal_range_by(|(package, _)| {
+                        package.package.cmp(package_name.as_normalized())
+                    });
+                    for (_, raw_json) in &packages[package_indices] {
+                        let record: VersionAndBuild = serde_json::from_str(raw_json.get())?;
+                        versions.push((record.version, record.build_number, record.build));
+                    }
+                }
+            }
+            SparseRepoDataInner::Parsed(repo_data) => {
+                for packages in [&repo_data.packages, &repo_data.conda_packages] {
+                    let package_indices = packages.equal_range_by(|(package, _, _)| {
+                        package.as_str().cmp(package_name.as_normalized())
+                    });
+                    for (_, _, record) in &packages[package_indices] {
+                        versions.push((
+                            record.version.version().clone(),
+                            record.build_number,
+                            record.build.clone(),
+                        ));
+                    }
+                }
+            }
+        }
+
+        versions.sort_by(
+            |(a_version, a_build_number, a_build), (b_version, b_build_number, b_build)| {
+                b_version
+                    .cmp(a_version)
+                    .then(b_build_number.cmp(a_build_number))
+                    .then(a_build.cmp(b_build))
+            },
+        );
+        versions.dedup_by(|(a_version, _, a_build), (b_version, _, b_build)| {
+            a_version == b_version && a_build == b_build
+        });
+
+        Ok(versions
+            .into_iter()
+            .map(|(version, _, build)| (version, build))
+            .collect())
+    }
+
+    /// Returns the distinct names of the dependencies of all records of the specified package.
+    /// Virtual packages are skipped, just like [`Self::load_records_recursive`] does.
+    fn dependency_names(&self, package_name: &PackageName) -> io::Result<HashSet<PackageName>> {
+        let mut dependency_names = HashSet::new();
+        let mut add_dependencies = |depends: &[String]| {
+            for dependency in depends {
+                let dependency_name = dependency_package_name(dependency);
+                if !is_virtual_package(&dependency_name) {
+                    dependency_names.insert(dependency_name);
+                }
+            }
+        };
+
+        match self.inner.as_ref() {
+            SparseRepoDataInner::Memmapped(inner) => {
+                let repo_data = inner.borrow_repo_data();
+                for packages in [&repo_data.packages, &repo_data.conda_packages] {
+                    let package_indices = packages.equal_range_by(|(package, _)| {
+                        package.package.cmp(package_name.as_normalized())
+                    });
+                    for (_, raw_json) in &packages[package_indices] {
+                        let record: Depends = serde_json::from_str(raw_json.get())?;
+                        add_dependencies(&record.depends);
+                    }
+                }
+            }
+            SparseRepoDataInner::Parsed(repo_data) => {
+                for packages in [&repo_data.packages, &repo_data.conda_packages] {
+                    let package_indices = packages.equal_range_by(|(package, _, _)| {
+                        package.as_str().cmp(package_name.as_normalized())
+                    });
+                    for (_, _, record) in &packages[package_indices] {
+                        add_dependencies(&record.depends);
+                    }
+                }
+            }
+        }
+
+        Ok(dependency_names)
+    }
+
+    /// Parses the dependencies of all records once and builds a reverse index that maps the name
+    /// of a package to the filenames of the records that depend on it. This is useful when many
+    /// "which packages depend on X" queries have to be answered.
+    pub fn build_dependency_index(&self) -> io::Result<DependencyIndex> {
+        let mut index = DependencyIndex::default();
+        match self.inner.as_ref() {
+            SparseRepoDataInner::Memmapped(inner) => {
+                let repo_data = inner.borrow_repo_data();
+                for (key, raw_json) in repo_data.packages.iter().chain(&repo_data.conda_packages) {
+                    let record: Depends = serde_json::from_str(raw_json.get())?;
+                    index.insert(key.filename, &record.depends);
+                }
+            }
+            SparseRepoDataInner::Parsed(repo_data) => {
+                for (_, filename, record) in
+                    repo_data.packages.iter().chain(&repo_data.conda_packages)
+                {
+                    index.insert(filename, &record.depends);
+                }
+            }
+        }
+        Ok(index)
+    }
+
+    /// Returns the sorted names of the packages that have records without any dependencies. If
+    /// `all_builds` is `false` a package is returned if at least one of its records has no
+    /// dependencies, if it is `true` all of its records must have no dependencies.
+    ///
+    /// Only the `depends` field of the records is parsed.
+    pub fn leaf_packages(&self, all_builds: bool) -> io::Result<Vec<String>> {
+        // Tracks per package name whether any and whether all records have no dependencies.
+        let mut leaves: BTreeMap<&str, (bool, bool)> = BTreeMap::new();
+        let mut add = |name, has_no_depends: bool| {
+            let (any, all) = leaves.entry(name).or_insert((false, true));
+            *any |= has_no_depends;
+            *all &= has_no_depends;
+        };
+        match self.inner.as_ref() {
+            SparseRepoDataInner::Memmapped(inner) => {
+                let repo_data = inner.borrow_repo_data();
+                for (key, raw_json) in repo_data.packages.iter().chain(&repo_data.conda_packages) {
+                    let record: Depends = serde_json::from_str(raw_json.get())?;
+                    add(key.package, record.depends.is_empty());
+                }
+            }
+            SparseRepoDataInner::Parsed(repo_data) => {
+                for (name, _, record) in repo_data.packages.iter().chain(&repo_data.conda_packages)
+                {
+                    add(name.as_str(), record.depends.is_empty());
+                }
+            }
+        }
+        Ok(leaves
+            .into_iter()
+            .filter(|(_, (any, all))| if all_builds { *all } else { *any })
+            .map(|(name, _)| name.to_owned())
+            .collect())
+    }
+
+    /// Returns the subdirectory from which this repodata was loaded
+    pub fn subdir(&self) -> &str {
+        self.subdir.as_str()
+    }
+
+    /// Returns the [`Platform`] of the subdirectory from which this repodata was loaded.
+    ///
+    /// Returns `None` for `noarch` and for subdirectories that do not correspond to a known
+    /// platform.
+    pub fn subdir_platform(&self) -> Option<Platform> {
+        self.subdir
+            .platform()
+            .filter(|platform| *platform != Platform::NoArch)
+    }
+
+    /// Returns the SHA256 hash of the content of the repodata. This can be used as a key for
+    /// caches that depend on the exact content of the repodata.
+    ///
+    /// For a memory mapped `repodata.json` this is the hash of the bytes of the file. For an
+    /// instance constructed with [`Self::from_repo_data`] the hash is computed over the records
+    /// instead. The hash is computed on first use and cached, clones share the cached value.
+    pub fn content_hash(&self) -> Sha256Hash {
+        *self.content_hash.get_or_init(|| match self.inner.as_ref() {
+            SparseRepoDataInner::Memmapped(inner) => {
+                compute_bytes_digest::<Sha256>(inner.borrow_memory_map())
+            }
+            SparseRepoDataInner::Parsed(repo_data) => {
+                let mut hasher = Sha256::default();
+                hasher.update(
+                    serde_json::to_vec(&repo_data.info).expect("ChannelInfo is serializable"),
+                );
+                // The order of records with the same name depends on the iteration order of the
+                // original hash map, so sort them to get a stable hash.
+                let records = repo_data
+                    .packages
+                    .iter()
+                    .chain(&repo_data.conda_packages)
+                    .map(|(_, filename, record)| (filename, record))
+                    .sorted_by_key(|(filename, _)| *filename);
+                for (filename, record) in records {
+                    hasher.update(filename);
+                    hasher
+                        .update(serde_json::to_vec(record).expect("PackageRecord is serializable"));
+                }
+                hasher.finalize()
+            }
+        })
+    }
+
+    /// Returns the version of the repodata format as specified by the `repodata_version` field,
+    /// or `None` if the repodata does not specify a version.
+    pub fn repodata_version(&self) -> Option<u32> {
+        match self.inner.as_ref() {
+            SparseRepoDataInner::Memmapped(inner) => inner.borrow_repo_data().repodata_version,
+            SparseRepoDataInner::Parsed(repo_data) => repo_data.repodata_version,
+        }
+    }
+}
 
 /// A serde compatible struct that only sparsely parses a repodata.json file.
 #[derive(Deserialize)]
@@ -195,6 +1302,10 @@ struct LazyRepoData<'i> {
     /// The channel information contained in the repodata.json file
     info: Option<ChannelInfo>,
 
+    /// The version of the repodata format
+    #[serde(default)]
+    repodata_version: Option<u32>,
+
     /// The tar.bz2 packages contained in the repodata.json file
     #[serde(borrow, deserialize_with = "deserialize_filename_and_raw_record")]
     packages: Vec<(PackageFilename<'i>, &'i RawValue)>,
@@ -210,252 +1321,1837 @@ struct LazyRepoData<'i> {
     conda_packages: Vec<(PackageFilename<'i>, &'i RawValue)>,
 }
 
-/// Parse the records for the specified package from the raw index
-fn parse_records<'i>(
-    package_name: &PackageName,
-    packages: &[(PackageFilename<'i>, &'i RawValue)],
-    base_url: Option<&str>,
-    channel: &Channel,
-    subdir: &str,
-    patch_function: Option<fn(&mut PackageRecord)>,
-) -> io::Result<Vec<RepoDataRecord>> {
-    let channel_name = channel.canonical_name();
+/// Some proxies wrap the repodata in an envelope object with a single `repodata` key, e.g.
+/// `{ "repodata": { "packages": { ... } } }`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RepoDataEnvelope<'i> {
+    #[serde(borrow)]
+    repodata: LazyRepoData<'i>,
+}
+
+/// Sparsely parses the contents of a `repodata.json` file. If the contents cannot be parsed
+/// directly but are wrapped in a [`RepoDataEnvelope`], the inner repodata is parsed instead. The
+/// error of the direct parse is returned if neither succeeds.
+fn parse_lazy_repo_data(bytes: &[u8]) -> serde_json::Result<LazyRepoData<'_>> {
+    serde_json::from_slice(bytes).or_else(|err| {
+        serde_json::from_slice::<RepoDataEnvelope<'_>>(bytes)
+            .map(|envelope| envelope.repodata)
+            .map_err(|_| err)
+    })
+}
+
+/// The records of an already parsed [`RepoData`] ordered by package name so they can be queried
+/// in the same way as a [`LazyRepoData`].
+struct ParsedRepoData {
+    /// The channel information contained in the repodata
+    info: Option<ChannelInfo>,
+
+    /// The version of the repodata format
+    repodata_version: Option<u32>,
+
+    /// The tar.bz2 packages as tuples of package name, filename and record.
+    packages: Vec<(String, String, PackageRecord)>,
+
+    /// The conda packages as tuples of package name, filename and record.
+    conda_packages: Vec<(String, String, PackageRecord)>,
+}
+
+impl From<RepoData> for ParsedRepoData {
+    fn from(repo_data: RepoData) -> Self {
+        fn sorted_by_name(
+            packages: impl IntoIterator<Item = (String, PackageRecord)>,
+        ) -> Vec<(String, String, PackageRecord)> {
+            let mut packages = packages
+                .into_iter()
+                .map(|(filename, record)| {
+                    (record.name.as_normalized().to_owned(), filename, record)
+                })
+                .collect::<Vec<_>>();
+            packages.sort_by(|(a, _, _), (b, _, _)| a.cmp(b));
+            packages
+        }
+
+        Self {
+            info: repo_data.info,
+            repodata_version: repo_data
+                .version
+                .and_then(|version| u32::try_from(version).ok()),
+            packages: sorted_by_name(repo_data.packages),
+            conda_packages: sorted_by_name(repo_data.conda_packages),
+        }
+    }
+}
+
+impl ParsedRepoData {
+    /// Returns the records for the specified package name.
+    fn read_records(
+        &self,
+        package_name: &PackageName,
+        channel: &Channel,
+        subdir: &str,
+    ) -> Vec<RepoDataRecord> {
+        let base_url = self.info.as_ref().and_then(|i| i.base_url.as_deref());
+        let mut records = clone_records(package_name, &self.packages, base_url, channel, subdir);
+        let mut conda_records = clone_records(
+            package_name,
+            &self.conda_packages,
+            base_url,
+            channel,
+            subdir,
+        );
+        records.append(&mut conda_records);
+        records
+    }
+}
+
+/// Constructs a [`RepoDataRecord`] from a parsed [`PackageRecord`] and the filename it is stored
+/// under in the repodata.
+fn to_repo_data_record(
+    filename: &str,
+    mut package_record: PackageRecord,
+    base_url: Option<&str>,
+    channel: &Channel,
+    channel_name: &str,
+    subdir: &str,
+) -> RepoDataRecord {
+    // Overwrite subdir if its empty
+    if package_record.subdir.is_empty() {
+        package_record.subdir = subdir.to_owned();
+    }
+
+    // Some channels contain records with duplicate dependencies
+    dedup_in_order(&mut package_record.depends);
+    dedup_in_order(&mut package_record.constrains);
+    RepoDataRecord {
+        url: compute_package_url(
+            &channel
+                .base_url
+                .join(&format!("{}/", &package_record.subdir))
+                .expect("failed determine repo_base_url"),
+            base_url,
+            filename,
+        ),
+        channel: channel_name.to_owned(),
+        package_record,
+        file_name: filename.to_owned(),
+    }
+}
+
+/// Removes duplicate entries while preserving the order of the first occurrences.
+fn dedup_in_order(items: &mut Vec<String>) {
+    if !items.iter().all_unique() {
+        *items = std::mem::take(items).into_iter().unique().collect();
+    }
+}
+
+/// Parse the records for the specified package from the raw index
+fn parse_records<'i>(
+    package_name: &PackageName,
+    packages: &[(PackageFilename<'i>, &'i RawValue)],
+    base_url: Option<&str>,
+    channel: &Channel,
+    subdir: &str,
+) -> io::Result<Vec<RepoDataRecord>> {
+    let channel_name = channel.canonical_name();
+
+    let package_indices =
+        packages.equal_range_by(|(package, _)| package.package.cmp(package_name.as_normalized()));
+    let mut result = Vec::with_capacity(package_indices.len());
+    for (key, raw_json) in &packages[package_indices] {
+        let package_record: PackageRecord = serde_json::from_str(raw_json.get())?;
+        result.push(to_repo_data_record(
+            key.filename,
+            package_record,
+            base_url,
+            channel,
+            &channel_name,
+            subdir,
+        ));
+    }
+
+    Ok(result)
+}
+
+/// Clone the records for the specified package from an already parsed index
+fn clone_records(
+    package_name: &PackageName,
+    packages: &[(String, String, PackageRecord)],
+    base_url: Option<&str>,
+    channel: &Channel,
+    subdir: &str,
+) -> Vec<RepoDataRecord> {
+    let channel_name = channel.canonical_name();
+
+    let package_indices = packages
+        .equal_range_by(|(package, _, _)| package.as_str().cmp(package_name.as_normalized()));
+    packages[package_indices]
+        .iter()
+        .map(|(_, filename, package_record)| {
+            to_repo_data_record(
+                filename,
+                package_record.clone(),
+                base_url,
+                channel,
+                &channel_name,
+                subdir,
+            )
+        })
+        .collect()
+}
+
+/// A helper function that immediately loads the records for the given packages (and their dependencies).
+/// Records for the specified packages are loaded from the repodata files.
+/// The patch_record_fn is applied to each record after it has been parsed and can mutate the record after
+/// it has been loaded.
+pub async fn load_repo_data_recursively(
+    repo_data_paths: impl IntoIterator<Item = (Channel, impl Into<Subdir>, impl AsRef<Path>)>,
+    package_names: impl IntoIterator<Item = PackageName>,
+    patch_function: Option<fn(&mut PackageRecord)>,
+) -> Result<Vec<Vec<RepoDataRecord>>, io::Error> {
+    // Open the different files and memory map them to get access to their bytes. Do this in parallel.
+    let lazy_repo_data = stream::iter(repo_data_paths)
+        .map(|(channel, subdir, path)| {
+            let path = path.as_ref().to_path_buf();
+            let subdir = subdir.into();
+            tokio::task::spawn_blocking(move || {
+                SparseRepoData::new(channel, subdir, path, patch_function)
+            })
+            .unwrap_or_else(|r| match r.try_into_panic() {
+                Ok(panic) => std::panic::resume_unwind(panic),
+                Err(err) => Err(io::Error::new(io::ErrorKind::Other, err.to_string())),
+            })
+        })
+        .buffered(50)
+        .try_collect::<Vec<_>>()
+        .await?;
+
+    SparseRepoData::load_records_recursive(&lazy_repo_data, package_names, patch_function)
+}
+
+/// Returns a [`MatchSpec`] that pins exactly the given record by its name, version and build
+/// string, e.g. `python ==3.11.5 h47c9636_0_cpython`. If `include_sha256` is `true` and the record
+/// has a sha256 hash the spec also requires that hash.
+///
+/// This is useful to reproduce an environment from a set of previously resolved records.
+pub fn exact_match_spec(record: &RepoDataRecord, include_sha256: bool) -> MatchSpec {
+    let package_record = &record.package_record;
+    MatchSpec {
+        name: Some(package_record.name.clone()),
+        version: Some(VersionSpec::Exact(
+            EqualityOperator::Equals,
+            package_record.version.version().clone(),
+        )),
+        build: Some(StringMatcher::Exact(package_record.build.clone())),
+        sha256: package_record.sha256.filter(|_| include_sha256),
+        ..MatchSpec::default()
+    }
+}
+
+fn deserialize_filename_and_raw_record<'d, D: Deserializer<'d>>(
+    deserializer: D,
+) -> Result<Vec<(PackageFilename<'d>, &'d RawValue)>, D::Error> {
+    let mut entries: Vec<(PackageFilename<'d>, &'d RawValue)> =
+        deserializer.deserialize_map(MapVisitor(PhantomData))?;
+
+    // Although in general the filenames are sorted in repodata.json this doesnt necessarily mean
+    // that the records are also sorted by package name.
+    //
+    // To illustrate, the following filenames are properly sorted by filename but they are NOT
+    // properly sorted by package name.
+    // - clang-format-12.0.1-default_he082bbe_4.tar.bz2 (package name: clang-format)
+    // - clang-format-13-13.0.0-default_he082bbe_0.tar.bz2 (package name: clang-format-13)
+    // - clang-format-13.0.0-default_he082bbe_0.tar.bz2 (package name: clang-format)
+    //
+    // Because most use-cases involve finding filenames by package name we reorder the entries here
+    // by package name. This enables use the binary search for the packages we need.
+    //
+    // Since (in most cases) the repodata is already ordered by filename which does closely resemble
+    // ordering by package name this sort operation will most likely be very fast.
+    entries.sort_by(|(a, _), (b, _)| a.package.cmp(b.package));
+
+    return Ok(entries);
+
+    #[allow(clippy::type_complexity)]
+    struct MapVisitor<I, K, V>(PhantomData<fn() -> (I, K, V)>);
+
+    impl<'de, I, K, V> Visitor<'de> for MapVisitor<I, K, V>
+    where
+        I: FromIterator<(K, V)>,
+        K: Deserialize<'de>,
+        V: Deserialize<'de>,
+    {
+        type Value = I;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("a map")
+        }
+
+        fn visit_map<A>(self, map: A) -> Result<Self::Value, A::Error>
+        where
+            A: MapAccess<'de>,
+        {
+            let iter = MapIter(map, PhantomData);
+            iter.collect()
+        }
+    }
+
+    struct MapIter<'de, A, K, V>(A, PhantomData<(&'de (), A, K, V)>);
+
+    impl<'de, A, K, V> Iterator for MapIter<'de, A, K, V>
+    where
+        A: MapAccess<'de>,
+        K: Deserialize<'de>,
+        V: Deserialize<'de>,
+    {
+        type Item = Result<(K, V), A::Error>;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            match self.0.next_entry() {
+                Ok(Some(x)) => Some(Ok(x)),
+                Ok(None) => None,
+                Err(err) => Some(Err(err)),
+            }
+        }
+    }
+}
+
+/// A struct that holds both a filename and the part of the filename thats just the package name.
+struct PackageFilename<'i> {
+    package: &'i str,
+    filename: &'i str,
+}
+
+impl<'de> Deserialize<'de> for PackageFilename<'de> {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        <&str>::deserialize(deserializer)?
+            .try_into()
+            .map_err(D::Error::custom)
+    }
+}
+
+impl<'de> TryFrom<&'de str> for PackageFilename<'de> {
+    type Error = &'static str;
+
+    fn try_from(s: &'de str) -> Result<Self, Self::Error> {
+        let mut parts = s.rsplitn(3, '-');
+        let version = parts.nth(1).ok_or("invalid filename")?;
+        let package = parts.next().ok_or("invalid filename")?;
+
+        // The build string itself might contain hyphens in which case the segment we took as the
+        // version is actually part of the build string. Versions always start with a digit so
+        // if that is not the case try to find the actual version.
+        if !starts_with_digit(version) {
+            if let Some(package) = package_name_before_version(s) {
+                tracing::debug!(
+                    "the build string of '{s}' contains a '-', assuming '{package}' is the package name"
+                );
+                return Ok(PackageFilename {
+                    package,
+                    filename: s,
+                });
+            }
+        }
+
+        Ok(PackageFilename {
+            package,
+            filename: s,
+        })
+    }
+}
+
+/// The components of a package filename as returned by [`parse_package_filename`].
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct ParsedFilename<'i> {
+    /// The name of the package (e.g. `clang-format`)
+    pub name: &'i str,
+
+    /// The version of the package (e.g. `13.0.1`)
+    pub version: &'i str,
+
+    /// The build string of the package (e.g. `root_62800_h69bbbaa_1`)
+    pub build: &'i str,
+
+    /// The extension of the archive including the leading dot (e.g. `.conda`)
+    pub extension: &'i str,
+}
+
+/// Splits the filename of a package archive (e.g. `clang-format-13.0.1-root_62800_h69bbbaa_1.conda`)
+/// into the name, version and build string of the package and the archive extension.
+///
+/// Package names can contain hyphens and some build strings do as well. Since versions never
+/// contain hyphens and always start with a digit, this is used to find the actual version segment.
+/// Returns `None` if the filename does not have a known archive extension or does not consist of
+/// at least three non-empty hyphen separated segments.
+pub fn parse_package_filename(filename: &str) -> Option<ParsedFilename<'_>> {
+    let (stem, archive_type) = ArchiveType::split_str(filename)?;
+    let PackageFilename { package: name, .. } = PackageFilename::try_from(stem).ok()?;
+    let (version, build) = stem.get(name.len() + 1..)?.split_once('-')?;
+    if name.is_empty() || version.is_empty() || build.is_empty() {
+        return None;
+    }
+    Some(ParsedFilename {
+        name,
+        version,
+        build,
+        extension: archive_type.extension(),
+    })
+}
+
+/// The maximum number of times a `repodata.json` file is mapped and parsed if the file changes
+/// while it is being parsed.
+const MAX_MAP_ATTEMPTS: usize = 2;
+
+/// A source of a memory mapped `repodata.json` file. This abstraction exists to be able to test
+/// the behavior when the file changes while it is being read.
+trait RepoDataFile {
+    /// Memory maps the current contents of the file.
+    fn map(&mut self) -> io::Result<memmap2::Mmap>;
+
+    /// Returns the last modification time of the file if it can be determined.
+    fn modified(&mut self) -> Option<SystemTime>;
+
+    /// Returns the path of the file, used in errors.
+    fn path(&self) -> &Path;
+}
+
+/// A [`RepoDataFile`] on the filesystem.
+struct FsRepoDataFile<'p> {
+    path: &'p Path,
+    hints: MmapHints,
+}
+
+impl RepoDataFile for FsRepoDataFile<'_> {
+    fn map(&mut self) -> io::Result<memmap2::Mmap> {
+        let file = std::fs::File::open(self.path)?;
+        let memory_map = unsafe { memmap2::Mmap::map(&file) }?;
+        apply_mmap_hints(&memory_map, self.hints);
+        Ok(memory_map)
+    }
+
+    fn modified(&mut self) -> Option<SystemTime> {
+        std::fs::metadata(self.path).and_then(|m| m.modified()).ok()
+    }
+
+    fn path(&self) -> &Path {
+        self.path
+    }
+}
+
+/// Passes the `hints` to the operating system. Failing to do so is not an error because the hints
+/// do not affect the contents of the memory map.
+#[cfg(unix)]
+fn apply_mmap_hints(memory_map: &memmap2::Mmap, hints: MmapHints) {
+    let advices = [
+        (hints.sequential, memmap2::Advice::Sequential),
+        (hints.will_need, memmap2::Advice::WillNeed),
+    ];
+    for (_, advice) in advices.into_iter().filter(|(enabled, _)| *enabled) {
+        if let Err(e) = memory_map.advise(advice) {
+            tracing::debug!("failed to advise {advice:?} for memory mapped repodata: {e}");
+        }
+    }
+}
+
+/// Memory map hints are only supported on unix platforms.
+#[cfg(not(unix))]
+fn apply_mmap_hints(_memory_map: &memmap2::Mmap, _hints: MmapHints) {}
+
+/// Memory maps and sparsely parses a `repodata.json` file.
+///
+/// On network filesystems a file that is being replaced might be observed partially written which
+/// results in a parse error. If parsing fails and the modification time of the file changed while
+/// it was being read, the file is mapped and parsed again (at most [`MAX_MAP_ATTEMPTS`] times).
+///
+/// An empty (or whitespace only) file results in a [`SparseRepoDataError::EmptyFile`] instead of
+/// a parse error.
+fn map_repo_data(
+    file: &mut impl RepoDataFile,
+) -> Result<MemmappedSparseRepoDataInner, SparseRepoDataError> {
+    let mut attempt = 1;
+    loop {
+        let modified = file.modified();
+        let memory_map = file.map()?;
+        if strip_utf8_bom(memory_map.as_ref())
+            .iter()
+            .all(u8::is_ascii_whitespace)
+        {
+            if attempt < MAX_MAP_ATTEMPTS && file.modified() != modified {
+                tracing::warn!("repodata.json changed while it was being read, retrying");
+                attempt += 1;
+                continue;
+            }
+            return Err(SparseRepoDataError::EmptyFile {
+                path: file.path().to_path_buf(),
+            });
+        }
+        let result = MemmappedSparseRepoDataInnerTryBuilder {
+            memory_map,
+            repo_data_builder: |memory_map| {
+                parse_lazy_repo_data(strip_utf8_bom(memory_map.as_ref()))
+            },
+        }
+        .try_build();
+        match result {
+            Ok(inner) => return Ok(inner),
+            Err(err) if attempt < MAX_MAP_ATTEMPTS && file.modified() != modified => {
+                tracing::warn!("repodata.json changed while it was being parsed ({err}), retrying");
+                attempt += 1;
+            }
+            Err(err) => return Err(io::Error::from(err).into()),
+        }
+    }
+}
+
+/// Some mirrors serve `repodata.json` with a leading UTF-8 byte order mark which is not valid
+/// JSON. This returns the bytes without it.
+fn strip_utf8_bom(bytes: &[u8]) -> &[u8] {
+    bytes.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(bytes)
+}
+
+/// Returns true if the string starts with an ascii digit.
+fn starts_with_digit(s: &str) -> bool {
+    s.starts_with(|c: char| c.is_ascii_digit())
+}
+
+/// Finds the package name in a filename by locating the right-most hyphen separated segment that
+/// looks like a version and is followed by at least one other segment (the build string). Returns
+/// `None` if no such segment exists.
+fn package_name_before_version(filename: &str) -> Option<&str> {
+    let segment_starts = filename
+        .match_indices('-')
+        .map(|(idx, _)| idx)
+        .collect::<Vec<_>>();
+
+    // The last segment is always (part of) the build string so skip it.
+    segment_starts
+        .iter()
+        .rev()
+        .skip(1)
+        .find(|&&idx| starts_with_digit(&filename[idx + 1..]))
+        .map(|&idx| &filename[..idx])
+}
+
+/// Walks the dependency graph starting from `package_names` and collects the records of all
+/// packages that are encountered. `load_records` is called with the index of a repodata and the
+/// name of a package and should return the records of that package in that repodata. Packages in
+/// `exclude` are never added to the walk unless they are part of `package_names`.
+///
+/// Virtual packages (e.g. `__glibc`) are provided by the system and never have records in a
+/// repodata, so dependencies on them are not followed.
+fn collect_records_recursive(
+    repo_data_count: usize,
+    package_names: impl IntoIterator<Item = PackageName>,
+    exclude: &HashSet<PackageName>,
+    cancelled: Option<&AtomicBool>,
+    mut load_records: impl FnMut(usize, &PackageName) -> io::Result<Vec<RepoDataRecord>>,
+) -> io::Result<Vec<Vec<RepoDataRecord>>> {
+    // Construct the result map
+    let mut result = Vec::from_iter((0..repo_data_count).map(|_| Vec::new()));
+
+    // Construct a set of packages that we have seen and have been added to the pending list.
+    let mut seen: HashSet<PackageName> = HashSet::from_iter(package_names);
+
+    // Construct a queue to store packages in that still need to be processed
+    let mut pending = VecDeque::from_iter(seen.iter().cloned());
+
+    // Iterate over the list of packages that still need to be processed.
+    while let Some(next_package) = pending.pop_front() {
+        if cancelled.is_some_and(|cancelled| cancelled.load(Ordering::Relaxed)) {
+            return Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "loading the records was cancelled",
+            ));
+        }
+
+        for (i, result) in result.iter_mut().enumerate() {
+            // Get all records from the repodata
+            let mut records = load_records(i, &next_package)?;
+
+            // Iterate over all packages to find recursive dependencies. Different records of
+            // the same package often share most of their dependencies, so we first make sure
+            // that every dependency name is only checked once.
+            let mut dependency_names: HashSet<PackageName> = HashSet::new();
+            for dependency in records
+                .iter()
+                .flat_map(|record| &record.package_record.depends)
+            {
+                let dependency_name = dependency_package_name(dependency);
+                if !dependency_names.insert(dependency_name.clone())
+                    || is_virtual_package(&dependency_name)
+                {
+                    continue;
+                }
+                if !seen.contains(&dependency_name) && !exclude.contains(&dependency_name) {
+                    pending.push_back(dependency_name.clone());
+                    seen.insert(dependency_name);
+                }
+            }
+
+            result.append(&mut records);
+        }
+    }
+
+    Ok(result)
+}
+
+/// Extracts the name of the package from a dependency string (e.g. `"python >=3.8"`).
+///
+/// Malformed dependency strings might contain leading whitespace or uppercase characters. The
+/// returned name is normalized so it can still be found in the index, which only contains
+/// normalized names.
+fn dependency_package_name(dependency: &str) -> PackageName {
+    let name = dependency.split_whitespace().next().unwrap_or_default();
+    PackageName::try_from(name).unwrap_or_else(|_| PackageName::new_unchecked(name))
+}
+
+/// Returns true if the package is a virtual package (e.g. `__glibc` or `__cuda`). These are
+/// resolved from the system and not from repodata.
+fn is_virtual_package(name: &PackageName) -> bool {
+    name.as_normalized().starts_with("__")
+}
+
+/// Returns the key by which records are ordered when a [`RecordSortOrder`] other than
+/// [`RecordSortOrder::FileOrder`] is used.
+fn record_version_key(record: &PackageRecord) -> (&VersionWithSource, u64) {
+    (&record.version, record.build_number)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        collect_records_recursive, exact_match_spec, load_repo_data_recursively,
+        parse_package_filename, MmapHints, NameDelta, PackageFilename, RecordSortOrder,
+        RepoDataDiff, SharedRecordCache, SparseRepoData, SparseRepoDataError, UrlLayout,
+    };
+    use rattler_conda_types::{
+        Channel, ChannelConfig, MatchSpec, PackageName, PackageRecord, Platform, RepoData,
+        RepoDataRecord, VersionSpec,
+    };
+    use rstest::rstest;
+    use std::collections::HashSet;
+    use std::path::{Path, PathBuf};
+    use std::str::FromStr;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use url::Url;
+
+    fn test_dir() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("../../test-data")
+    }
+
+    /// Writes the given `repodata.json` contents to a temporary file and loads it as a
+    /// [`SparseRepoData`].
+    fn sparse_from_str(repodata: &str) -> SparseRepoData {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), repodata).unwrap();
+        SparseRepoData::new(
+            Channel::from_str("conda-forge", &ChannelConfig::default()).unwrap(),
+            Platform::Linux64,
+            file.path(),
+            None,
+        )
+        .unwrap()
+    }
+
+    const SMALL_REPODATA: &str = r#"{
+        "info": { "subdir": "linux-64" },
+        "packages": {
+            "a-1.0-h0_0.tar.bz2": { "name": "a", "version": "1.0", "build": "h0_0", "build_number": 0, "depends": ["b >=1", "c"], "subdir": "linux-64" },
+            "a-1.0-h1_1.tar.bz2": { "name": "a", "version": "1.0", "build": "h1_1", "build_number": 1, "depends": ["b >=1", "c"], "subdir": "linux-64" },
+            "b-1.0-h0_0.tar.bz2": { "name": "b", "version": "1.0", "build": "h0_0", "build_number": 0, "depends": ["c"], "subdir": "linux-64" },
+            "c-1.0-h0_0.tar.bz2": { "name": "c", "version": "1.0", "build": "h0_0", "build_number": 0, "subdir": "linux-64" },
+            "d-1.0-h0_0.tar.bz2": { "name": "d", "version": "1.0", "build": "h0_0", "build_number": 0, "subdir": "linux-64" }
+        }
+    }"#;
+
+    async fn load_sparse(
+        package_names: impl IntoIterator<Item = impl AsRef<str>>,
+    ) -> Vec<Vec<RepoDataRecord>> {
+        load_repo_data_recursively(
+            [
+                (
+                    Channel::from_str("conda-forge", &ChannelConfig::default()).unwrap(),
+                    Platform::NoArch,
+                    test_dir().join("channels/conda-forge/noarch/repodata.json"),
+                ),
+                (
+                    Channel::from_str("conda-forge", &ChannelConfig::default()).unwrap(),
+                    Platform::Linux64,
+                    test_dir().join("channels/conda-forge/linux-64/repodata.json"),
+                ),
+            ],
+            package_names
+                .into_iter()
+                .map(|name| PackageName::try_from(name.as_ref()).unwrap()),
+            None,
+        )
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_empty_sparse_load() {
+        let sparse_empty_data = load_sparse(Vec::<String>::new()).await;
+        assert_eq!(sparse_empty_data, vec![vec![], vec![]]);
+    }
+
+    #[tokio::test]
+    async fn test_sparse_single() {
+        let sparse_empty_data = load_sparse(["_libgcc_mutex"]).await;
+        let total_records = sparse_empty_data
+            .iter()
+            .map(|repo| repo.len())
+            .sum::<usize>();
+
+        assert_eq!(total_records, 3);
+    }
+
+    #[tokio::test]
+    async fn test_parse_duplicate() {
+        let sparse_empty_data = load_sparse(["_libgcc_mutex", "_libgcc_mutex"]).await;
+        let total_records = sparse_empty_data
+            .iter()
+            .map(|repo| repo.len())
+            .sum::<usize>();
+
+        // Number of records should still be 3. The duplicate package name should be ignored.
+        assert_eq!(total_records, 3);
+    }
+
+    #[tokio::test]
+    async fn test_sparse_jupyterlab_detectron2() {
+        let sparse_empty_data = load_sparse(["jupyterlab", "detectron2"]).await;
+
+        let total_records = sparse_empty_data
+            .iter()
+            .map(|repo| repo.len())
+            .sum::<usize>();
+
+        assert_eq!(total_records, 21731);
+    }
+
+    #[test]
+    fn test_sparse_recursive_shared_dependencies() {
+        let sparse = sparse_from_str(SMALL_REPODATA);
+        let records = SparseRepoData::load_records_recursive(
+            [&sparse],
+            [PackageName::new_unchecked("a")],
+            None,
+        )
+        .unwrap();
+
+        let file_names = records[0]
+            .iter()
+            .map(|record| record.file_name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            file_names,
+            vec![
+                "a-1.0-h0_0.tar.bz2",
+                "a-1.0-h1_1.tar.bz2",
+                "b-1.0-h0_0.tar.bz2",
+                "c-1.0-h0_0.tar.bz2"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_from_repo_data() {
+        let sparse = sparse_from_str(SMALL_REPODATA);
+        let repo_data: RepoData = serde_json::from_str(SMALL_REPODATA).unwrap();
+        let parsed = SparseRepoData::from_repo_data(
+            Channel::from_str("conda-forge", &ChannelConfig::default()).unwrap(),
+            Platform::Linux64,
+            repo_data,
+            None,
+        );
+
+        assert_eq!(
+            sparse.package_names().collect::<Vec<_>>(),
+            parsed.package_names().collect::<Vec<_>>()
+        );
+        for name in sparse.package_names() {
+            let name = PackageName::new_unchecked(name);
+            let mut sparse_records = sparse.load_records(&name).unwrap();
+            let mut parsed_records = parsed.load_records(&name).unwrap();
+            sparse_records.sort_by(|a, b| a.file_name.cmp(&b.file_name));
+            parsed_records.sort_by(|a, b| a.file_name.cmp(&b.file_name));
+            assert_eq!(sparse_records, parsed_records);
+        }
+
+        let recursive = SparseRepoData::load_records_recursive(
+            [&parsed],
+            [PackageName::new_unchecked("a")],
+            None,
+        )
+        .unwrap();
+        assert_eq!(recursive[0].len(), 4);
+    }
+
+    #[test]
+    fn test_closure_package_names() {
+        let sparse = sparse_from_str(SMALL_REPODATA);
+        let closure =
+            SparseRepoData::closure_package_names([&sparse], [PackageName::new_unchecked("a")])
+                .unwrap();
+
+        let records = SparseRepoData::load_records_recursive(
+            [&sparse],
+            [PackageName::new_unchecked("a")],
+            None,
+        )
+        .unwrap();
+        let record_names = records
+            .iter()
+            .flatten()
+            .map(|record| record.package_record.name.clone())
+            .collect::<HashSet<_>>();
+
+        assert_eq!(closure, record_names);
+        assert_eq!(closure.len(), 3);
+    }
+
+    #[test]
+    fn test_closure_package_names_skips_virtual_packages() {
+        let repodata = r#"{
+            "info": { "subdir": "linux-64" },
+            "packages": {
+                "a-1.0-h0_0.tar.bz2": { "name": "a", "version": "1.0", "build": "h0_0", "build_number": 0, "depends": ["__glibc >=2.17", "b"], "subdir": "linux-64" },
+                "b-1.0-h0_0.tar.bz2": { "name": "b", "version": "1.0", "build": "h0_0", "build_number": 0, "depends": ["__cuda"], "subdir": "linux-64" }
+            }
+        }"#;
+        let sparse = sparse_from_str(repodata);
+        let closure =
+            SparseRepoData::closure_package_names([&sparse], [PackageName::new_unchecked("a")])
+                .unwrap();
+
+        let records = SparseRepoData::load_records_recursive(
+            [&sparse],
+            [PackageName::new_unchecked("a")],
+            None,
+        )
+        .unwrap();
+        let record_names = records
+            .iter()
+            .flatten()
+            .map(|record| record.package_record.name.clone())
+            .collect::<HashSet<_>>();
+
+        assert_eq!(closure, record_names);
+        assert_eq!(
+            closure,
+            HashSet::from_iter([
+                PackageName::new_unchecked("a"),
+                PackageName::new_unchecked("b")
+            ])
+        );
+    }
+
+    #[test]
+    fn test_load_records_recursive_multi() {
+        let sparse = sparse_from_str(SMALL_REPODATA);
+        let seed_sets = vec![
+            vec![PackageName::new_unchecked("a")],
+            vec![
+                PackageName::new_unchecked("b"),
+                PackageName::new_unchecked("d"),
+            ],
+        ];
+
+        let file_names = |records: &Vec<Vec<RepoDataRecord>>| {
+            records
+                .iter()
+                .map(|records| {
+                    records
+                        .iter()
+                        .map(|record| record.file_name.clone())
+                        .collect::<HashSet<_>>()
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let multi =
+            SparseRepoData::load_records_recursive_multi([&sparse], &seed_sets, None).unwrap();
+        assert_eq!(multi.len(), seed_sets.len());
+        for (seed_set, records) in seed_sets.iter().zip(&multi) {
+            let individual =
+                SparseRepoData::load_records_recursive([&sparse], seed_set.iter().cloned(), None)
+                    .unwrap();
+            assert_eq!(file_names(records), file_names(&individual));
+        }
+        assert_eq!(multi[0][0].len(), 4);
+        assert_eq!(multi[1][0].len(), 3);
+    }
+
+    #[test]
+    fn test_dependency_index() {
+        let sparse = sparse_from_str(SMALL_REPODATA);
+        let index = sparse.build_dependency_index().unwrap();
+
+        let dependents = |name: &str| {
+            let mut dependents = index.dependents(&PackageName::new_unchecked(name)).to_vec();
+            dependents.sort();
+            dependents
+        };
+        assert_eq!(
+            dependents("c"),
+            [
+                "a-1.0-h0_0.tar.bz2",
+                "a-1.0-h1_1.tar.bz2",
+                "b-1.0-h0_0.tar.bz2"
+            ]
+        );
+        assert_eq!(
+            dependents("b"),
+            ["a-1.0-h0_0.tar.bz2", "a-1.0-h1_1.tar.bz2"]
+        );
+        assert!(dependents("a").is_empty());
+    }
+
+    #[test]
+    fn test_load_records_recursive_excluding() {
+        let sparse = sparse_from_str(SMALL_REPODATA);
+        let record_names = |exclude: &[&str], seeds: &[&str]| {
+            let exclude = exclude
+                .iter()
+                .map(|name| PackageName::new_unchecked(*name))
+                .collect();
+            SparseRepoData::load_records_recursive_excluding(
+                [&sparse],
+                seeds.iter().map(|name| PackageName::new_unchecked(*name)),
+                &exclude,
+                None,
+            )
+            .unwrap()
+            .into_iter()
+            .flatten()
+            .map(|record| record.package_record.name.as_normalized().to_owned())
+            .collect::<HashSet<_>>()
+        };
+
+        // `c` is still reachable directly from `a`
+        assert_eq!(
+            record_names(&["b"], &["a"]),
+            HashSet::from(["a".to_owned(), "c".to_owned()])
+        );
+
+        // `c` is only reachable through `b`
+        assert_eq!(
+            record_names(&["b"], &["d"]),
+            HashSet::from(["d".to_owned()])
+        );
+
+        // Seeds are always loaded
+        assert_eq!(
+            record_names(&["b"], &["b"]),
+            HashSet::from(["b".to_owned(), "c".to_owned()])
+        );
+    }
+
+    #[test]
+    fn test_load_all_records_lenient() {
+        let repodata = r#"{
+            "info": { "subdir": "linux-64" },
+            "packages": {
+                "a-1.0-h0_0.tar.bz2": { "name": "a", "version": "1.0", "build": "h0_0", "build_number": 0, "subdir": "linux-64" },
+                "b-1.0-h0_0.tar.bz2": { "name": "b", "version": "1.0", "build": "h0_0", "build_number": "zero", "subdir": "linux-64" },
+                "c-1.0-h0_0.tar.bz2": { "name": "c", "version": "1.0", "build": "h0_0", "build_number": 0, "subdir": "linux-64" }
+            }
+        }"#;
+        let sparse = sparse_from_str(repodata);
+
+        // Loading the broken package strictly fails
+        assert!(sparse
+            .load_records(&PackageName::new_unchecked("b"))
+            .is_err());
+
+        let (records, errors) = sparse.load_all_records_lenient();
+        assert_eq!(
+            records
+                .iter()
+                .map(|record| record.file_name.as_str())
+                .collect::<Vec<_>>(),
+            ["a-1.0-h0_0.tar.bz2", "c-1.0-h0_0.tar.bz2"]
+        );
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].0, "b-1.0-h0_0.tar.bz2");
+    }
+
+    #[test]
+    fn test_shared_cache() {
+        let cache = SharedRecordCache::new(10);
+        let first = sparse_from_str(SMALL_REPODATA).with_shared_cache(cache.clone());
+        let second = sparse_from_str(SMALL_REPODATA)
+            .with_shared_cache(cache.clone())
+            .with_url_rewrite_fn(|url, _| url.set_host(Some("mirror.example.com")).unwrap());
+
+        let package_name = PackageName::new_unchecked("a");
+        let first_records = first.load_records(&package_name).unwrap();
+        let second_records = second.load_records(&package_name).unwrap();
+        assert_eq!(cache.misses(), 1);
+        assert_eq!(cache.hits(), 1);
+        assert_eq!(cache.len(), 1);
+
+        // Post processing is still applied per instance
+        assert_eq!(first_records.len(), second_records.len());
+        assert_eq!(first_records[0].url.host_str(), Some("conda.anaconda.org"));
+        assert_eq!(second_records[0].url.host_str(), Some("mirror.example.com"));
+    }
+
+    #[test]
+    fn test_shared_cache_different_content() {
+        let cache = SharedRecordCache::new(10);
+        let first = sparse_from_str(SMALL_REPODATA).with_shared_cache(cache.clone());
+        let second = sparse_from_str(&SMALL_REPODATA.replace("h1_1", "h2_1"))
+            .with_shared_cache(cache.clone());
+
+        // Both instances refer to the same channel and subdir but their content differs, so the
+        // records must not be shared.
+        let package_name = PackageName::new_unchecked("a");
+        let first_records = first.load_records(&package_name).unwrap();
+        let second_records = second.load_records(&package_name).unwrap();
+        assert_eq!(cache.misses(), 2);
+        assert_eq!(cache.len(), 2);
+        assert_ne!(first_records, second_records);
+    }
+
+    #[test]
+    fn test_repodata_version() {
+        assert_eq!(sparse_from_str(SMALL_REPODATA).repodata_version(), None);
+
+        let sparse = sparse_from_str(
+            r#"{
+                "info": { "subdir": "linux-64" },
+                "repodata_version": 2,
+                "packages": {}
+            }"#,
+        );
+        assert_eq!(sparse.repodata_version(), Some(2));
+
+        let repo_data: RepoData = serde_json::from_str(
+            r#"{ "info": { "subdir": "linux-64" }, "repodata_version": 1, "packages": {} }"#,
+        )
+        .unwrap();
+        let parsed = SparseRepoData::from_repo_data(
+            Channel::from_str("conda-forge", &ChannelConfig::default()).unwrap(),
+            Platform::Linux64,
+            repo_data,
+            None,
+        );
+        assert_eq!(parsed.repodata_version(), Some(1));
+    }
+
+    #[test]
+    fn test_load_records_recursive_cancellable() {
+        static CANCELLED: AtomicBool = AtomicBool::new(false);
+
+        // The patch function is called for every loaded record so it is used as a hook to cancel
+        // the load after the records of the first package have been loaded.
+        fn cancel(_: &mut PackageRecord) {
+            CANCELLED.store(true, Ordering::Relaxed);
+        }
+
+        let sparse = sparse_from_str(SMALL_REPODATA);
+        let records = SparseRepoData::load_records_recursive_cancellable(
+            [&sparse],
+            [PackageName::new_unchecked("a")],
+            None,
+            &CANCELLED,
+        )
+        .unwrap();
+        assert_eq!(records[0].len(), 4);
+
+        let err = SparseRepoData::load_records_recursive_cancellable(
+            [&sparse],
+            [PackageName::new_unchecked("a")],
+            Some(cancel),
+            &CANCELLED,
+        )
+        .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::Interrupted);
+    }
+
+    #[test]
+    fn test_load_record_by_filename() {
+        let sparse = sparse_from_str(SMALL_REPODATA);
+        let parsed = SparseRepoData::from_repo_data(
+            Channel::from_str("conda-forge", &ChannelConfig::default()).unwrap(),
+            Platform::Linux64,
+            serde_json::from_str(SMALL_REPODATA).unwrap(),
+            None,
+        );
+
+        for repo_data in [&sparse, &parsed] {
+            let record = repo_data
+                .load_record_by_filename("a-1.0-h1_1.tar.bz2")
+                .unwrap()
+                .unwrap();
+            assert_eq!(record.file_name, "a-1.0-h1_1.tar.bz2");
+            assert_eq!(record.package_record.build, "h1_1");
+            assert_eq!(
+                record.url.as_str(),
+                "https://conda.anaconda.org/conda-forge/linux-64/a-1.0-h1_1.tar.bz2"
+            );
+
+            assert!(repo_data
+                .load_record_by_filename("a-2.0-h0_0.tar.bz2")
+                .unwrap()
+                .is_none());
+            assert!(repo_data
+                .load_record_by_filename("invalid")
+                .unwrap()
+                .is_none());
+        }
+    }
+
+    #[test]
+    fn test_content_hash() {
+        let sparse = sparse_from_str(SMALL_REPODATA);
+        assert_eq!(
+            sparse.content_hash(),
+            sparse_from_str(SMALL_REPODATA).content_hash()
+        );
+        assert_eq!(sparse.content_hash(), sparse.clone().content_hash());
+
+        let other = sparse_from_str(&SMALL_REPODATA.replace("h1_1", "h2_1"));
+        assert_ne!(sparse.content_hash(), other.content_hash());
+
+        let parsed = |repodata: &str| {
+            SparseRepoData::from_repo_data(
+                Channel::from_str("conda-forge", &ChannelConfig::default()).unwrap(),
+                Platform::Linux64,
+                serde_json::from_str(repodata).unwrap(),
+                None,
+            )
+        };
+        assert_eq!(
+            parsed(SMALL_REPODATA).content_hash(),
+            parsed(SMALL_REPODATA).content_hash()
+        );
+        assert_ne!(
+            parsed(SMALL_REPODATA).content_hash(),
+            parsed(&SMALL_REPODATA.replace("h1_1", "h2_1")).content_hash()
+        );
+    }
+
+    #[test]
+    fn test_clone() {
+        let sparse = sparse_from_str(SMALL_REPODATA);
+        let cloned = sparse.clone();
+        assert!(std::sync::Arc::ptr_eq(&sparse.inner, &cloned.inner));
+
+        let package_name = PackageName::new_unchecked("a");
+        let from_cloned = std::thread::spawn(move || cloned.load_records(&package_name).unwrap())
+            .join()
+            .unwrap();
+        assert_eq!(
+            sparse
+                .load_records(&PackageName::new_unchecked("a"))
+                .unwrap(),
+            from_cloned
+        );
+    }
+
+    #[test]
+    fn test_versions() {
+        let repodata = r#"{
+            "info": { "subdir": "linux-64" },
+            "packages": {
+                "a-1.0-h0_1.tar.bz2": { "name": "a", "version": "1.0", "build": "h0_1", "build_number": 1, "subdir": "linux-64" },
+                "a-1.10-h0_0.tar.bz2": { "name": "a", "version": "1.10", "build": "h0_0", "build_number": 0, "subdir": "linux-64" },
+                "a-1.0-h0_0.tar.bz2": { "name": "a", "version": "1.0", "build": "h0_0", "build_number": 0, "subdir": "linux-64" },
+                "a-1.2-h0_0.tar.bz2": { "name": "a", "version": "1.2", "build": "h0_0", "build_number": 0, "subdir": "linux-64" }
+            },
+            "packages.conda": {
+                "a-1.0-h0_1.conda": { "name": "a", "version": "1.0", "build": "h0_1", "build_number": 1, "subdir": "linux-64" }
+            }
+        }"#;
+        let versions = sparse_from_str(repodata)
+            .versions(&PackageName::new_unchecked("a"))
+            .unwrap()
+            .into_iter()
+            .map(|(version, build)| format!("{version}-{build}"))
+            .collect::<Vec<_>>();
+        assert_eq!(versions, ["1.10-h0_0", "1.2-h0_0", "1.0-h0_1", "1.0-h0_0"]);
+    }
+
+    #[test]
+    fn test_url_rewrite_fn() {
+        let sparse = sparse_from_str(SMALL_REPODATA).with_url_rewrite_fn(|url, record| {
+            assert!(url.path().ends_with(".tar.bz2"));
+            assert!(!record.name.as_normalized().is_empty());
+            url.set_host(Some("mirror.example.com")).unwrap();
+        });
+
+        let records = SparseRepoData::load_records_recursive(
+            [&sparse],
+            [PackageName::new_unchecked("a")],
+            None,
+        )
+        .unwrap();
+        assert_eq!(records[0].len(), 4);
+        for record in &records[0] {
+            assert_eq!(record.url.host_str(), Some("mirror.example.com"));
+            assert!(record.url.path().ends_with(&record.file_name));
+        }
+    }
+
+    #[test]
+    fn test_url_layout_flat() {
+        let sparse = sparse_from_str(SMALL_REPODATA).with_url_layout(UrlLayout::Flat);
+
+        let records = sparse
+            .load_records(&PackageName::new_unchecked("a"))
+            .unwrap();
+        assert_eq!(records.len(), 2);
+        for record in &records {
+            assert_eq!(
+                record.url.as_str(),
+                format!(
+                    "https://conda.anaconda.org/conda-forge/{}",
+                    record.file_name
+                )
+            );
+        }
+
+        let record = sparse_from_str(SMALL_REPODATA)
+            .load_record_by_filename("c-1.0-h0_0.tar.bz2")
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            record.url.as_str(),
+            "https://conda.anaconda.org/conda-forge/linux-64/c-1.0-h0_0.tar.bz2"
+        );
+    }
+
+    #[test]
+    fn test_subdir_url() {
+        let sparse = sparse_from_str(SMALL_REPODATA)
+            .with_url_layout(UrlLayout::Flat)
+            .with_subdir_url(Url::parse("https://mirror.example/custom/").unwrap());
+
+        let records = sparse
+            .load_records(&PackageName::new_unchecked("a"))
+            .unwrap();
+        assert_eq!(records.len(), 2);
+        for record in &records {
+            assert_eq!(
+                record.url.as_str(),
+                format!("https://mirror.example/custom/{}", record.file_name)
+            );
+        }
+    }
+
+    #[test]
+    fn test_dedup_depends() {
+        let repodata = r#"{
+            "info": { "subdir": "linux-64" },
+            "packages": {
+                "a-1.0-h0_0.tar.bz2": { "name": "a", "version": "1.0", "build": "h0_0", "build_number": 0, "depends": ["b >=1", "c", "b >=1"], "constrains": ["d", "d"], "subdir": "linux-64" }
+            }
+        }"#;
+
+        let records = sparse_from_str(repodata)
+            .load_records(&PackageName::new_unchecked("a"))
+            .unwrap();
+        assert_eq!(records[0].package_record.depends, vec!["b >=1", "c"]);
+        assert_eq!(records[0].package_record.constrains, vec!["d"]);
+    }
+
+    #[test]
+    fn test_recursive_malformed_depends() {
+        let repodata = r#"{
+            "info": { "subdir": "linux-64" },
+            "packages": {
+                "a-1.0-h0_0.tar.bz2": { "name": "a", "version": "1.0", "build": "h0_0", "build_number": 0, "depends": [" b >=1", "C"], "subdir": "linux-64" },
+                "b-1.0-h0_0.tar.bz2": { "name": "b", "version": "1.0", "build": "h0_0", "build_number": 0, "subdir": "linux-64" },
+                "c-1.0-h0_0.tar.bz2": { "name": "c", "version": "1.0", "build": "h0_0", "build_number": 0, "subdir": "linux-64" }
+            }
+        }"#;
+        let sparse = sparse_from_str(repodata);
+
+        let records = SparseRepoData::load_records_recursive(
+            [&sparse],
+            [PackageName::new_unchecked("a")],
+            None,
+        )
+        .unwrap();
+        let mut file_names = records[0]
+            .iter()
+            .map(|record| record.file_name.as_str())
+            .collect::<Vec<_>>();
+        file_names.sort();
+        assert_eq!(
+            file_names,
+            vec![
+                "a-1.0-h0_0.tar.bz2",
+                "b-1.0-h0_0.tar.bz2",
+                "c-1.0-h0_0.tar.bz2"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_recursive_skips_virtual_packages() {
+        let repodata = r#"{
+            "info": { "subdir": "linux-64" },
+            "packages": {
+                "a-1.0-h0_0.tar.bz2": { "name": "a", "version": "1.0", "build": "h0_0", "build_number": 0, "depends": ["__glibc >=2.17", "b"], "subdir": "linux-64" },
+                "b-1.0-h0_0.tar.bz2": { "name": "b", "version": "1.0", "build": "h0_0", "build_number": 0, "depends": ["__cuda"], "subdir": "linux-64" }
+            }
+        }"#;
+        let sparse = sparse_from_str(repodata);
+
+        let mut searched = Vec::new();
+        let records = collect_records_recursive(
+            1,
+            [PackageName::new_unchecked("a")],
+            &HashSet::new(),
+            None,
+            |_, name| {
+                searched.push(name.as_normalized().to_owned());
+                sparse.load_records(name)
+            },
+        )
+        .unwrap();
+        assert_eq!(searched, vec!["a", "b"]);
+        assert_eq!(records[0].len(), 2);
+    }
+
+    #[test]
+    fn test_record_counts() {
+        let sparse = sparse_from_str(SMALL_REPODATA);
+        let counts = sparse.record_counts();
 
-    let package_indices =
-        packages.equal_range_by(|(package, _)| package.package.cmp(package_name.as_normalized()));
-    let mut result = Vec::with_capacity(package_indices.len());
-    for (key, raw_json) in &packages[package_indices] {
-        let mut package_record: PackageRecord = serde_json::from_str(raw_json.get())?;
-        // Overwrite subdir if its empty
-        if package_record.subdir.is_empty() {
-            package_record.subdir = subdir.to_owned();
-        }
-        result.push(RepoDataRecord {
-            url: compute_package_url(
-                &channel
-                    .base_url
-                    .join(&format!("{}/", &package_record.subdir))
-                    .expect("failed determine repo_base_url"),
-                base_url,
-                key.filename,
-            ),
-            channel: channel_name.clone(),
-            package_record,
-            file_name: key.filename.to_owned(),
-        });
+        assert_eq!(counts.get("a"), Some(&2));
+        assert_eq!(counts.get("d"), Some(&1));
+        assert_eq!(counts.len(), 4);
+        assert_eq!(
+            counts.values().sum::<usize>(),
+            sparse.load_all_records_lenient().0.len()
+        );
     }
 
-    // Apply the patch function if one was specified
-    if let Some(patch_fn) = patch_function {
-        for record in &mut result {
-            patch_fn(&mut record.package_record);
+    #[test]
+    fn test_patch_filter() {
+        static PATCHED: AtomicUsize = AtomicUsize::new(0);
+        fn patch(record: &mut PackageRecord) {
+            PATCHED.fetch_add(1, Ordering::SeqCst);
+            record.depends.push(String::from("pip"));
         }
+
+        let repodata = r#"{
+            "info": { "subdir": "linux-64" },
+            "packages": {
+                "python-3.11.0-h0_0.tar.bz2": { "name": "python", "version": "3.11.0", "build": "h0_0", "build_number": 0, "depends": ["openssl"], "subdir": "linux-64" },
+                "openssl-3.0.0-h0_0.tar.bz2": { "name": "openssl", "version": "3.0.0", "build": "h0_0", "build_number": 0, "subdir": "linux-64" }
+            }
+        }"#;
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), repodata).unwrap();
+        let sparse = SparseRepoData::new(
+            Channel::from_str("conda-forge", &ChannelConfig::default()).unwrap(),
+            Platform::Linux64,
+            file.path(),
+            Some(patch),
+        )
+        .unwrap()
+        .with_patch_filter(|record| record.name.as_normalized() == "python");
+
+        let records = sparse
+            .load_records(&PackageName::new_unchecked("openssl"))
+            .unwrap();
+        assert_eq!(PATCHED.load(Ordering::SeqCst), 0);
+        assert!(records[0].package_record.depends.is_empty());
+
+        let records = sparse
+            .load_records(&PackageName::new_unchecked("python"))
+            .unwrap();
+        assert_eq!(PATCHED.load(Ordering::SeqCst), 1);
+        assert_eq!(records[0].package_record.depends, vec!["openssl", "pip"]);
     }
 
-    Ok(result)
-}
+    #[test]
+    fn test_has_version_in_range() {
+        let repodata = r#"{
+            "info": { "subdir": "linux-64" },
+            "packages": {
+                "numpy-1.19.5-h0_0.tar.bz2": { "name": "numpy", "version": "1.19.5", "build": "h0_0", "build_number": 0, "subdir": "linux-64" },
+                "numpy-1.26.0-h0_0.tar.bz2": { "name": "numpy", "version": "1.26.0", "build": "h0_0", "build_number": 0, "subdir": "linux-64" }
+            }
+        }"#;
+        let parsed: RepoData = serde_json::from_str(repodata).unwrap();
+        let channel = Channel::from_str("conda-forge", &ChannelConfig::default()).unwrap();
+        let numpy = PackageName::new_unchecked("numpy");
 
-/// A helper function that immediately loads the records for the given packages (and their dependencies).
-/// Records for the specified packages are loaded from the repodata files.
-/// The patch_record_fn is applied to each record after it has been parsed and can mutate the record after
-/// it has been loaded.
-pub async fn load_repo_data_recursively(
-    repo_data_paths: impl IntoIterator<Item = (Channel, impl Into<String>, impl AsRef<Path>)>,
-    package_names: impl IntoIterator<Item = PackageName>,
-    patch_function: Option<fn(&mut PackageRecord)>,
-) -> Result<Vec<Vec<RepoDataRecord>>, io::Error> {
-    // Open the different files and memory map them to get access to their bytes. Do this in parallel.
-    let lazy_repo_data = stream::iter(repo_data_paths)
-        .map(|(channel, subdir, path)| {
-            let path = path.as_ref().to_path_buf();
-            let subdir = subdir.into();
-            tokio::task::spawn_blocking(move || {
-                SparseRepoData::new(channel, subdir, path, patch_function)
-            })
-            .unwrap_or_else(|r| match r.try_into_panic() {
-                Ok(panic) => std::panic::resume_unwind(panic),
-                Err(err) => Err(io::Error::new(io::ErrorKind::Other, err.to_string())),
-            })
-        })
-        .buffered(50)
-        .try_collect::<Vec<_>>()
-        .await?;
+        for sparse in [
+            sparse_from_str(repodata),
+            SparseRepoData::from_repo_data(channel, Platform::Linux64, parsed, None),
+        ] {
+            let has_version = |spec: &str| {
+                sparse
+                    .has_version_in_range(&numpy, &VersionSpec::from_str(spec).unwrap())
+                    .unwrap()
+            };
+            assert!(has_version(">=1.20,<1.27"));
+            assert!(!has_version(">=1.20,<1.26"));
+            assert!(!has_version(">=2,<1"));
+            assert!(!sparse
+                .has_version_in_range(&PackageName::new_unchecked("scipy"), &VersionSpec::Any)
+                .unwrap());
+        }
+    }
 
-    SparseRepoData::load_records_recursive(&lazy_repo_data, package_names, patch_function)
-}
+    #[test]
+    fn test_mmap_hints() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), SMALL_REPODATA).unwrap();
+        let load_all = |hints: MmapHints| {
+            let sparse = SparseRepoData::new_with_mmap_hints(
+                Channel::from_str("conda-forge", &ChannelConfig::default()).unwrap(),
+                Platform::Linux64,
+                file.path(),
+                None,
+                hints,
+            )
+            .unwrap();
+            let package_names = sparse
+                .package_names()
+                .map(PackageName::new_unchecked)
+                .collect::<Vec<_>>();
+            package_names
+                .iter()
+                .flat_map(|name| sparse.load_records(name).unwrap())
+                .collect::<Vec<_>>()
+        };
 
-fn deserialize_filename_and_raw_record<'d, D: Deserializer<'d>>(
-    deserializer: D,
-) -> Result<Vec<(PackageFilename<'d>, &'d RawValue)>, D::Error> {
-    let mut entries: Vec<(PackageFilename<'d>, &'d RawValue)> =
-        deserializer.deserialize_map(MapVisitor(PhantomData))?;
+        let records = load_all(MmapHints::default());
+        assert_eq!(records.len(), 5);
+        assert_eq!(
+            load_all(MmapHints {
+                sequential: true,
+                will_need: true,
+            }),
+            records
+        );
+    }
 
-    // Although in general the filenames are sorted in repodata.json this doesnt necessarily mean
-    // that the records are also sorted by package name.
-    //
-    // To illustrate, the following filenames are properly sorted by filename but they are NOT
-    // properly sorted by package name.
-    // - clang-format-12.0.1-default_he082bbe_4.tar.bz2 (package name: clang-format)
-    // - clang-format-13-13.0.0-default_he082bbe_0.tar.bz2 (package name: clang-format-13)
-    // - clang-format-13.0.0-default_he082bbe_0.tar.bz2 (package name: clang-format)
-    //
-    // Because most use-cases involve finding filenames by package name we reorder the entries here
-    // by package name. This enables use the binary search for the packages we need.
-    //
-    // Since (in most cases) the repodata is already ordered by filename which does closely resemble
-    // ordering by package name this sort operation will most likely be very fast.
-    entries.sort_by(|(a, _), (b, _)| a.package.cmp(b.package));
+    #[test]
+    fn test_diff() {
+        let before = sparse_from_str(SMALL_REPODATA);
+        let mut repodata: serde_json::Value = serde_json::from_str(SMALL_REPODATA).unwrap();
+        let packages = repodata["packages"].as_object_mut().unwrap();
+        packages.remove("b-1.0-h0_0.tar.bz2").unwrap();
+        let after = sparse_from_str(&repodata.to_string());
 
-    return Ok(entries);
+        let diff = before.diff(&after);
+        assert_eq!(diff.removed, vec![String::from("b-1.0-h0_0.tar.bz2")]);
+        assert!(diff.added.is_empty());
 
-    #[allow(clippy::type_complexity)]
-    struct MapVisitor<I, K, V>(PhantomData<fn() -> (I, K, V)>);
+        let diff = after.diff(&before);
+        assert_eq!(diff.added, vec![String::from("b-1.0-h0_0.tar.bz2")]);
+        assert!(diff.removed.is_empty());
 
-    impl<'de, I, K, V> Visitor<'de> for MapVisitor<I, K, V>
-    where
-        I: FromIterator<(K, V)>,
-        K: Deserialize<'de>,
-        V: Deserialize<'de>,
-    {
-        type Value = I;
+        assert_eq!(before.diff(&before), RepoDataDiff::default());
+    }
 
-        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-            formatter.write_str("a map")
-        }
+    #[test]
+    fn test_reload() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("repodata.json");
+        std::fs::write(&path, SMALL_REPODATA).unwrap();
+        let mut sparse = SparseRepoData::new(
+            Channel::from_str("conda-forge", &ChannelConfig::default()).unwrap(),
+            Platform::Linux64,
+            &path,
+            None,
+        )
+        .unwrap();
 
-        fn visit_map<A>(self, map: A) -> Result<Self::Value, A::Error>
-        where
-            A: MapAccess<'de>,
-        {
-            let iter = MapIter(map, PhantomData);
-            iter.collect()
-        }
+        // Replace the file like a downloader would, writing the new contents next to it first.
+        let mut repodata: serde_json::Value = serde_json::from_str(SMALL_REPODATA).unwrap();
+        let packages = repodata["packages"].as_object_mut().unwrap();
+        packages.remove("d-1.0-h0_0.tar.bz2").unwrap();
+        packages.insert(
+            String::from("e-1.0-h0_0.tar.bz2"),
+            serde_json::json!({ "name": "e", "version": "1.0", "build": "h0_0", "build_number": 0, "subdir": "linux-64" }),
+        );
+        let temp_path = dir.path().join("repodata.json.tmp");
+        std::fs::write(&temp_path, repodata.to_string()).unwrap();
+        std::fs::rename(&temp_path, &path).unwrap();
+
+        let delta = sparse.reload().unwrap();
+        assert_eq!(delta.added, vec![String::from("e")]);
+        assert_eq!(delta.removed, vec![String::from("d")]);
+        assert_eq!(
+            sparse
+                .load_records(&PackageName::new_unchecked("e"))
+                .unwrap()
+                .len(),
+            1
+        );
+        assert_eq!(sparse.reload().unwrap(), NameDelta::default());
+
+        let mut in_memory = SparseRepoData::from_repo_data(
+            Channel::from_str("conda-forge", &ChannelConfig::default()).unwrap(),
+            Platform::Linux64,
+            serde_json::from_str(SMALL_REPODATA).unwrap(),
+            None,
+        );
+        assert_eq!(
+            in_memory.reload().unwrap_err().kind(),
+            std::io::ErrorKind::Unsupported
+        );
     }
 
-    struct MapIter<'de, A, K, V>(A, PhantomData<(&'de (), A, K, V)>);
+    #[test]
+    fn test_par_load_records() {
+        let sparse = sparse_from_str(SMALL_REPODATA);
+        let names = ["a", "b", "c", "d", "does-not-exist"]
+            .into_iter()
+            .map(PackageName::new_unchecked)
+            .collect::<Vec<_>>();
 
-    impl<'de, A, K, V> Iterator for MapIter<'de, A, K, V>
-    where
-        A: MapAccess<'de>,
-        K: Deserialize<'de>,
-        V: Deserialize<'de>,
-    {
-        type Item = Result<(K, V), A::Error>;
+        let records = sparse.par_load_records(&names).unwrap();
+        assert_eq!(records.len(), names.len());
+        for (name, records) in names.iter().zip(records) {
+            assert_eq!(records, sparse.load_records(name).unwrap());
+        }
+    }
 
-        fn next(&mut self) -> Option<Self::Item> {
-            match self.0.next_entry() {
-                Ok(Some(x)) => Some(Ok(x)),
-                Ok(None) => None,
-                Err(err) => Some(Err(err)),
-            }
+    #[test]
+    fn test_try_from_path_empty_file() {
+        for contents in ["", " \n\t"] {
+            let file = tempfile::NamedTempFile::new().unwrap();
+            std::fs::write(file.path(), contents).unwrap();
+            let result = SparseRepoData::try_from_path(
+                Channel::from_str("conda-forge", &ChannelConfig::default()).unwrap(),
+                Platform::Linux64,
+                file.path(),
+                None,
+            );
+            assert!(
+                matches!(&result, Err(SparseRepoDataError::EmptyFile { path }) if path == file.path())
+            );
         }
+
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), SMALL_REPODATA).unwrap();
+        let sparse = SparseRepoData::try_from_path(
+            Channel::from_str("conda-forge", &ChannelConfig::default()).unwrap(),
+            Platform::Linux64,
+            file.path(),
+            None,
+        )
+        .unwrap();
+        assert_eq!(sparse.package_names().count(), 4);
     }
-}
 
-/// A struct that holds both a filename and the part of the filename thats just the package name.
-struct PackageFilename<'i> {
-    package: &'i str,
-    filename: &'i str,
-}
+    #[test]
+    fn test_leaf_packages() {
+        let sparse = sparse_from_str(SMALL_REPODATA);
+        assert_eq!(sparse.leaf_packages(false).unwrap(), vec!["c", "d"]);
+        assert_eq!(sparse.leaf_packages(true).unwrap(), vec!["c", "d"]);
 
-impl<'de> Deserialize<'de> for PackageFilename<'de> {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where
-        D: Deserializer<'de>,
-    {
-        <&str>::deserialize(deserializer)?
-            .try_into()
-            .map_err(D::Error::custom)
+        // A second build of `a` without any dependencies
+        let mut repodata: serde_json::Value = serde_json::from_str(SMALL_REPODATA).unwrap();
+        repodata["packages"].as_object_mut().unwrap().insert(
+            String::from("a-2.0-h0_0.tar.bz2"),
+            serde_json::json!({ "name": "a", "version": "2.0", "build": "h0_0", "build_number": 0, "subdir": "linux-64" }),
+        );
+        let sparse = sparse_from_str(&repodata.to_string());
+        assert_eq!(sparse.leaf_packages(false).unwrap(), vec!["a", "c", "d"]);
+        assert_eq!(sparse.leaf_packages(true).unwrap(), vec!["c", "d"]);
     }
-}
 
-impl<'de> TryFrom<&'de str> for PackageFilename<'de> {
-    type Error = &'static str;
+    #[test]
+    fn test_subdir_platform() {
+        let sparse = sparse_from_str(SMALL_REPODATA);
+        assert_eq!(sparse.subdir_platform(), Some(Platform::Linux64));
 
-    fn try_from(s: &'de str) -> Result<Self, Self::Error> {
-        let package = s.rsplitn(3, '-').nth(2).ok_or("invalid filename")?;
-        Ok(PackageFilename {
-            package,
-            filename: s,
-        })
+        let noarch = SparseRepoData::from_repo_data(
+            Channel::from_str("conda-forge", &ChannelConfig::default()).unwrap(),
+            Platform::NoArch,
+            serde_json::from_str(SMALL_REPODATA).unwrap(),
+            None,
+        );
+        assert_eq!(noarch.subdir_platform(), None);
     }
-}
 
-#[cfg(test)]
-mod test {
-    use super::{load_repo_data_recursively, PackageFilename};
-    use rattler_conda_types::{Channel, ChannelConfig, PackageName, RepoData, RepoDataRecord};
-    use rstest::rstest;
-    use std::path::{Path, PathBuf};
+    #[test]
+    fn test_for_each_record() {
+        let sparse = sparse_from_str(SMALL_REPODATA);
+        let package_name = PackageName::new_unchecked("a");
 
-    fn test_dir() -> PathBuf {
-        Path::new(env!("CARGO_MANIFEST_DIR")).join("../../test-data")
+        let mut visited = Vec::new();
+        sparse
+            .for_each_record(&package_name, |record| {
+                visited.push(record.file_name.clone());
+            })
+            .unwrap();
+
+        let loaded = sparse
+            .load_records(&package_name)
+            .unwrap()
+            .into_iter()
+            .map(|record| record.file_name)
+            .collect::<Vec<_>>();
+        assert_eq!(visited.len(), 2);
+        assert_eq!(visited, loaded);
     }
 
-    async fn load_sparse(
-        package_names: impl IntoIterator<Item = impl AsRef<str>>,
-    ) -> Vec<Vec<RepoDataRecord>> {
-        load_repo_data_recursively(
-            [
-                (
-                    Channel::from_str("conda-forge", &ChannelConfig::default()).unwrap(),
-                    "noarch",
-                    test_dir().join("channels/conda-forge/noarch/repodata.json"),
-                ),
-                (
-                    Channel::from_str("conda-forge", &ChannelConfig::default()).unwrap(),
-                    "linux-64",
-                    test_dir().join("channels/conda-forge/linux-64/repodata.json"),
-                ),
-            ],
-            package_names
-                .into_iter()
-                .map(|name| PackageName::try_from(name.as_ref()).unwrap()),
+    #[test]
+    fn test_load_enveloped_repodata() {
+        let sparse = sparse_from_str(&format!(r#"{{ "repodata": {SMALL_REPODATA} }}"#));
+        let records = sparse
+            .load_records(&PackageName::new_unchecked("a"))
+            .unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(sparse.package_names().count(), 4);
+
+        // An envelope with other keys is not unwrapped
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(
+            file.path(),
+            format!(r#"{{ "repodata": {SMALL_REPODATA}, "other": 1 }}"#),
+        )
+        .unwrap();
+        assert!(SparseRepoData::new(
+            Channel::from_str("conda-forge", &ChannelConfig::default()).unwrap(),
+            Platform::Linux64,
+            file.path(),
             None,
         )
-        .await
-        .unwrap()
+        .is_err());
     }
 
-    #[tokio::test]
-    async fn test_empty_sparse_load() {
-        let sparse_empty_data = load_sparse(Vec::<String>::new()).await;
-        assert_eq!(sparse_empty_data, vec![vec![], vec![]]);
-    }
+    #[test]
+    fn test_load_records_top_n() {
+        let sparse = sparse_from_str(
+            r#"{
+            "info": { "subdir": "linux-64" },
+            "packages": {
+                "python-3.10.0-h0_0.tar.bz2": { "name": "python", "version": "3.10.0", "build": "h0_0", "build_number": 0, "subdir": "linux-64" },
+                "python-3.11.0-h0_0.tar.bz2": { "name": "python", "version": "3.11.0", "build": "h0_0", "build_number": 0, "subdir": "linux-64" },
+                "python-3.11.0-h0_1.tar.bz2": { "name": "python", "version": "3.11.0", "build": "h0_1", "build_number": 1, "subdir": "linux-64" },
+                "python-3.8.0-h0_0.tar.bz2": { "name": "python", "version": "3.8.0", "build": "h0_0", "build_number": 0, "subdir": "linux-64" },
+                "python-3.9.0-h0_0.tar.bz2": { "name": "python", "version": "3.9.0", "build": "h0_0", "build_number": 0, "subdir": "linux-64" }
+            }
+        }"#,
+        );
+        let package_name = PackageName::new_unchecked("python");
 
-    #[tokio::test]
-    async fn test_sparse_single() {
-        let sparse_empty_data = load_sparse(["_libgcc_mutex"]).await;
-        let total_records = sparse_empty_data
+        let top = sparse.load_records_top_n(&package_name, 3).unwrap();
+        assert_eq!(
+            top.iter().map(|r| r.file_name.as_str()).collect::<Vec<_>>(),
+            [
+                "python-3.11.0-h0_1.tar.bz2",
+                "python-3.11.0-h0_0.tar.bz2",
+                "python-3.10.0-h0_0.tar.bz2"
+            ]
+        );
+        let all = sparse.load_records(&package_name).unwrap();
+        let oldest_top = top.last().unwrap();
+        assert!(all
             .iter()
-            .map(|repo| repo.len())
-            .sum::<usize>();
+            .filter(|r| !top.contains(r))
+            .all(|r| r.package_record.version < oldest_top.package_record.version));
 
-        assert_eq!(total_records, 3);
+        assert_eq!(
+            sparse.load_records_top_n(&package_name, 10).unwrap().len(),
+            5
+        );
+        assert!(sparse
+            .load_records_top_n(&package_name, 0)
+            .unwrap()
+            .is_empty());
     }
 
-    #[tokio::test]
-    async fn test_parse_duplicate() {
-        let sparse_empty_data = load_sparse(["_libgcc_mutex", "_libgcc_mutex"]).await;
-        let total_records = sparse_empty_data
+    #[test]
+    fn test_exact_match_spec() {
+        let sparse = sparse_from_str(SMALL_REPODATA);
+        let records = sparse
+            .load_records(&PackageName::new_unchecked("a"))
+            .unwrap();
+        let record = records
             .iter()
-            .map(|repo| repo.len())
-            .sum::<usize>();
+            .find(|r| r.package_record.build == "h1_1")
+            .unwrap();
 
-        // Number of records should still be 3. The duplicate package name should be ignored.
-        assert_eq!(total_records, 3);
+        let spec = exact_match_spec(record, false);
+        assert_eq!(spec.to_string(), "a ==1.0 h1_1");
+        let matching = records
+            .iter()
+            .filter(|r| spec.matches(&r.package_record))
+            .collect::<Vec<_>>();
+        assert_eq!(matching, vec![record]);
+
+        // The sha256 is only required if requested and available
+        let mut record = record.clone();
+        let sha256 = rattler_digest::compute_bytes_digest::<rattler_digest::Sha256>(b"a");
+        record.package_record.sha256 = Some(sha256);
+        assert_eq!(exact_match_spec(&record, false).sha256, None);
+        let spec = exact_match_spec(&record, true);
+        assert_eq!(spec.sha256, Some(sha256));
+        assert!(spec.matches(&record.package_record));
+        record.package_record.sha256 =
+            Some(rattler_digest::compute_bytes_digest::<rattler_digest::Sha256>(b"b"));
+        assert!(!spec.matches(&record.package_record));
     }
 
-    #[tokio::test]
-    async fn test_sparse_jupyterlab_detectron2() {
-        let sparse_empty_data = load_sparse(["jupyterlab", "detectron2"]).await;
+    #[test]
+    fn test_find_best_match() {
+        let sparse = sparse_from_str(
+            r#"{
+            "info": { "subdir": "linux-64" },
+            "packages": {
+                "python-3.10.0-h0_0.tar.bz2": { "name": "python", "version": "3.10.0", "build": "h0_0", "build_number": 0, "subdir": "linux-64" },
+                "python-3.11.0-h0_0.tar.bz2": { "name": "python", "version": "3.11.0", "build": "h0_0", "build_number": 0, "subdir": "linux-64" },
+                "python-3.11.0-h0_1.tar.bz2": { "name": "python", "version": "3.11.0", "build": "h0_1", "build_number": 1, "subdir": "linux-64" },
+                "python-3.12.0-h0_0.tar.bz2": { "name": "python", "version": "3.12.0", "build": "h0_0", "build_number": 0, "subdir": "linux-64" },
+                "python-3.9.0-h0_0.tar.bz2": { "name": "python", "version": "3.9.0", "build": "h0_0", "build_number": 0, "subdir": "linux-64" }
+            }
+        }"#,
+        );
+        let find = |spec: &str| {
+            sparse
+                .find_best_match(&MatchSpec::from_str(spec).unwrap())
+                .unwrap()
+                .map(|record| record.file_name)
+        };
 
-        let total_records = sparse_empty_data
-            .iter()
-            .map(|repo| repo.len())
-            .sum::<usize>();
+        assert_eq!(
+            find("python >=3.11").as_deref(),
+            Some("python-3.12.0-h0_0.tar.bz2")
+        );
+        assert_eq!(
+            find("python 3.11.*").as_deref(),
+            Some("python-3.11.0-h0_1.tar.bz2")
+        );
+        assert_eq!(find("python >=4"), None);
+        assert_eq!(find("numpy"), None);
+    }
 
-        assert_eq!(total_records, 21731);
+    #[test]
+    fn test_load_with_utf8_bom() {
+        let repodata = r#"{
+            "info": { "subdir": "linux-64" },
+            "packages": {
+                "_libgcc_mutex-0.1-conda_forge.tar.bz2": { "name": "_libgcc_mutex", "version": "0.1", "build": "conda_forge", "build_number": 0, "subdir": "linux-64" }
+            }
+        }"#;
+        let sparse = sparse_from_str(&format!("\u{feff}{repodata}"));
+        let records = sparse
+            .load_records(&PackageName::new_unchecked("_libgcc_mutex"))
+            .unwrap();
+        assert_eq!(records.len(), 1);
+    }
+
+    /// A [`super::RepoDataFile`] that returns different contents on every attempt and whose
+    /// modification time changes whenever it is queried unless `stable_mtime` is set.
+    struct ChangingRepoDataFile {
+        file: tempfile::NamedTempFile,
+        contents: Vec<&'static str>,
+        attempts: usize,
+        stable_mtime: bool,
+        mtime_queries: u64,
+    }
+
+    impl super::RepoDataFile for ChangingRepoDataFile {
+        fn map(&mut self) -> std::io::Result<memmap2::Mmap> {
+            let contents = self.contents[self.attempts.min(self.contents.len() - 1)];
+            self.attempts += 1;
+            std::fs::write(self.file.path(), contents)?;
+            unsafe { memmap2::Mmap::map(self.file.as_file()) }
+        }
+
+        fn modified(&mut self) -> Option<std::time::SystemTime> {
+            if !self.stable_mtime {
+                self.mtime_queries += 1;
+            }
+            Some(std::time::UNIX_EPOCH + std::time::Duration::from_secs(self.mtime_queries))
+        }
+
+        fn path(&self) -> &Path {
+            self.file.path()
+        }
+    }
+
+    #[test]
+    fn test_map_repo_data_retries_changed_file() {
+        let partial = &SMALL_REPODATA[..SMALL_REPODATA.len() / 2];
+        let mut file = ChangingRepoDataFile {
+            file: tempfile::NamedTempFile::new().unwrap(),
+            contents: vec![partial, SMALL_REPODATA],
+            attempts: 0,
+            stable_mtime: false,
+            mtime_queries: 0,
+        };
+        let inner = super::map_repo_data(&mut file).unwrap();
+        assert_eq!(file.attempts, 2);
+        assert_eq!(inner.borrow_repo_data().packages.len(), 5);
+
+        // If the file did not change, the error is returned immediately
+        let mut file = ChangingRepoDataFile {
+            file: tempfile::NamedTempFile::new().unwrap(),
+            contents: vec![partial, SMALL_REPODATA],
+            attempts: 0,
+            stable_mtime: true,
+            mtime_queries: 0,
+        };
+        assert!(super::map_repo_data(&mut file).is_err());
+        assert_eq!(file.attempts, 1);
+    }
+
+    #[test]
+    fn test_sort_order() {
+        let repodata = r#"{
+            "info": { "subdir": "linux-64" },
+            "packages": {
+                "a-1.0-h0_1.tar.bz2": { "name": "a", "version": "1.0", "build": "h0_1", "build_number": 1, "subdir": "linux-64" },
+                "a-2.0-h0_0.tar.bz2": { "name": "a", "version": "2.0", "build": "h0_0", "build_number": 0, "subdir": "linux-64" },
+                "a-1.0-h0_0.tar.bz2": { "name": "a", "version": "1.0", "build": "h0_0", "build_number": 0, "subdir": "linux-64" }
+            }
+        }"#;
+        let package_name = PackageName::new_unchecked("a");
+        let file_names = |sort_order| {
+            sparse_from_str(repodata)
+                .with_sort_order(sort_order)
+                .load_records(&package_name)
+                .unwrap()
+                .into_iter()
+                .map(|record| record.file_name)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            file_names(RecordSortOrder::NewestFirst),
+            [
+                "a-2.0-h0_0.tar.bz2",
+                "a-1.0-h0_1.tar.bz2",
+                "a-1.0-h0_0.tar.bz2"
+            ]
+        );
+        assert_eq!(
+            file_names(RecordSortOrder::OldestFirst),
+            [
+                "a-1.0-h0_0.tar.bz2",
+                "a-1.0-h0_1.tar.bz2",
+                "a-2.0-h0_0.tar.bz2"
+            ]
+        );
+        assert_eq!(
+            file_names(RecordSortOrder::FileOrder),
+            sparse_from_str(repodata)
+                .load_records(&package_name)
+                .unwrap()
+                .into_iter()
+                .map(|record| record.file_name)
+                .collect::<Vec<_>>()
+        );
     }
 
     #[tokio::test]
@@ -517,7 +3213,36 @@ mod test {
     #[rstest]
     #[case("clang-format-13.0.1-root_62800_h69bbbaa_1.conda", "clang-format")]
     #[case("clang-format-13-13.0.1-default_he082bbe_0.tar.bz2", "clang-format-13")]
+    #[case("foo-bar-1.0-py_0-custom.tar.bz2", "foo-bar")]
+    #[case("foo-1.0-cuda-11_0.conda", "foo")]
     fn test_deserialize_package_name(#[case] filename: &str, #[case] result: &str) {
         assert_eq!(PackageFilename::try_from(filename).unwrap().package, result);
     }
+
+    #[rstest]
+    #[case(
+        "clang-format-13.0.1-root_62800_h69bbbaa_1.conda",
+        Some(("clang-format", "13.0.1", "root_62800_h69bbbaa_1", ".conda"))
+    )]
+    #[case(
+        "clang-format-13-13.0.1-default_he082bbe_0.tar.bz2",
+        Some(("clang-format-13", "13.0.1", "default_he082bbe_0", ".tar.bz2"))
+    )]
+    #[case(
+        "foo-bar-1.0-py_0-custom.tar.bz2",
+        Some(("foo-bar", "1.0", "py_0-custom", ".tar.bz2"))
+    )]
+    #[case("foo-1.0-h0_0.zip", None)]
+    #[case("foo-1.0.conda", None)]
+    #[case("foo-1.0-.conda", None)]
+    fn test_parse_package_filename(
+        #[case] filename: &str,
+        #[case] expected: Option<(&str, &str, &str, &str)>,
+    ) {
+        let parsed = parse_package_filename(filename);
+        assert_eq!(
+            parsed.map(|p| (p.name, p.version, p.build, p.extension)),
+            expected
+        );
+    }
 }
@@ -0,0 +1,130 @@
+//! Defines [`OwnedRepoDataIndex`], a fully owned alternative to [`super::SparseRepoData`] for
+//! repodata that has already been parsed.
+
+use super::ParsedRepoData;
+use itertools::Itertools;
+use rattler_conda_types::{Channel, PackageName, PackageRecord, RepoData, RepoDataRecord, Subdir};
+use std::io;
+
+/// An index over the records of an already parsed [`RepoData`] that provides the same query API
+/// as [`super::SparseRepoData`].
+///
+/// Unlike [`super::SparseRepoData`] this struct does not memory map any file, it owns all of its
+/// records. It is `Send + Sync + 'static` and can therefore be stored in an `Arc` and shared
+/// between threads freely.
+pub struct OwnedRepoDataIndex {
+    /// The records ordered by package name.
+    repo_data: ParsedRepoData,
+
+    /// The channel from which the repodata was downloaded.
+    channel: Channel,
+
+    /// The subdirectory from where the repodata was downloaded.
+    subdir: Subdir,
+
+    /// A function that can be used to patch the package record after it has been loaded.
+    patch_record_fn: Option<fn(&mut PackageRecord)>,
+}
+
+impl OwnedRepoDataIndex {
+    /// Constructs a new index from an already parsed [`RepoData`] and the [`Channel`] it was
+    /// downloaded from.
+    /// The `patch_function` can be used to patch the package record after it has been loaded
+    /// (e.g. to add `pip` to `python`).
+    pub fn new(
+        channel: Channel,
+        subdir: impl Into<Subdir>,
+        repo_data: RepoData,
+        patch_function: Option<fn(&mut PackageRecord)>,
+    ) -> Self {
+        Self {
+            repo_data: ParsedRepoData::from(repo_data),
+            channel,
+            subdir: subdir.into(),
+            patch_record_fn: patch_function,
+        }
+    }
+
+    /// Returns an iterator over all unique package names in the index in sorted order.
+    pub fn package_names(&self) -> impl Iterator<Item = &'_ str> + '_ {
+        self.repo_data
+            .packages
+            .iter()
+            .merge_by(
+                self.repo_data.conda_packages.iter(),
+                |(a, _, _), (b, _, _)| a <= b,
+            )
+            .map(|(name, _, _)| name.as_str())
+            .dedup()
+    }
+
+    /// Returns all the records for the specified package name.
+    pub fn load_records(&self, package_name: &PackageName) -> io::Result<Vec<RepoDataRecord>> {
+        let mut records =
+            self.repo_data
+                .read_records(package_name, &self.channel, self.subdir.as_str());
+        if let Some(patch_fn) = self.patch_record_fn {
+            for record in records.iter_mut() {
+                patch_fn(&mut record.package_record);
+            }
+        }
+        Ok(records)
+    }
+
+    /// Returns the subdirectory from which this repodata was loaded
+    pub fn subdir(&self) -> &str {
+        self.subdir.as_str()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::OwnedRepoDataIndex;
+    use rattler_conda_types::{Channel, ChannelConfig, PackageName, Platform, RepoData};
+    use std::sync::Arc;
+
+    const REPODATA: &str = r#"{
+        "info": { "subdir": "linux-64" },
+        "packages": {
+            "b-1.0-h0_0.tar.bz2": { "name": "b", "version": "1.0", "build": "h0_0", "build_number": 0, "subdir": "linux-64" },
+            "a-1.0-h0_0.tar.bz2": { "name": "a", "version": "1.0", "build": "h0_0", "build_number": 0, "depends": ["b"], "subdir": "linux-64" }
+        },
+        "packages.conda": {
+            "a-2.0-h0_0.conda": { "name": "a", "version": "2.0", "build": "h0_0", "build_number": 0, "depends": ["b"], "subdir": "linux-64" },
+            "c-1.0-h0_0.conda": { "name": "c", "version": "1.0", "build": "h0_0", "build_number": 0, "subdir": "linux-64" }
+        }
+    }"#;
+
+    #[test]
+    fn test_owned_index_across_threads() {
+        let repo_data: RepoData = serde_json::from_str(REPODATA).unwrap();
+        let index = Arc::new(OwnedRepoDataIndex::new(
+            Channel::from_str("conda-forge", &ChannelConfig::default()).unwrap(),
+            Platform::Linux64,
+            repo_data,
+            None,
+        ));
+
+        let handle = std::thread::spawn({
+            let index = index.clone();
+            move || {
+                index
+                    .load_records(&PackageName::new_unchecked("a"))
+                    .unwrap()
+                    .into_iter()
+                    .map(|record| record.file_name)
+                    .collect::<Vec<_>>()
+            }
+        });
+
+        assert_eq!(
+            handle.join().unwrap(),
+            vec!["a-1.0-h0_0.tar.bz2", "a-2.0-h0_0.conda"]
+        );
+        assert_eq!(
+            index.package_names().collect::<Vec<_>>(),
+            vec!["a", "b", "c"]
+        );
+        assert_eq!(index.subdir(), "linux-64");
+    }
+}
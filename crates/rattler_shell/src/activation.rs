@@ -2,8 +2,10 @@
 
 //! This crate provides helper functions to activate and deactivate virtual environments.
 
-use std::collections::HashMap;
-use std::process::ExitStatus;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::Write;
+use std::process::{Command, ExitStatus, Output, Stdio};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::{
     fs,
     path::{Path, PathBuf},
@@ -11,12 +13,13 @@ use std::{
 
 use crate::shell::Shell;
 use indexmap::IndexMap;
+use itertools::Itertools;
 use rattler_conda_types::Platform;
-
-const ENV_START_SEPERATOR: &str = "<=== RATTLER ENV START ===>";
+use serde::{Deserialize, Serialize};
 
 /// Type of modification done to the `PATH` variable
-#[derive(Default, Clone)]
+#[derive(Default, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum PathModificationBehavior {
     /// Replaces the complete path variable with specified paths.
     #[default]
@@ -29,7 +32,8 @@ pub enum PathModificationBehavior {
 
 /// A struct that contains the values of the environment variables that are relevant for the activation process.
 /// The values are stored as strings. Currently, only the `PATH` and `CONDA_PREFIX` environment variables are used.
-#[derive(Default, Clone)]
+#[derive(Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct ActivationVariables {
     /// The value of the `CONDA_PREFIX` environment variable that contains the activated conda prefix path
     pub conda_prefix: Option<PathBuf>,
@@ -39,17 +43,45 @@ pub struct ActivationVariables {
 
     /// The type of behavior of what should happen with the defined paths.
     pub path_modification_behavior: PathModificationBehavior,
+
+    /// The name of the environment to show in the prompt. If set, the activation script sets the
+    /// `CONDA_PROMPT_MODIFIER` environment variable to the name formatted by
+    /// [`Shell::prompt_modifier`].
+    pub prompt_name: Option<String>,
+
+    /// The names of the environment variables of the environment whose value should be appended
+    /// to the existing value instead of replacing it (e.g. `CMAKE_PREFIX_PATH`). The values are
+    /// joined with the path separator of the platform.
+    pub additive_env_vars: HashSet<String>,
+
+    /// If true, the activation script contains a comment after every environment variable of the
+    /// environment that names the file the variable was collected from. This is useful to find
+    /// out which package set a variable.
+    pub annotate_env_vars: bool,
 }
 
 impl ActivationVariables {
-    /// Create a new `ActivationVariables` struct from the environment variables.
+    /// Create a new `ActivationVariables` struct from the environment variables. The `PATH`
+    /// environment variable is split using the separator of the current platform.
     pub fn from_env() -> Result<Self, std::env::VarError> {
         Ok(Self {
             conda_prefix: std::env::var("CONDA_PREFIX").ok().map(PathBuf::from),
-            path: None,
+            path: std::env::var_os("PATH").map(|path| std::env::split_paths(&path).collect()),
             path_modification_behavior: PathModificationBehavior::Prepend,
+            prompt_name: None,
+            additive_env_vars: HashSet::new(),
+            annotate_env_vars: false,
         })
     }
+
+    /// Reads the activation variables from a JSON file, e.g.
+    /// `{"conda_prefix": "/opt/env", "path": ["/usr/bin"], "path_modification_behavior": "prepend"}`.
+    /// Fields that are missing from the file are set to their default value.
+    pub fn from_json_file(path: &Path) -> Result<Self, ActivationError> {
+        let contents = fs::read_to_string(path)?;
+        serde_json::from_str(&contents)
+            .map_err(|e| ActivationError::InvalidActivationVariablesJson(e, path.to_path_buf()))
+    }
 }
 
 /// A struct that holds values for the activation and deactivation
@@ -74,10 +106,72 @@ pub struct Activator<T: Shell> {
     /// A list of environment variables to set when activating the environment
     pub env_vars: IndexMap<String, String>,
 
-    /// The platform for which to generate the Activator
+    /// The file (a json file in `etc/conda/env_vars.d` or the `conda-meta/state` file) that each
+    /// of the `env_vars` was collected from.
+    pub env_var_sources: IndexMap<String, PathBuf>,
+
+    /// The problems that were encountered while collecting the `env_vars`, e.g. values that are
+    /// not strings and were therefore skipped.
+    pub env_var_warnings: CollectWarnings,
+
+    /// A list of environment variables to set when activating the environment, unless they are
+    /// already set. This can be used to provide defaults that the user can override.
+    pub env_vars_defaults: IndexMap<String, String>,
+
+    /// If true, the shell that runs the activation script in [`Activator::run_activation`] reads
+    /// the startup files of the user (e.g. `.bashrc`). By default they are not read so the
+    /// detected environment only reflects the changes made by the activation.
+    pub read_startup_files: bool,
+
+    /// If true, the activation script starts with a check that fails with a clear error if the
+    /// prefix does not exist (see [`Shell::guard_prefix_exists`]). This is useful for scripts
+    /// that are generated before the environment is created.
+    pub guard_prefix: bool,
+
+    /// If true, the activation script is wrapped with [`Shell::guard_once`] using the
+    /// [`Activator::activation_marker`] of the prefix. Sourcing the script a second time in the
+    /// same shell is then a no-op instead of running the activation scripts again and growing the
+    /// `PATH`.
+    ///
+    /// NuShell does not persist environment changes made inside of a block, so for NuShell the
+    /// script is not guarded and only sets the marker. [`Activator::activation_oneline`] returns
+    /// an error if this is enabled.
+    pub guard_once: bool,
+
+    /// The layout of the prefix from which the scripts and environment variables were collected.
+    /// The same layout is assumed for a previously activated prefix that is deactivated during
+    /// the activation.
+    pub layout: PrefixLayout,
+
+    /// The platform for which to generate the Activator. All paths, separators and scripts
+    /// generated by the activator target this platform, which does not need to be the host.
     pub platform: Platform,
 }
 
+/// The locations of the activation scripts and environment variables of a prefix, relative to the
+/// root of the prefix. The [`Default`] is the layout used by conda (e.g. `etc/conda/activate.d`).
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct PrefixLayout {
+    /// The directory that contains the scripts to run when activating the prefix
+    pub activate_dir: PathBuf,
+
+    /// The directory that contains the scripts to run when deactivating the prefix
+    pub deactivate_dir: PathBuf,
+
+    /// The directory that contains the json files with the environment variables of packages
+    pub env_vars_dir: PathBuf,
+}
+
+impl Default for PrefixLayout {
+    fn default() -> Self {
+        Self {
+            activate_dir: PathBuf::from("etc/conda/activate.d"),
+            deactivate_dir: PathBuf::from("etc/conda/deactivate.d"),
+            env_vars_dir: PathBuf::from("etc/conda/env_vars.d"),
+        }
+    }
+}
+
 /// Collect all script files that match a certain shell type from a given path.
 /// The files are sorted by their filename.
 /// If the path does not exist, an empty vector is returned.
@@ -107,6 +201,7 @@ fn collect_scripts<T: Shell>(path: &Path, shell_type: &T) -> Result<Vec<PathBuf>
         .into_iter()
         .filter_map(|r| r.ok())
         .map(|r| r.path())
+        .filter(|path| resolves_to_file(path))
         .filter(|path| shell_type.can_run_script(path))
         .collect::<Vec<_>>();
 
@@ -115,6 +210,20 @@ fn collect_scripts<T: Shell>(path: &Path, shell_type: &T) -> Result<Vec<PathBuf>
     Ok(scripts)
 }
 
+/// Returns true if the path refers to a regular file, following symlinks. Broken symlinks are
+/// skipped with a warning instead of failing the activation later on.
+fn resolves_to_file(path: &Path) -> bool {
+    match fs::metadata(path) {
+        Ok(metadata) => metadata.is_file(),
+        Err(e) => {
+            if path.is_symlink() {
+                tracing::warn!("skipping broken symlink {}: {e}", path.display());
+            }
+            false
+        }
+    }
+}
+
 /// Error that can occur when activating a conda environment
 #[derive(thiserror::Error, Debug)]
 pub enum ActivationError {
@@ -126,6 +235,10 @@ pub enum ActivationError {
     #[error("Invalid json for environment vars: {0} in file {1:?}")]
     InvalidEnvVarFileJson(serde_json::Error, PathBuf),
 
+    /// An error that can occur when parsing a JSON file with activation variables
+    #[error("Invalid json for activation variables: {0} in file {1:?}")]
+    InvalidActivationVariablesJson(serde_json::Error, PathBuf),
+
     /// An error that can occur wiht malformed JSON when parsing files in the `env_vars.d` directory
     #[error("Malformed JSON: not a plain JSON object in file {file:?}")]
     InvalidEnvVarFileJsonNoObject {
@@ -140,6 +253,13 @@ pub enum ActivationError {
         file: PathBuf,
     },
 
+    /// The prefix that should be activated does not exist
+    #[error("The prefix {path:?} does not exist")]
+    PrefixDoesNotExist {
+        /// The path of the prefix that does not exist
+        path: PathBuf,
+    },
+
     /// An error that occurs when writing the activation script to a file fails
     #[error("Failed to write activation script to file {0}")]
     FailedToWriteActivationScript(#[from] std::fmt::Error),
@@ -159,6 +279,55 @@ pub enum ActivationError {
         /// The error code of running the script
         status: ExitStatus,
     },
+
+    /// The activation script cannot be collapsed into a single line because
+    /// [`Activator::guard_once`] is enabled
+    #[error(
+        "The activation script cannot be collapsed into a single line when `guard_once` is enabled"
+    )]
+    GuardOnceNotSupportedOnOneline,
+
+    /// Running the activation script took longer than the configured timeout
+    #[error("Running the activation script timed out after {timeout:?}")]
+    ActivationScriptTimedOut {
+        /// The contents of the activation script that was run
+        script: String,
+
+        /// The timeout that was exceeded
+        timeout: Duration,
+    },
+
+    /// An error that can occur when parsing an environment snapshot
+    #[error("Invalid environment snapshot: {0} in file {1:?}")]
+    InvalidEnvSnapshot(serde_json::Error, PathBuf),
+
+    /// The environment snapshot was written in a format that is not supported
+    #[error("Unsupported environment snapshot version {version} in file {file:?}")]
+    UnsupportedEnvSnapshotVersion {
+        /// The version of the snapshot
+        version: u32,
+        /// The path to the snapshot file
+        file: PathBuf,
+    },
+}
+
+/// Counts the problems that were encountered while collecting the environment variables of a
+/// prefix. Every problem is also logged as a warning.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct CollectWarnings {
+    /// The number of environment variables that were skipped because their value is not a string
+    pub non_string_values: usize,
+
+    /// The number of environment variables that were defined more than once, only the last
+    /// definition is used
+    pub overridden: usize,
+}
+
+impl CollectWarnings {
+    /// Returns the total number of warnings.
+    pub fn total(&self) -> usize {
+        self.non_string_values + self.overridden
+    }
 }
 
 /// Collect all environment variables that are set in a conda environment.
@@ -168,18 +337,25 @@ pub enum ActivationError {
 /// # Arguments
 ///
 /// * `prefix` - The path to the root of the conda environment
+/// * `layout` - The layout of the prefix that determines where `env_vars.d` is located
 ///
 /// # Returns
 ///
-/// A map of environment variables
+/// A map of environment variables to their value and the file the value was read from, together
+/// with the number of warnings that were encountered
 ///
 /// # Errors
 ///
 /// If the `state` file or the `env_vars.d` directory cannot be read, an error is returned.
-fn collect_env_vars(prefix: &Path) -> Result<IndexMap<String, String>, ActivationError> {
+#[allow(clippy::type_complexity)]
+fn collect_env_vars(
+    prefix: &Path,
+    layout: &PrefixLayout,
+) -> Result<(IndexMap<String, (String, PathBuf)>, CollectWarnings), ActivationError> {
     let state_file = prefix.join("conda-meta/state");
-    let pkg_env_var_dir = prefix.join("etc/conda/env_vars.d");
+    let pkg_env_var_dir = prefix.join(&layout.env_vars_dir);
     let mut env_vars = IndexMap::new();
+    let mut warnings = CollectWarnings::default();
 
     if pkg_env_var_dir.exists() {
         let env_var_files = pkg_env_var_dir.read_dir()?;
@@ -212,10 +388,18 @@ fn collect_env_vars(prefix: &Path) -> Result<IndexMap<String, String>, Activatio
 
             for (key, value) in env_var_json {
                 if let Some(value) = value.as_str() {
-                    env_vars.insert(key.to_string(), value.to_string());
+                    if env_vars
+                        .insert(key.to_string(), (value.to_string(), env_var_file.clone()))
+                        .is_some()
+                    {
+                        tracing::warn!(
+                            "WARNING: environment variable {key} already defined in packages (path: {env_var_file:?})");
+                        warnings.overridden += 1;
+                    }
                 } else {
                     tracing::warn!(
                         "WARNING: environment variable {key} has no string value (path: {env_var_file:?})");
+                    warnings.non_string_values += 1;
                 }
             }
         }
@@ -235,20 +419,26 @@ fn collect_env_vars(prefix: &Path) -> Result<IndexMap<String, String>, Activatio
         })?;
 
         for (key, value) in state_env_vars {
-            if state_env_vars.contains_key(key) {
-                tracing::warn!(
-                    "WARNING: environment variable {key} already defined in packages (path: {state_file:?})");
-            }
-
             if let Some(value) = value.as_str() {
-                env_vars.insert(key.to_uppercase().to_string(), value.to_string());
+                if env_vars
+                    .insert(
+                        key.to_uppercase().to_string(),
+                        (value.to_string(), state_file.clone()),
+                    )
+                    .is_some()
+                {
+                    tracing::warn!(
+                        "WARNING: environment variable {key} already defined in packages (path: {state_file:?})");
+                    warnings.overridden += 1;
+                }
             } else {
                 tracing::warn!(
                     "WARNING: environment variable {key} has no string value (path: {state_file:?})");
+                warnings.non_string_values += 1;
             }
         }
     }
-    Ok(env_vars)
+    Ok((env_vars, warnings))
 }
 
 /// Return a vector of path entries that are prefixed with the given path.
@@ -265,17 +455,52 @@ fn prefix_path_entries(prefix: &Path, platform: &Platform) -> Vec<PathBuf> {
     if platform.is_windows() {
         vec![
             prefix.to_path_buf(),
-            prefix.join("Library/mingw-w64/bin"),
-            prefix.join("Library/usr/bin"),
-            prefix.join("Library/bin"),
-            prefix.join("Scripts"),
-            prefix.join("bin"),
+            join_for_platform(prefix, &["Library", "mingw-w64", "bin"], platform),
+            join_for_platform(prefix, &["Library", "usr", "bin"], platform),
+            join_for_platform(prefix, &["Library", "bin"], platform),
+            join_for_platform(prefix, &["Scripts"], platform),
+            join_for_platform(prefix, &["bin"], platform),
         ]
     } else {
-        vec![prefix.join("bin")]
+        vec![join_for_platform(prefix, &["bin"], platform)]
+    }
+}
+
+/// Joins `components` to `prefix` using the directory separator of `platform` instead of the
+/// separator of the host. [`Path::join`] would otherwise mix separators when generating paths for
+/// a Windows target on a unix host or vice versa.
+fn join_for_platform(prefix: &Path, components: &[&str], platform: &Platform) -> PathBuf {
+    let separator = if platform.is_windows() { "\\" } else { "/" };
+    let prefix = prefix.to_string_lossy();
+    PathBuf::from(format!(
+        "{}{separator}{}",
+        prefix.trim_end_matches(&['/', '\\'][..]),
+        components.join(separator)
+    ))
+}
+
+/// Returns true if both paths refer to the same prefix. If possible the paths are canonicalized
+/// before comparing them.
+fn is_same_prefix(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
     }
 }
 
+/// Returns the name of the environment variable that marks `prefix` as activated. The name
+/// contains a FNV-1a hash of the prefix which, unlike the hasher of the standard library, is
+/// stable across Rust versions and platforms.
+fn activation_marker(prefix: &Path) -> String {
+    let hash = prefix
+        .to_string_lossy()
+        .bytes()
+        .fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+        });
+    format!("__RATTLER_ACTIVATED_{hash:016x}")
+}
+
 /// The result of a activation. It contains the activation script and the new path entries.
 /// The activation script already sets the PATH environment variable, but for "environment stacking"
 /// purposes it's useful to have the new path entries separately.
@@ -283,8 +508,153 @@ pub struct ActivationResult {
     /// The activation script that sets the environment variables, runs activation/deactivation scripts
     /// and sets the new PATH environment variable
     pub script: String,
-    /// The new path entries that are added to the PATH environment variable
+    /// The entries of the PATH environment variable after activation, in the order defined by
+    /// [`ActivationVariables::path_modification_behavior`]: with
+    /// [`PathModificationBehavior::Append`] the entries of the environment come after the existing
+    /// entries, otherwise they come first.
     pub path: Vec<PathBuf>,
+    /// The environment variables that the activation script sets unconditionally, in the order in
+    /// which they are set. This includes `CONDA_PREFIX`, the variables of the environment and the
+    /// `PATH` joined from [`Self::path`]. Variables that are only set when they are unset, variables
+    /// that are appended to their existing value (see [`ActivationVariables::additive_env_vars`])
+    /// and variables set by the activation scripts of packages are not included.
+    ///
+    /// If the current `PATH` was not passed in [`ActivationVariables::path`] the `PATH` only
+    /// contains the entries of the environment.
+    pub env_vars: IndexMap<String, String>,
+}
+
+impl ActivationResult {
+    /// Applies the activation to the environment of the current process instead of emitting a
+    /// script. Every variable in [`Self::env_vars`] is set, if the activation sets the `PATH` it is
+    /// set to [`Self::path`] joined with the path separator of the host.
+    ///
+    /// Variables set by the activation scripts of packages are not applied, use
+    /// [`Activator::run_activation`] to detect those. Pass the current `PATH` in
+    /// [`ActivationVariables::path`] (e.g. through [`ActivationVariables::from_env`]), otherwise
+    /// the `PATH` of the process only contains the entries of the environment afterwards.
+    ///
+    /// Modifying the environment of a process is not thread-safe on most platforms: other threads
+    /// that read the environment at the same time (also indirectly, e.g. through `getenv` in C
+    /// code) can observe inconsistent values or crash. Call this before spawning any threads.
+    ///
+    /// Returns an error without modifying the environment if an entry of [`Self::path`] contains
+    /// the path separator of the host.
+    pub fn apply_to_current_process(&self) -> Result<(), std::env::JoinPathsError> {
+        let path = if self.env_vars.contains_key("PATH") {
+            Some(std::env::join_paths(&self.path)?)
+        } else {
+            None
+        };
+
+        for (key, value) in &self.env_vars {
+            match &path {
+                Some(path) if key == "PATH" => std::env::set_var(key, path),
+                _ => std::env::set_var(key, value),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Options that control how the activation script is executed by
+/// [`Activator::run_activation_with_options`]. The [`Default`] runs the script from the root of
+/// the prefix with the environment of the current process and without a timeout, which is what
+/// [`Activator::run_activation`] does.
+#[derive(Debug, Clone, Default)]
+pub struct RunActivationOptions {
+    /// The environment the shell is started with. When `None` the shell inherits the environment
+    /// of the current process.
+    pub base_env: Option<HashMap<String, String>>,
+
+    /// The working directory of the shell. When `None` the root of the prefix is used.
+    pub cwd: Option<PathBuf>,
+
+    /// The maximum duration of running the activation script. If the script takes longer the
+    /// shell is killed and [`ActivationError::ActivationScriptTimedOut`] is returned.
+    pub timeout: Option<Duration>,
+
+    /// Whether the shell reads the startup files of the user (e.g. `.bashrc`). When `None`
+    /// [`Activator::read_startup_files`] is used.
+    pub read_startup_files: Option<bool>,
+}
+
+/// Runs `command` to completion and collects its output like [`Command::output`]. If the command
+/// does not finish within `timeout` it is killed and `None` is returned.
+fn output_with_timeout(
+    command: &mut Command,
+    timeout: Option<Duration>,
+) -> Result<Option<Output>, std::io::Error> {
+    let Some(timeout) = timeout else {
+        return command.output().map(Some);
+    };
+
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // Read the pipes on separate threads, the child blocks if a pipe buffer fills up.
+    let read_pipe = |pipe: Option<Box<dyn std::io::Read + Send>>| {
+        std::thread::spawn(move || {
+            let mut bytes = Vec::new();
+            if let Some(mut pipe) = pipe {
+                let _ = pipe.read_to_end(&mut bytes);
+            }
+            bytes
+        })
+    };
+    let stdout = read_pipe(child.stdout.take().map(|p| Box::new(p) as _));
+    let stderr = read_pipe(child.stderr.take().map(|p| Box::new(p) as _));
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            // Processes spawned by the script might keep the pipes open, so don't wait for the
+            // reader threads.
+            child.kill()?;
+            child.wait()?;
+            return Ok(None);
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    };
+
+    Ok(Some(Output {
+        status,
+        stdout: stdout.join().unwrap_or_default(),
+        stderr: stderr.join().unwrap_or_default(),
+    }))
+}
+
+/// The version of the format written by [`Activator::write_env_snapshot`].
+const ENV_SNAPSHOT_VERSION: u32 = 1;
+
+/// The contents of a file written by [`Activator::write_env_snapshot`].
+#[derive(Serialize, Deserialize)]
+struct EnvSnapshot {
+    version: u32,
+    path: Vec<PathBuf>,
+    env_vars: IndexMap<String, String>,
+}
+
+/// Reads an environment snapshot written by [`Activator::write_env_snapshot`] and returns the
+/// environment variables that the activation sets. The variables can be applied to a process
+/// without running the activation again.
+pub fn apply_env_snapshot(path: &Path) -> Result<HashMap<String, String>, ActivationError> {
+    let contents = fs::read_to_string(path)?;
+    let snapshot: EnvSnapshot = serde_json::from_str(&contents)
+        .map_err(|e| ActivationError::InvalidEnvSnapshot(e, path.to_path_buf()))?;
+    if snapshot.version != ENV_SNAPSHOT_VERSION {
+        return Err(ActivationError::UnsupportedEnvSnapshotVersion {
+            version: snapshot.version,
+            file: path.to_path_buf(),
+        });
+    }
+    Ok(snapshot.env_vars.into_iter().collect())
 }
 
 impl<T: Shell + Clone> Activator<T> {
@@ -294,35 +664,85 @@ impl<T: Shell + Clone> Activator<T> {
     ///
     /// * `path` - The path to the root of the conda environment
     /// * `shell_type` - The shell type that the activator is for
-    /// * `operating_system` - The operating system that the activator is for
+    /// * `platform` - The platform that the activator generates scripts for
+    ///
+    /// The activation and deactivation scripts and the environment variables are discovered by
+    /// reading the prefix on the host filesystem, but everything the activator generates (path
+    /// entries, path separators and scripts) is formatted for `platform`. This makes it possible
+    /// to generate activation scripts for a different target, e.g. a Linux container from a macOS
+    /// host.
     ///
     /// # Returns
     ///
     /// A new activator
     ///
+    /// # Errors
+    ///
+    /// Returns [`ActivationError::PrefixDoesNotExist`] if `path` does not exist. Missing
+    /// `activate.d`, `deactivate.d` or `env_vars.d` directories are not an error.
+    ///
     /// # Examples
     ///
     /// ```
     /// use rattler_shell::activation::Activator;
     /// use rattler_shell::shell;
     /// use rattler_conda_types::Platform;
-    /// use std::path::PathBuf;
     ///
-    /// let activator = Activator::from_path(&PathBuf::from("tests/fixtures/env_vars"), shell::Bash, Platform::Osx64).unwrap();
+    /// let prefix = tempfile::TempDir::new().unwrap();
+    /// let activator = Activator::from_path(prefix.path(), shell::Bash, Platform::Osx64).unwrap();
     /// assert_eq!(activator.paths.len(), 1);
-    /// assert_eq!(activator.paths[0], PathBuf::from("tests/fixtures/env_vars/bin"));
+    /// assert_eq!(activator.paths[0], prefix.path().join("bin"));
     /// ```
     pub fn from_path(
         path: &Path,
         shell_type: T,
         platform: Platform,
     ) -> Result<Activator<T>, ActivationError> {
-        let activation_scripts = collect_scripts(&path.join("etc/conda/activate.d"), &shell_type)?;
+        if !path.exists() {
+            return Err(ActivationError::PrefixDoesNotExist {
+                path: path.to_path_buf(),
+            });
+        }
+
+        Self::from_path_unchecked(path, shell_type, platform, PrefixLayout::default())
+    }
+
+    /// Same as [`Self::from_path`] but reads the activation scripts and environment variables from
+    /// the directories of the given [`PrefixLayout`] instead of the conda layout. This makes it
+    /// possible to activate relocated or custom packaged environments.
+    pub fn from_path_with_layout(
+        path: &Path,
+        shell_type: T,
+        platform: Platform,
+        layout: PrefixLayout,
+    ) -> Result<Activator<T>, ActivationError> {
+        if !path.exists() {
+            return Err(ActivationError::PrefixDoesNotExist {
+                path: path.to_path_buf(),
+            });
+        }
+
+        Self::from_path_unchecked(path, shell_type, platform, layout)
+    }
+
+    /// Same as [`Self::from_path_with_layout`] but does not require the prefix to exist. This is
+    /// used to deactivate a previously active prefix that might have been removed in the meantime.
+    fn from_path_unchecked(
+        path: &Path,
+        shell_type: T,
+        platform: Platform,
+        layout: PrefixLayout,
+    ) -> Result<Activator<T>, ActivationError> {
+        let activation_scripts = collect_scripts(&path.join(&layout.activate_dir), &shell_type)?;
 
         let deactivation_scripts =
-            collect_scripts(&path.join("etc/conda/deactivate.d"), &shell_type)?;
+            collect_scripts(&path.join(&layout.deactivate_dir), &shell_type)?;
 
-        let env_vars = collect_env_vars(path)?;
+        let (env_vars, env_var_warnings) = collect_env_vars(path, &layout)?;
+        let (env_vars, env_var_sources) = env_vars
+            .into_iter()
+            .map(|(key, (value, source))| ((key.clone(), value), (key, source)))
+            .unzip();
 
         let paths = prefix_path_entries(path, &platform);
 
@@ -333,10 +753,23 @@ impl<T: Shell + Clone> Activator<T> {
             activation_scripts,
             deactivation_scripts,
             env_vars,
+            env_var_sources,
+            env_var_warnings,
+            env_vars_defaults: IndexMap::new(),
+            read_startup_files: false,
+            guard_prefix: false,
+            guard_once: false,
+            layout,
             platform,
         })
     }
 
+    /// Returns the name of the environment variable that marks the prefix as activated when
+    /// [`Activator::guard_once`] is enabled, e.g. `__RATTLER_ACTIVATED_0123456789abcdef`.
+    pub fn activation_marker(&self) -> String {
+        activation_marker(&self.target_prefix)
+    }
+
     /// Create an activation script for a given shell and platform. This
     /// returns a tuple of the newly computed PATH variable and the activation script.
     pub fn activation(
@@ -344,21 +777,40 @@ impl<T: Shell + Clone> Activator<T> {
         variables: ActivationVariables,
     ) -> Result<ActivationResult, ActivationError> {
         let mut script = String::new();
+        let mut env_vars = IndexMap::new();
+
+        if self.guard_prefix {
+            self.shell_type
+                .guard_prefix_exists(&mut script, &self.target_prefix)
+                .map_err(ActivationError::FailedToWriteActivationScript)?;
+        }
 
         let mut path = variables.path.clone().unwrap_or_default();
+        let mut is_reactivation = false;
         if let Some(conda_prefix) = variables.conda_prefix {
-            let deactivate = Activator::from_path(
+            let deactivate = Activator::from_path_unchecked(
                 Path::new(&conda_prefix),
                 self.shell_type.clone(),
                 self.platform,
+                self.layout.clone(),
             )?;
 
+            // Check if we are re-activating the environment that is currently active.
+            is_reactivation = is_same_prefix(&conda_prefix, &self.target_prefix);
+
             for (key, _) in &deactivate.env_vars {
                 self.shell_type
                     .unset_env_var(&mut script, key)
                     .map_err(ActivationError::FailedToWriteActivationScript)?;
             }
 
+            // Allow the deactivated prefix to be activated again later on.
+            if self.guard_once && !is_reactivation {
+                self.shell_type
+                    .unset_env_var(&mut script, &deactivate.activation_marker())
+                    .map_err(ActivationError::FailedToWriteActivationScript)?;
+            }
+
             for deactivation_script in &deactivate.deactivation_scripts {
                 self.shell_type
                     .run_script(&mut script, deactivation_script)
@@ -366,32 +818,91 @@ impl<T: Shell + Clone> Activator<T> {
             }
 
             path.retain(|x| !deactivate.paths.contains(x));
+            if is_reactivation {
+                // The paths of the prefix might be spelled differently than the paths of the
+                // deactivated prefix, make sure they are not added twice.
+                path.retain(|x| !self.paths.contains(x));
+            }
         }
 
         // prepend new paths
         let path = [self.paths.clone(), path].concat();
-
-        self.shell_type
-            .set_path(
-                &mut script,
-                path.as_slice(),
+        let activated_path = self.order_path(&path, &variables.path_modification_behavior);
+
+        // When re-activating the current environment without knowing the current value of `PATH`
+        // the paths of the environment are already part of `PATH`. Adding them again would only
+        // result in duplicate entries.
+        let paths_already_active = is_reactivation
+            && variables.path.is_none()
+            && !matches!(
                 variables.path_modification_behavior,
-                &self.platform,
-            )
-            .map_err(ActivationError::FailedToWriteActivationScript)?;
+                PathModificationBehavior::Replace
+            );
+
+        if !paths_already_active {
+            self.shell_type
+                .set_path(
+                    &mut script,
+                    path.as_slice(),
+                    variables.path_modification_behavior,
+                    &self.platform,
+                )
+                .map_err(ActivationError::FailedToWriteActivationScript)?;
+
+            env_vars.insert(
+                String::from("PATH"),
+                activated_path
+                    .iter()
+                    .map(|path| path.to_string_lossy())
+                    .join(self.shell_type.path_seperator(&self.platform)),
+            );
+        }
 
         // deliberately not taking care of `CONDA_SHLVL` or any other complications at this point
+        let conda_prefix = self.target_prefix.to_string_lossy().into_owned();
         self.shell_type
-            .set_env_var(
-                &mut script,
-                "CONDA_PREFIX",
-                &self.target_prefix.to_string_lossy(),
-            )
+            .set_env_var(&mut script, "CONDA_PREFIX", &conda_prefix)
             .map_err(ActivationError::FailedToWriteActivationScript)?;
+        env_vars.insert(String::from("CONDA_PREFIX"), conda_prefix);
+
+        if let Some(prompt_name) = &variables.prompt_name {
+            let prompt_modifier = self.shell_type.prompt_modifier(prompt_name);
+            self.shell_type
+                .set_env_var(&mut script, "CONDA_PROMPT_MODIFIER", &prompt_modifier)
+                .map_err(ActivationError::FailedToWriteActivationScript)?;
+            env_vars.insert(String::from("CONDA_PROMPT_MODIFIER"), prompt_modifier);
+        }
 
         for (key, value) in &self.env_vars {
+            if variables.additive_env_vars.contains(key) {
+                // Append to the existing value, similar to how the `PATH` is modified
+                let value = [self.shell_type.format_env_var(key), value.clone()]
+                    .join(self.shell_type.path_seperator(&self.platform));
+                self.shell_type
+                    .set_env_var(&mut script, key, &value)
+                    .map_err(ActivationError::FailedToWriteActivationScript)?;
+            } else {
+                self.shell_type
+                    .set_env_var(&mut script, key, value)
+                    .map_err(ActivationError::FailedToWriteActivationScript)?;
+                env_vars.insert(key.clone(), value.clone());
+            }
+
+            if variables.annotate_env_vars {
+                if let Some(source) = self.env_var_sources.get(key) {
+                    self.shell_type
+                        .write_comment(
+                            &mut script,
+                            &format!("{key} was set by {}", source.display()),
+                        )
+                        .map_err(ActivationError::FailedToWriteActivationScript)?;
+                }
+            }
+        }
+
+        for (key, value) in &self.env_vars_defaults {
             self.shell_type
-                .set_env_var(&mut script, key, value)
+                .set_env_var_if_unset(&mut script, key, value)
                 .map_err(ActivationError::FailedToWriteActivationScript)?;
         }
 
@@ -401,29 +912,231 @@ impl<T: Shell + Clone> Activator<T> {
                 .map_err(ActivationError::FailedToWriteActivationScript)?;
         }
 
-        Ok(ActivationResult { script, path })
+        let script = if self.guard_once {
+            let marker = self.activation_marker();
+            let mut guarded = String::new();
+            self.shell_type
+                .guard_once(&mut guarded, &marker, &script)
+                .map_err(ActivationError::FailedToWriteActivationScript)?;
+            env_vars.insert(marker, String::from("1"));
+            guarded
+        } else {
+            script
+        };
+
+        Ok(ActivationResult {
+            script,
+            path: activated_path,
+            env_vars,
+        })
+    }
+
+    /// Orders the entries of the PATH after activation. `path` starts with the paths of this
+    /// environment followed by the existing entries.
+    fn order_path(&self, path: &[PathBuf], behavior: &PathModificationBehavior) -> Vec<PathBuf> {
+        match behavior {
+            PathModificationBehavior::Append => {
+                let (prefix_paths, existing_paths) = path.split_at(self.paths.len());
+                [existing_paths, prefix_paths].concat()
+            }
+            PathModificationBehavior::Replace | PathModificationBehavior::Prepend => path.to_vec(),
+        }
+    }
+
+    /// Same as [`Self::activation`] but returns the activation script collapsed into a single line.
+    /// The statements of the script are joined by the [`Shell::statement_separator`] of the shell.
+    /// This is useful to embed the activation in a non-interactive invocation of a shell (e.g.
+    /// `bash -c "..."`).
+    ///
+    /// Returns [`ActivationError::GuardOnceNotSupportedOnOneline`] if [`Self::guard_once`] is
+    /// enabled: the guards of most shells span multiple lines (e.g. a block or a jump label) and
+    /// cannot be joined into a single statement.
+    pub fn activation_oneline(
+        &self,
+        variables: ActivationVariables,
+    ) -> Result<String, ActivationError> {
+        if self.guard_once {
+            return Err(ActivationError::GuardOnceNotSupportedOnOneline);
+        }
+        let result = self.activation(variables)?;
+        Ok(self.shell_type.chain_commands(result.script.lines()))
+    }
+
+    /// Same as [`Self::activation`] but additionally appends a single JSON line to the file at
+    /// `log_path` that records the time of the activation, the prefix, the shell and the number of
+    /// entries in the resulting `PATH`. The log file is created if it does not exist yet.
+    ///
+    /// Failing to write the log is not considered an error, instead a warning is emitted.
+    pub fn activation_with_log(
+        &self,
+        variables: ActivationVariables,
+        log_path: &Path,
+    ) -> Result<ActivationResult, ActivationError> {
+        let result = self.activation(variables)?;
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or_default();
+        let entry = serde_json::json!({
+            "timestamp": timestamp,
+            "prefix": self.target_prefix,
+            "shell": self.shell_type.executable(),
+            "path_len": result.path.len(),
+        });
+
+        let write_result = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(log_path)
+            .and_then(|mut file| writeln!(file, "{entry}"));
+        if let Err(e) = write_result {
+            tracing::warn!("failed to write activation log to {log_path:?}: {e}");
+        }
+
+        Ok(result)
+    }
+
+    /// Computes the environment after activation and returns it as a JSON object that maps the
+    /// names of environment variables to their values (e.g. `{ "PATH": "...", "CONDA_PREFIX": "..." }`).
+    /// This allows tools that are not a shell to consume the activation.
+    ///
+    /// If the environment does not contain any activation scripts (or default or additive
+    /// variables) the environment is computed without invoking a shell and
+    /// [`ActivationResult::env_vars`] is returned. The `PATH` is computed from the `path` in
+    /// `variables`, use [`ActivationVariables::from_env`] to include the `PATH` of the current
+    /// process. Otherwise, the result depends on the environment of the shell so the activation is
+    /// executed with [`Self::run_activation`] and the changed variables are returned.
+    pub fn activation_as_json(
+        &self,
+        variables: ActivationVariables,
+    ) -> Result<String, ActivationError> {
+        let env: BTreeMap<String, String> = if self.activation_scripts.is_empty()
+            && self.env_vars_defaults.is_empty()
+            && variables.additive_env_vars.is_empty()
+        {
+            self.activation(variables)?.env_vars.into_iter().collect()
+        } else {
+            self.run_activation(variables)?.into_iter().collect()
+        };
+
+        Ok(serde_json::to_string(&env).expect("a map of strings can always be serialized"))
+    }
+
+    /// Computes the activation (see [`Self::activation`]) and writes the resulting path entries
+    /// and environment variables as a versioned json file to `out`. The snapshot can be read back
+    /// with [`apply_env_snapshot`] to reconstruct the activated environment later on.
+    ///
+    /// The snapshot contains the same variables as [`ActivationResult::env_vars`], changes made by
+    /// the activation scripts of packages are not captured.
+    pub fn write_env_snapshot(
+        &self,
+        variables: ActivationVariables,
+        out: &Path,
+    ) -> Result<(), ActivationError> {
+        let result = self.activation(variables)?;
+        let snapshot = EnvSnapshot {
+            version: ENV_SNAPSHOT_VERSION,
+            path: result.path,
+            env_vars: result.env_vars,
+        };
+        let json = serde_json::to_string_pretty(&snapshot)
+            .expect("a snapshot of strings can always be serialized");
+        fs::write(out, json)?;
+        Ok(())
     }
 
     /// Runs the activation script and returns the environment variables changed in the environment
-    /// after running the script.
+    /// after running the script. The script is run from the root of the prefix, use
+    /// [`Self::run_activation_in_dir`] to run it from another directory.
     /// TODO: This only handles UTF-8 formatted strings..
     pub fn run_activation(
         &self,
         variables: ActivationVariables,
     ) -> Result<HashMap<String, String>, ActivationError> {
+        Ok(self.run_activation_iter(variables)?.collect())
+    }
+
+    /// Same as [`Self::run_activation`] but runs the activation script with `cwd` as the current
+    /// working directory. This matters for activation scripts that use relative paths.
+    pub fn run_activation_in_dir(
+        &self,
+        variables: ActivationVariables,
+        cwd: &Path,
+    ) -> Result<HashMap<String, String>, ActivationError> {
+        self.run_activation_with_options(
+            variables,
+            &RunActivationOptions {
+                cwd: Some(cwd.to_path_buf()),
+                ..RunActivationOptions::default()
+            },
+        )
+    }
+
+    /// Same as [`Self::run_activation`] but allows configuring how the activation script is
+    /// executed, e.g. the environment the shell starts with or a timeout. See
+    /// [`RunActivationOptions`].
+    pub fn run_activation_with_options(
+        &self,
+        variables: ActivationVariables,
+        options: &RunActivationOptions,
+    ) -> Result<HashMap<String, String>, ActivationError> {
+        Ok(self
+            .run_activation_detection(variables, options)?
+            .0
+            .collect())
+    }
+
+    /// Runs the activation script and returns an iterator over the environment variables changed
+    /// in the environment after running the script. Unlike [`Self::run_activation`] the changed
+    /// variables are not collected into a map which is useful if they are directly forwarded to
+    /// another process.
+    pub fn run_activation_iter(
+        &self,
+        variables: ActivationVariables,
+    ) -> Result<impl Iterator<Item = (String, String)>, ActivationError> {
+        Ok(self
+            .run_activation_detection(variables, &RunActivationOptions::default())?
+            .0)
+    }
+
+    /// Runs the activation script and returns the environment variables changed in the environment
+    /// together with the full script that was executed to detect the changes. The script is useful
+    /// to debug activation issues.
+    pub fn run_activation_with_script(
+        &self,
+        variables: ActivationVariables,
+    ) -> Result<(HashMap<String, String>, String), ActivationError> {
+        let (changed_env, script) =
+            self.run_activation_detection(variables, &RunActivationOptions::default())?;
+        Ok((changed_env.collect(), script))
+    }
+
+    /// Runs the activation script wrapped in a script that emits the environment before and after
+    /// activation as configured by `options`. Returns an iterator over the changed variables and
+    /// the executed script.
+    fn run_activation_detection(
+        &self,
+        variables: ActivationVariables,
+        options: &RunActivationOptions,
+    ) -> Result<(impl Iterator<Item = (String, String)>, String), ActivationError> {
         let activation_script = self.activation(variables)?.script;
 
+        // Generate a unique separator for this invocation so that it cannot collide with the value
+        // of an existing environment variable.
+        let env_start_separator = format!("<=== RATTLER ENV START {} ===>", uuid::Uuid::new_v4());
+
         // Create a script that starts by emitting all environment variables, then runs the
         // activation script followed by again emitting all environment variables. Any changes
         // should then become visible.
         let mut activation_detection_script = String::new();
         self.shell_type.env(&mut activation_detection_script)?;
         self.shell_type
-            .echo(&mut activation_detection_script, ENV_START_SEPERATOR)?;
+            .echo(&mut activation_detection_script, &env_start_separator)?;
         activation_detection_script =
             format!("{}{}", &activation_detection_script, &activation_script);
         self.shell_type
-            .echo(&mut activation_detection_script, ENV_START_SEPERATOR)?;
+            .echo(&mut activation_detection_script, &env_start_separator)?;
         self.shell_type.env(&mut activation_detection_script)?;
 
         // Create a temporary file that we can execute with our shell.
@@ -434,10 +1147,26 @@ impl<T: Shell + Clone> Activator<T> {
         fs::write(&activation_script_path, &activation_detection_script)?;
 
         // Get only the path to the temporary file
-        let activation_result = self
-            .shell_type
-            .create_run_script_command(&activation_script_path)
-            .output()?;
+        let mut command = if options
+            .read_startup_files
+            .unwrap_or(self.read_startup_files)
+        {
+            self.shell_type
+                .create_run_script_command_with_startup_files(&activation_script_path)
+        } else {
+            self.shell_type
+                .create_run_script_command(&activation_script_path)
+        };
+        command.current_dir(options.cwd.as_deref().unwrap_or(&self.target_prefix));
+        if let Some(base_env) = &options.base_env {
+            command.env_clear().envs(base_env);
+        }
+        let Some(activation_result) = output_with_timeout(&mut command, options.timeout)? else {
+            return Err(ActivationError::ActivationScriptTimedOut {
+                script: activation_detection_script,
+                timeout: options.timeout.unwrap_or_default(),
+            });
+        };
 
         if !activation_result.status.success() {
             return Err(ActivationError::FailedToRunActivationScript {
@@ -450,24 +1179,34 @@ impl<T: Shell + Clone> Activator<T> {
 
         let stdout = String::from_utf8_lossy(&activation_result.stdout);
         let (before_env, rest) = stdout
-            .split_once(ENV_START_SEPERATOR)
+            .split_once(&env_start_separator)
             .unwrap_or(("", stdout.as_ref()));
-        let (_, after_env) = rest.rsplit_once(ENV_START_SEPERATOR).unwrap_or(("", ""));
+        let (_, after_env) = rest.rsplit_once(&env_start_separator).unwrap_or(("", ""));
 
-        // Parse both environments and find the difference
-        let before_env = self.shell_type.parse_env(before_env);
-        let after_env = self.shell_type.parse_env(after_env);
+        // Parse both environments
+        let before_env = self
+            .shell_type
+            .parse_env(before_env)
+            .into_iter()
+            .map(|(key, value)| (key.to_owned(), value.to_owned()))
+            .collect::<HashMap<_, _>>();
+        let after_env = self
+            .shell_type
+            .parse_env(after_env)
+            .into_iter()
+            .map(|(key, value)| (key.to_owned(), value.to_owned()))
+            .collect::<Vec<_>>();
 
-        // Find and return the differences
-        Ok(after_env
+        // Lazily find the differences
+        let changed_env = after_env
             .into_iter()
-            .filter(|(key, value)| before_env.get(key) != Some(value))
+            .filter(move |(key, value)| before_env.get(key) != Some(value))
             // this happens on Windows for some reason
             // @SET "=C:=C:\Users\robostack\Programs\pixi"
             // @SET "=ExitCode=00000000"
-            .filter(|(key, _)| !key.is_empty())
-            .map(|(key, value)| (key.to_owned(), value.to_owned()))
-            .collect())
+            .filter(|(key, _)| !key.is_empty());
+
+        Ok((changed_env, activation_detection_script))
     }
 }
 
@@ -515,55 +1254,335 @@ mod tests {
     }
 
     #[test]
-    fn test_collect_env_vars() {
+    #[cfg(unix)]
+    fn test_collect_scripts_symlinks() {
         let tdir = TempDir::new("test").unwrap();
-        let path = tdir.path().join("conda-meta/state");
-        fs::create_dir_all(path.parent().unwrap()).unwrap();
 
-        let quotes = r#"{"env_vars": {"Hallo": "myval", "TEST": "itsatest", "AAA": "abcdef"}}"#;
-        fs::write(&path, quotes).unwrap();
+        let path = tdir.path().join("etc/conda/activate.d/");
+        fs::create_dir_all(&path).unwrap();
 
-        let env_vars = collect_env_vars(tdir.path()).unwrap();
-        assert_eq!(env_vars.len(), 3);
+        let target = tdir.path().join("target.sh");
+        fs::write(&target, "").unwrap();
 
-        assert_eq!(env_vars["HALLO"], "myval");
-        assert_eq!(env_vars["TEST"], "itsatest");
-        assert_eq!(env_vars["AAA"], "abcdef");
+        let valid = path.join("valid.sh");
+        std::os::unix::fs::symlink(&target, &valid).unwrap();
+        std::os::unix::fs::symlink(tdir.path().join("missing.sh"), path.join("broken.sh")).unwrap();
+        let directory = tdir.path().join("directory.sh");
+        fs::create_dir_all(&directory).unwrap();
+        std::os::unix::fs::symlink(&directory, path.join("directory.sh")).unwrap();
+
+        let scripts = collect_scripts(&path, &shell::Bash).unwrap();
+        assert_eq!(scripts, vec![valid]);
     }
 
     #[test]
-    fn test_collect_env_vars_with_directory() {
+    fn test_activation_env_vars() {
         let tdir = TempDir::new("test").unwrap();
         let state_path = tdir.path().join("conda-meta/state");
         fs::create_dir_all(state_path.parent().unwrap()).unwrap();
+        let quotes = r#"{"env_vars": {"STATE": "Hello, world!", "TEST": "itsatest"}}"#;
+        fs::write(&state_path, quotes).unwrap();
 
-        let content_pkg_1 = r#"{"VAR1": "someval", "TEST": "pkg1-test", "III": "super"}"#;
-        let content_pkg_2 = r#"{"VAR1": "overwrite1", "TEST2": "pkg2-test"}"#;
-
-        let env_var_d = tdir.path().join("etc/conda/env_vars.d");
-        fs::create_dir_all(&env_var_d).expect("Could not create env vars directory");
+        let activator = Activator::from_path(tdir.path(), shell::Bash, Platform::Linux64).unwrap();
+        let result = activator
+            .activation(ActivationVariables {
+                path: Some(vec![PathBuf::from("/usr/bin")]),
+                ..ActivationVariables::default()
+            })
+            .unwrap();
 
-        let pkg1 = env_var_d.join("pkg1.json");
-        let pkg2 = env_var_d.join("pkg2.json");
+        assert_eq!(
+            result.env_vars.get("CONDA_PREFIX").map(String::as_str),
+            Some(tdir.path().to_string_lossy().as_ref())
+        );
+        assert_eq!(
+            result.env_vars.get("STATE").map(String::as_str),
+            Some("Hello, world!")
+        );
+        assert_eq!(
+            result.env_vars.get("TEST").map(String::as_str),
+            Some("itsatest")
+        );
+        assert_eq!(
+            result.env_vars.get("PATH").map(String::as_str),
+            Some(format!("{}/bin:/usr/bin", tdir.path().display()).as_str())
+        );
+    }
 
-        fs::write(&pkg1, content_pkg_1).expect("could not write file");
-        fs::write(&pkg2, content_pkg_2).expect("could not write file");
+    #[test]
+    fn test_from_path_missing_prefix() {
+        let tdir = TempDir::new("test").unwrap();
+        let missing = tdir.path().join("does-not-exist");
 
-        let quotes = r#"{"env_vars": {"Hallo": "myval", "TEST": "itsatest", "AAA": "abcdef"}}"#;
-        fs::write(&state_path, quotes).unwrap();
+        let err = Activator::from_path(&missing, shell::Bash, Platform::Linux64).unwrap_err();
+        assert!(
+            matches!(&err, ActivationError::PrefixDoesNotExist { path } if path == &missing),
+            "unexpected error: {err}"
+        );
 
-        let env_vars = collect_env_vars(tdir.path()).expect("Could not load env vars");
-        assert_eq!(env_vars.len(), 6);
+        // An existing prefix without any of the optional directories is fine
+        Activator::from_path(tdir.path(), shell::Bash, Platform::Linux64).unwrap();
+    }
 
-        assert_eq!(env_vars["VAR1"], "overwrite1");
-        assert_eq!(env_vars["TEST"], "itsatest");
-        assert_eq!(env_vars["III"], "super");
-        assert_eq!(env_vars["TEST2"], "pkg2-test");
-        assert_eq!(env_vars["HALLO"], "myval");
-        assert_eq!(env_vars["AAA"], "abcdef");
+    #[test]
+    fn test_prompt_modifier() {
+        let tdir = TempDir::new("test").unwrap();
+        let activator = Activator::from_path(tdir.path(), shell::Bash, Platform::Linux64).unwrap();
 
-        // assert order of keys
-        let mut keys = env_vars.keys();
+        let result = activator
+            .activation(ActivationVariables {
+                prompt_name: Some(String::from("envname")),
+                ..ActivationVariables::default()
+            })
+            .unwrap();
+        assert!(result
+            .script
+            .contains("export CONDA_PROMPT_MODIFIER=\"(envname) \""));
+        assert_eq!(
+            result
+                .env_vars
+                .get("CONDA_PROMPT_MODIFIER")
+                .map(String::as_str),
+            Some("(envname) ")
+        );
+
+        // Without a name the prompt is left untouched
+        let result = activator
+            .activation(ActivationVariables::default())
+            .unwrap();
+        assert!(!result.script.contains("CONDA_PROMPT_MODIFIER"));
+    }
+
+    #[test]
+    fn test_additive_env_vars() {
+        let tdir = TempDir::new("test").unwrap();
+        let state_path = tdir.path().join("conda-meta/state");
+        fs::create_dir_all(state_path.parent().unwrap()).unwrap();
+        let quotes = r#"{"env_vars": {"CMAKE_PREFIX_PATH": "/opt/cmake", "TEST": "itsatest"}}"#;
+        fs::write(&state_path, quotes).unwrap();
+
+        let activator = Activator::from_path(tdir.path(), shell::Bash, Platform::Linux64).unwrap();
+        let result = activator
+            .activation(ActivationVariables {
+                additive_env_vars: HashSet::from([String::from("CMAKE_PREFIX_PATH")]),
+                ..ActivationVariables::default()
+            })
+            .unwrap();
+
+        assert!(result
+            .script
+            .contains("export CMAKE_PREFIX_PATH=\"${CMAKE_PREFIX_PATH}:/opt/cmake\""));
+        assert!(result.script.contains("export TEST=\"itsatest\""));
+        assert!(!result.env_vars.contains_key("CMAKE_PREFIX_PATH"));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_activation_for_windows_target() {
+        let tdir = TempDir::new("test").unwrap();
+        let prefix_str = tdir.path().to_string_lossy();
+
+        let activator = Activator::from_path(tdir.path(), shell::Bash, Platform::Win64).unwrap();
+        assert_eq!(
+            activator.paths[1],
+            PathBuf::from(format!(r"{prefix_str}\Library\mingw-w64\bin"))
+        );
+        assert_eq!(
+            activator.paths[5],
+            PathBuf::from(format!(r"{prefix_str}\bin"))
+        );
+
+        let result = activator
+            .activation(ActivationVariables {
+                path: Some(vec![PathBuf::from("/usr/bin")]),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(
+            result.env_vars["PATH"],
+            format!(
+                r"{prefix_str};{prefix_str}\Library\mingw-w64\bin;{prefix_str}\Library\usr\bin;{prefix_str}\Library\bin;{prefix_str}\Scripts;{prefix_str}\bin;/usr/bin"
+            )
+        );
+        assert!(result
+            .script
+            .contains(&format!(r"{prefix_str}\Library\bin;")));
+    }
+
+    #[test]
+    fn test_activation_variables_from_json_file() {
+        let tdir = TempDir::new("test").unwrap();
+        let path = tdir.path().join("activation.json");
+
+        let variables = ActivationVariables {
+            conda_prefix: Some(PathBuf::from("/opt/conda/envs/base")),
+            path: Some(vec![PathBuf::from("/usr/bin"), PathBuf::from("/bin")]),
+            path_modification_behavior: PathModificationBehavior::Append,
+            ..Default::default()
+        };
+        fs::write(&path, serde_json::to_string(&variables).unwrap()).unwrap();
+
+        let loaded = ActivationVariables::from_json_file(&path).unwrap();
+        assert_eq!(loaded.conda_prefix, variables.conda_prefix);
+        assert_eq!(loaded.path, variables.path);
+        assert_eq!(
+            loaded.path_modification_behavior,
+            PathModificationBehavior::Append
+        );
+
+        fs::write(&path, r#"{"path_modification_behavior": "prepend"}"#).unwrap();
+        let loaded = ActivationVariables::from_json_file(&path).unwrap();
+        assert_eq!(loaded.conda_prefix, None);
+        assert_eq!(
+            loaded.path_modification_behavior,
+            PathModificationBehavior::Prepend
+        );
+
+        fs::write(&path, r#"{"path_modification_behavior": "sideways"}"#).unwrap();
+        assert!(matches!(
+            ActivationVariables::from_json_file(&path),
+            Err(ActivationError::InvalidActivationVariablesJson(_, _))
+        ));
+    }
+
+    #[test]
+    fn test_collect_env_vars() {
+        let tdir = TempDir::new("test").unwrap();
+        let path = tdir.path().join("conda-meta/state");
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+
+        let quotes = r#"{"env_vars": {"Hallo": "myval", "TEST": "itsatest", "AAA": "abcdef"}}"#;
+        fs::write(&path, quotes).unwrap();
+
+        let (env_vars, warnings) = collect_env_vars(tdir.path(), &PrefixLayout::default()).unwrap();
+        assert_eq!(env_vars.len(), 3);
+        assert_eq!(warnings, CollectWarnings::default());
+
+        assert_eq!(env_vars["HALLO"].0, "myval");
+        assert_eq!(env_vars["TEST"].0, "itsatest");
+        assert_eq!(env_vars["AAA"].0, "abcdef");
+    }
+
+    #[test]
+    fn test_from_path_with_layout() {
+        let tdir = TempDir::new("test").unwrap();
+        let activate_d = tdir.path().join("share/activate.d");
+        fs::create_dir_all(&activate_d).unwrap();
+        fs::write(activate_d.join("script1.sh"), "").unwrap();
+        let env_vars_d = tdir.path().join("share/env_vars.d");
+        fs::create_dir_all(&env_vars_d).unwrap();
+        fs::write(env_vars_d.join("pkg1.json"), r#"{"FOO": "bar"}"#).unwrap();
+
+        // Scripts in the custom location are not found with the default layout
+        let activator = Activator::from_path(tdir.path(), shell::Bash, Platform::Linux64).unwrap();
+        assert!(activator.activation_scripts.is_empty());
+        assert!(activator.env_vars.is_empty());
+
+        let activator = Activator::from_path_with_layout(
+            tdir.path(),
+            shell::Bash,
+            Platform::Linux64,
+            PrefixLayout {
+                activate_dir: PathBuf::from("share/activate.d"),
+                env_vars_dir: PathBuf::from("share/env_vars.d"),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            activator.activation_scripts,
+            vec![activate_d.join("script1.sh")]
+        );
+        assert_eq!(activator.env_vars["FOO"], "bar");
+    }
+
+    #[test]
+    fn test_collect_env_vars_warnings() {
+        let tdir = TempDir::new("test").unwrap();
+        let env_var_d = tdir.path().join("etc/conda/env_vars.d");
+        fs::create_dir_all(&env_var_d).unwrap();
+        fs::write(
+            env_var_d.join("pkg1.json"),
+            r#"{"FOO": "bar", "NUMBER": 1}"#,
+        )
+        .unwrap();
+
+        let activator = Activator::from_path(tdir.path(), shell::Bash, Platform::Linux64).unwrap();
+        assert_eq!(activator.env_vars.len(), 1);
+        assert_eq!(
+            activator.env_var_warnings,
+            CollectWarnings {
+                non_string_values: 1,
+                overridden: 0,
+            }
+        );
+        assert_eq!(activator.env_var_warnings.total(), 1);
+    }
+
+    #[test]
+    fn test_annotate_env_vars() {
+        let tdir = TempDir::new("test").unwrap();
+        let env_var_d = tdir.path().join("etc/conda/env_vars.d");
+        fs::create_dir_all(&env_var_d).unwrap();
+        let pkg1 = env_var_d.join("pkg1.json");
+        fs::write(&pkg1, r#"{"FOO": "bar"}"#).unwrap();
+
+        let activator = Activator::from_path(tdir.path(), shell::Bash, Platform::Linux64).unwrap();
+        assert_eq!(activator.env_var_sources["FOO"], pkg1);
+
+        let result = activator
+            .activation(ActivationVariables {
+                annotate_env_vars: true,
+                ..Default::default()
+            })
+            .unwrap();
+        assert!(result.script.contains(&format!(
+            "export FOO=\"bar\"\n# FOO was set by {}\n",
+            pkg1.display()
+        )));
+
+        let result = activator
+            .activation(ActivationVariables::default())
+            .unwrap();
+        assert!(!result.script.contains("was set by"));
+    }
+
+    #[test]
+    fn test_collect_env_vars_with_directory() {
+        let tdir = TempDir::new("test").unwrap();
+        let state_path = tdir.path().join("conda-meta/state");
+        fs::create_dir_all(state_path.parent().unwrap()).unwrap();
+
+        let content_pkg_1 = r#"{"VAR1": "someval", "TEST": "pkg1-test", "III": "super"}"#;
+        let content_pkg_2 = r#"{"VAR1": "overwrite1", "TEST2": "pkg2-test"}"#;
+
+        let env_var_d = tdir.path().join("etc/conda/env_vars.d");
+        fs::create_dir_all(&env_var_d).expect("Could not create env vars directory");
+
+        let pkg1 = env_var_d.join("pkg1.json");
+        let pkg2 = env_var_d.join("pkg2.json");
+
+        fs::write(&pkg1, content_pkg_1).expect("could not write file");
+        fs::write(&pkg2, content_pkg_2).expect("could not write file");
+
+        let quotes = r#"{"env_vars": {"Hallo": "myval", "TEST": "itsatest", "AAA": "abcdef"}}"#;
+        fs::write(&state_path, quotes).unwrap();
+
+        let (env_vars, warnings) = collect_env_vars(tdir.path(), &PrefixLayout::default())
+            .expect("Could not load env vars");
+        assert_eq!(env_vars.len(), 6);
+        // `VAR1` is overridden by pkg2 and `TEST` by the state file
+        assert_eq!(warnings.overridden, 2);
+
+        assert_eq!(env_vars["VAR1"].0, "overwrite1");
+        assert_eq!(env_vars["TEST"].0, "itsatest");
+        assert_eq!(env_vars["III"].0, "super");
+        assert_eq!(env_vars["TEST2"].0, "pkg2-test");
+        assert_eq!(env_vars["HALLO"].0, "myval");
+        assert_eq!(env_vars["AAA"].0, "abcdef");
+
+        // assert order of keys
+        let mut keys = env_vars.keys();
         let key_vec = vec![
             "VAR1", // overwritten - should this be sorted down?
             "TEST", "III", "TEST2", "HALLO", "AAA",
@@ -617,6 +1636,9 @@ mod tests {
                     PathBuf::from("/usr/local/bin"),
                 ]),
                 path_modification_behavior,
+                prompt_name: None,
+                additive_env_vars: HashSet::new(),
+                annotate_env_vars: false,
             })
             .unwrap();
         let prefix = tdir.path().to_str().unwrap();
@@ -635,6 +1657,99 @@ mod tests {
         insta::assert_snapshot!("test_activation_script_bash_prepend", script);
     }
 
+    #[test]
+    #[cfg(unix)]
+    fn test_activation_script_guard_prefix() {
+        let tdir = create_temp_dir();
+        let mut activator =
+            Activator::from_path(tdir.path(), shell::Bash, Platform::Osx64).unwrap();
+        activator.guard_prefix = true;
+
+        let result = activator
+            .activation(ActivationVariables {
+                path: Some(vec![PathBuf::from("/usr/bin")]),
+                path_modification_behavior: PathModificationBehavior::Prepend,
+                ..Default::default()
+            })
+            .unwrap();
+        let script = result
+            .script
+            .replace(tdir.path().to_str().unwrap(), "__PREFIX__");
+        insta::assert_snapshot!(script);
+
+        // The guard succeeds for an existing prefix and fails for a missing one
+        let mut guard = String::new();
+        shell::Bash
+            .guard_prefix_exists(&mut guard, tdir.path())
+            .unwrap();
+        let missing = tdir.path().join("missing");
+        shell::Bash
+            .guard_prefix_exists(&mut guard, &missing)
+            .unwrap();
+        let output = std::process::Command::new("bash")
+            .arg("-c")
+            .arg(format!("{guard}echo reached"))
+            .output()
+            .unwrap();
+        assert!(!output.status.success());
+        assert!(String::from_utf8_lossy(&output.stderr).contains("does not exist"));
+        assert!(!String::from_utf8_lossy(&output.stdout).contains("reached"));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_activation_script_guard_once() {
+        let tdir = create_temp_dir();
+        fs::write(
+            tdir.path().join("etc/conda/activate.d/script1.sh"),
+            "echo activated\n",
+        )
+        .unwrap();
+        let mut activator =
+            Activator::from_path(tdir.path(), shell::Bash, Platform::Osx64).unwrap();
+        activator.guard_once = true;
+
+        let result = activator
+            .activation(ActivationVariables {
+                path_modification_behavior: PathModificationBehavior::Prepend,
+                ..Default::default()
+            })
+            .unwrap();
+        let marker = activator.activation_marker();
+        assert_eq!(result.env_vars.get(&marker).map(String::as_str), Some("1"));
+        let snapshot = result
+            .script
+            .replace(&marker, "__MARKER__")
+            .replace(tdir.path().to_str().unwrap(), "__PREFIX__");
+        insta::assert_snapshot!(snapshot);
+
+        // Sourcing the script twice only applies the activation once
+        let script_path = tdir.path().join("activate.sh");
+        fs::write(&script_path, &result.script).unwrap();
+        let output = std::process::Command::new("bash")
+            .arg("-c")
+            .arg(format!(
+                ". {0}; . {0}; echo \"$PATH\"",
+                shell::Bash.quote_path(&script_path)
+            ))
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert_eq!(stdout.matches("activated").count(), 1);
+        let bin = tdir.path().join("bin");
+        assert_eq!(
+            stdout
+                .lines()
+                .last()
+                .unwrap()
+                .split(':')
+                .filter(|entry| Path::new(entry) == bin)
+                .count(),
+            1
+        );
+    }
+
     #[test]
     #[cfg(unix)]
     fn test_activation_script_zsh() {
@@ -687,6 +1802,53 @@ mod tests {
         insta::assert_snapshot!(script);
     }
 
+    /// Asserts that wrapping `shell` in a [`ShellEnum`] does not change the generated activation
+    /// script or the way scripts are run.
+    #[cfg(unix)]
+    fn assert_shell_enum_delegates<T: Shell + Clone + Into<ShellEnum>>(shell: T) {
+        let shell_enum: ShellEnum = shell.clone().into();
+        for behavior in [
+            PathModificationBehavior::Append,
+            PathModificationBehavior::Prepend,
+            PathModificationBehavior::Replace,
+        ] {
+            assert_eq!(
+                get_script(shell_enum.clone(), behavior.clone()),
+                get_script(shell.clone(), behavior)
+            );
+        }
+
+        let script_path = Path::new("/tmp/script");
+        let command = shell_enum.create_run_script_command(script_path);
+        let expected = shell.create_run_script_command(script_path);
+        assert_eq!(command.get_program(), expected.get_program());
+        assert!(command.get_args().eq(expected.get_args()));
+        assert_eq!(shell_enum.extension(), shell.extension());
+        assert_eq!(
+            shell_enum.parse_env("FOO=bar\n"),
+            shell.parse_env("FOO=bar\n")
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_activation_shell_enum() {
+        assert_shell_enum_delegates(shell::Bash);
+        assert_shell_enum_delegates(shell::Zsh);
+        assert_shell_enum_delegates(shell::Xonsh);
+        assert_shell_enum_delegates(shell::CmdExe);
+        assert_shell_enum_delegates(shell::PowerShell::default());
+        assert_shell_enum_delegates(shell::Fish);
+        assert_shell_enum_delegates(shell::NuShell);
+
+        // A shell that is only known at runtime can be used to create an activation script
+        let script = get_script(
+            ShellEnum::from_env_or_default(),
+            PathModificationBehavior::Prepend,
+        );
+        assert!(script.contains("__PREFIX__"));
+    }
+
     fn test_run_activation(shell: ShellEnum) {
         let environment_dir = tempfile::TempDir::new().unwrap();
 
@@ -747,6 +1909,449 @@ mod tests {
         insta::assert_yaml_snapshot!(shell.executable(), env_diff);
     }
 
+    #[test]
+    #[cfg(unix)]
+    fn test_activation_oneline_bash() {
+        let tdir = TempDir::new("test").unwrap();
+        let env_var_d = tdir.path().join("etc/conda/env_vars.d");
+        fs::create_dir_all(&env_var_d).unwrap();
+        fs::write(env_var_d.join("pkg1.json"), r#"{"PKG1": "Hello, world!"}"#).unwrap();
+
+        let activator =
+            Activator::from_path(tdir.path(), shell::Bash, Platform::current()).unwrap();
+        let oneline = activator
+            .activation_oneline(ActivationVariables::default())
+            .unwrap();
+        assert!(!oneline.contains('\n'));
+        assert!(oneline.contains("; export"));
+
+        // Running the single line should result in the same environment as the script
+        let output = std::process::Command::new("bash")
+            .arg("-c")
+            .arg(format!("{oneline}; /usr/bin/env"))
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let env = shell::Bash.parse_env(&stdout);
+        assert_eq!(env.get("PKG1"), Some(&"Hello, world!"));
+        assert_eq!(env.get("CONDA_PREFIX"), tdir.path().to_str().as_ref());
+    }
+
+    #[test]
+    fn test_activation_oneline_guard_once() {
+        let tdir = TempDir::new("test").unwrap();
+        let mut activator =
+            Activator::from_path(tdir.path(), shell::Bash, Platform::current()).unwrap();
+        activator.guard_once = true;
+
+        // Joining the guarded script would produce invalid shell code, e.g. `then; export ...`
+        assert!(matches!(
+            activator.activation_oneline(ActivationVariables::default()),
+            Err(ActivationError::GuardOnceNotSupportedOnOneline)
+        ));
+    }
+
+    #[test]
+    fn test_activation_variables_from_env() {
+        let variables = ActivationVariables::from_env().unwrap();
+        let expected =
+            std::env::var_os("PATH").map(|path| std::env::split_paths(&path).collect::<Vec<_>>());
+        assert!(expected.is_some());
+        assert_eq!(variables.path, expected);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_activation_env_vars_defaults() {
+        let tdir = TempDir::new("test").unwrap();
+        let mut activator =
+            Activator::from_path(tdir.path(), shell::Bash, Platform::current()).unwrap();
+        activator
+            .env_vars_defaults
+            .insert("RATTLER_TEST_DEFAULT".to_string(), "default".to_string());
+        activator
+            .env_vars_defaults
+            .insert("RATTLER_TEST_OVERRIDE".to_string(), "default".to_string());
+
+        let script = activator
+            .activation(ActivationVariables::default())
+            .unwrap()
+            .script;
+        let output = std::process::Command::new("bash")
+            .arg("-c")
+            .arg(format!("{script}\n/usr/bin/env"))
+            .env("RATTLER_TEST_OVERRIDE", "user")
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let env = shell::Bash.parse_env(&stdout);
+        assert_eq!(env.get("RATTLER_TEST_DEFAULT"), Some(&"default"));
+        assert_eq!(env.get("RATTLER_TEST_OVERRIDE"), Some(&"user"));
+    }
+
+    #[test]
+    fn test_reactivation() {
+        let tdir = TempDir::new("test").unwrap();
+        let activator = Activator::from_path(tdir.path(), shell::Bash, Platform::Osx64).unwrap();
+        let prefix_bin = tdir.path().join("bin");
+
+        // The prefix is already active and its paths are part of PATH
+        let result = activator
+            .activation(ActivationVariables {
+                conda_prefix: Some(tdir.path().to_path_buf()),
+                path: Some(vec![
+                    prefix_bin.clone(),
+                    PathBuf::from("/usr/bin"),
+                    prefix_bin.clone(),
+                ]),
+                path_modification_behavior: PathModificationBehavior::Prepend,
+                prompt_name: None,
+                additive_env_vars: HashSet::new(),
+                annotate_env_vars: false,
+            })
+            .unwrap();
+        assert_eq!(
+            result.path,
+            vec![prefix_bin.clone(), PathBuf::from("/usr/bin")]
+        );
+        assert_eq!(
+            result.script.matches(prefix_bin.to_str().unwrap()).count(),
+            1
+        );
+
+        // Without knowing the current PATH the paths should not be prepended to PATH again
+        let result = activator
+            .activation(ActivationVariables {
+                conda_prefix: Some(tdir.path().to_path_buf()),
+                path: None,
+                path_modification_behavior: PathModificationBehavior::Prepend,
+                prompt_name: None,
+                additive_env_vars: HashSet::new(),
+                annotate_env_vars: false,
+            })
+            .unwrap();
+        assert_eq!(result.path, vec![prefix_bin.clone()]);
+        assert!(!result.script.contains(prefix_bin.to_str().unwrap()));
+    }
+
+    #[test]
+    fn test_activation_with_log() {
+        let tdir = TempDir::new("test").unwrap();
+        let log_path = tdir.path().join("activation.log");
+
+        let activator = Activator::from_path(tdir.path(), shell::Bash, Platform::Osx64).unwrap();
+        for _ in 0..2 {
+            activator
+                .activation_with_log(ActivationVariables::default(), &log_path)
+                .unwrap();
+        }
+
+        let log = fs::read_to_string(&log_path).unwrap();
+        let entries = log
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(entries.len(), 2);
+        for entry in entries {
+            assert_eq!(entry["prefix"].as_str(), tdir.path().to_str());
+            assert_eq!(entry["shell"].as_str(), Some("bash"));
+            assert_eq!(entry["path_len"].as_u64(), Some(1));
+            assert!(entry["timestamp"].is_u64());
+        }
+    }
+
+    #[test]
+    fn test_activation_as_json() {
+        let tdir = TempDir::new("test").unwrap();
+        let env_var_d = tdir.path().join("etc/conda/env_vars.d");
+        fs::create_dir_all(&env_var_d).unwrap();
+        fs::write(env_var_d.join("pkg1.json"), r#"{"PKG1": "Hello, world!"}"#).unwrap();
+
+        let activator = Activator::from_path(tdir.path(), shell::Bash, Platform::Osx64).unwrap();
+        let json = activator
+            .activation_as_json(ActivationVariables {
+                conda_prefix: None,
+                path: Some(vec![PathBuf::from("/usr/bin"), PathBuf::from("/bin")]),
+                path_modification_behavior: PathModificationBehavior::Prepend,
+                prompt_name: None,
+                additive_env_vars: HashSet::new(),
+                annotate_env_vars: false,
+            })
+            .unwrap();
+
+        let env: BTreeMap<String, String> = serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            env.get("CONDA_PREFIX").map(String::as_str),
+            tdir.path().to_str()
+        );
+        assert_eq!(
+            env["PATH"],
+            format!("{}:/usr/bin:/bin", tdir.path().join("bin").display())
+        );
+        assert_eq!(env["PKG1"], "Hello, world!");
+    }
+
+    #[test]
+    fn test_activation_as_json_append() {
+        let tdir = TempDir::new("test").unwrap();
+        let env_var_d = tdir.path().join("etc/conda/env_vars.d");
+        fs::create_dir_all(&env_var_d).unwrap();
+        fs::write(env_var_d.join("pkg1.json"), r#"{"PKG1": "Hello, world!"}"#).unwrap();
+
+        let mut activator =
+            Activator::from_path(tdir.path(), shell::Bash, Platform::Osx64).unwrap();
+        activator.guard_once = true;
+        let variables = ActivationVariables {
+            path: Some(vec![PathBuf::from("/usr/bin"), PathBuf::from("/bin")]),
+            path_modification_behavior: PathModificationBehavior::Append,
+            prompt_name: Some(String::from("myenv")),
+            ..Default::default()
+        };
+
+        let result = activator.activation(variables.clone()).unwrap();
+        let prefix_bin = tdir.path().join("bin");
+        assert_eq!(
+            result.path,
+            vec![
+                PathBuf::from("/usr/bin"),
+                PathBuf::from("/bin"),
+                prefix_bin.clone()
+            ]
+        );
+        assert_eq!(
+            result.env_vars["PATH"],
+            format!("/usr/bin:/bin:{}", prefix_bin.display())
+        );
+
+        let json = activator.activation_as_json(variables).unwrap();
+        let env: BTreeMap<String, String> = serde_json::from_str(&json).unwrap();
+        assert_eq!(env, result.env_vars.into_iter().collect::<BTreeMap<_, _>>());
+        assert!(env.contains_key("CONDA_PROMPT_MODIFIER"));
+        assert!(env.contains_key(&activator.activation_marker()));
+    }
+
+    #[test]
+    fn test_env_snapshot_round_trip() {
+        let tdir = TempDir::new("test").unwrap();
+        let env_var_d = tdir.path().join("etc/conda/env_vars.d");
+        fs::create_dir_all(&env_var_d).unwrap();
+        fs::write(env_var_d.join("pkg1.json"), r#"{"PKG1": "Hello, world!"}"#).unwrap();
+
+        let activator = Activator::from_path(tdir.path(), shell::Bash, Platform::Osx64).unwrap();
+        let variables = ActivationVariables {
+            path: Some(vec![PathBuf::from("/usr/bin"), PathBuf::from("/bin")]),
+            path_modification_behavior: PathModificationBehavior::Prepend,
+            ..Default::default()
+        };
+        let expected: HashMap<String, String> = activator
+            .activation(variables.clone())
+            .unwrap()
+            .env_vars
+            .into_iter()
+            .collect();
+
+        let snapshot = tdir.path().join("snapshot.json");
+        activator.write_env_snapshot(variables, &snapshot).unwrap();
+        let env = apply_env_snapshot(&snapshot).unwrap();
+        assert_eq!(env, expected);
+        assert_eq!(env["PKG1"], "Hello, world!");
+
+        fs::write(&snapshot, r#"{"version": 2, "path": [], "env_vars": {}}"#).unwrap();
+        assert!(matches!(
+            apply_env_snapshot(&snapshot),
+            Err(ActivationError::UnsupportedEnvSnapshotVersion { version: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn test_activation_with_log_failure_is_not_fatal() {
+        let tdir = TempDir::new("test").unwrap();
+        let log_path = tdir.path().join("does/not/exist/activation.log");
+
+        let activator = Activator::from_path(tdir.path(), shell::Bash, Platform::Osx64).unwrap();
+        activator
+            .activation_with_log(ActivationVariables::default(), &log_path)
+            .unwrap();
+        assert!(!log_path.exists());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_run_activation_iter() {
+        let environment_dir = tempfile::TempDir::new().unwrap();
+
+        let state_path = environment_dir.path().join("conda-meta/state");
+        fs::create_dir_all(state_path.parent().unwrap()).unwrap();
+        let quotes = r#"{"env_vars": {"STATE": "Hello, world!"}}"#;
+        fs::write(&state_path, quotes).unwrap();
+
+        let activator =
+            Activator::from_path(environment_dir.path(), shell::Bash, Platform::current()).unwrap();
+
+        let activation_env = activator
+            .run_activation(ActivationVariables::default())
+            .unwrap();
+        let activation_env_iter = activator
+            .run_activation_iter(ActivationVariables::default())
+            .unwrap()
+            .collect::<HashMap<_, _>>();
+
+        assert_eq!(activation_env, activation_env_iter);
+        assert_eq!(
+            activation_env_iter.get("STATE").map(String::as_str),
+            Some("Hello, world!")
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_run_activation_with_script() {
+        let environment_dir = tempfile::TempDir::new().unwrap();
+
+        let state_path = environment_dir.path().join("conda-meta/state");
+        fs::create_dir_all(state_path.parent().unwrap()).unwrap();
+        let quotes = r#"{"env_vars": {"STATE": "Hello, world!"}}"#;
+        fs::write(&state_path, quotes).unwrap();
+
+        let activator =
+            Activator::from_path(environment_dir.path(), shell::Bash, Platform::current()).unwrap();
+
+        let (activation_env, script) = activator
+            .run_activation_with_script(ActivationVariables::default())
+            .unwrap();
+
+        assert_eq!(
+            activation_env.get("STATE").map(String::as_str),
+            Some("Hello, world!")
+        );
+        assert_eq!(script.matches("<=== RATTLER ENV START").count(), 2);
+        let activation_script = activator
+            .activation(ActivationVariables::default())
+            .unwrap()
+            .script;
+        assert!(script.contains(&activation_script));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_run_activation_in_dir() {
+        let environment_dir = tempfile::TempDir::new().unwrap();
+        let activate_d = environment_dir.path().join("etc/conda/activate.d");
+        fs::create_dir_all(&activate_d).unwrap();
+        fs::write(
+            activate_d.join("relative.sh"),
+            "echo activated > marker.txt\n",
+        )
+        .unwrap();
+
+        let activator =
+            Activator::from_path(environment_dir.path(), shell::Bash, Platform::current()).unwrap();
+
+        // By default the script runs from the root of the prefix
+        activator
+            .run_activation(ActivationVariables::default())
+            .unwrap();
+        assert!(environment_dir.path().join("marker.txt").is_file());
+
+        let cwd = tempfile::TempDir::new().unwrap();
+        activator
+            .run_activation_in_dir(ActivationVariables::default(), cwd.path())
+            .unwrap();
+        assert_eq!(
+            fs::read_to_string(cwd.path().join("marker.txt")).unwrap(),
+            "activated\n"
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_run_activation_with_options() {
+        let environment_dir = tempfile::TempDir::new().unwrap();
+        let activate_d = environment_dir.path().join("etc/conda/activate.d");
+        fs::create_dir_all(&activate_d).unwrap();
+        fs::write(
+            activate_d.join("derived.sh"),
+            "export DERIVED=\"${RATTLER_BASE_VAR:-unset}-derived\"\n",
+        )
+        .unwrap();
+
+        let activator =
+            Activator::from_path(environment_dir.path(), shell::Bash, Platform::current()).unwrap();
+
+        // The script sees the base environment instead of the environment of this process
+        let base_env = HashMap::from([
+            (
+                String::from("PATH"),
+                std::env::var("PATH").unwrap_or_default(),
+            ),
+            (String::from("RATTLER_BASE_VAR"), String::from("base")),
+        ]);
+        let env = activator
+            .run_activation_with_options(
+                ActivationVariables::default(),
+                &RunActivationOptions {
+                    base_env: Some(base_env),
+                    timeout: Some(Duration::from_secs(60)),
+                    ..RunActivationOptions::default()
+                },
+            )
+            .unwrap();
+        assert_eq!(env["DERIVED"], "base-derived");
+
+        // A script that does not finish in time is killed
+        fs::write(activate_d.join("slow.sh"), "sleep 5\n").unwrap();
+        let activator =
+            Activator::from_path(environment_dir.path(), shell::Bash, Platform::current()).unwrap();
+        let start = Instant::now();
+        let result = activator.run_activation_with_options(
+            ActivationVariables {
+                // Keep the `PATH` of this process so `sleep` can be found
+                path: std::env::var_os("PATH").map(|path| std::env::split_paths(&path).collect()),
+                path_modification_behavior: PathModificationBehavior::Prepend,
+                ..ActivationVariables::default()
+            },
+            &RunActivationOptions {
+                timeout: Some(Duration::from_millis(200)),
+                ..RunActivationOptions::default()
+            },
+        );
+        assert!(matches!(
+            result,
+            Err(ActivationError::ActivationScriptTimedOut { .. })
+        ));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_run_activation_separator_collision() {
+        let environment_dir = tempfile::TempDir::new().unwrap();
+
+        // Set an environment variable that contains the separator that used to be hardcoded.
+        let state_path = environment_dir.path().join("conda-meta/state");
+        fs::create_dir_all(state_path.parent().unwrap()).unwrap();
+        let quotes =
+            r#"{"env_vars": {"SEPARATOR": "<=== RATTLER ENV START ===>", "OTHER": "value"}}"#;
+        fs::write(&state_path, quotes).unwrap();
+
+        let activator =
+            Activator::from_path(environment_dir.path(), shell::Bash, Platform::current()).unwrap();
+        let activation_env = activator
+            .run_activation(ActivationVariables::default())
+            .unwrap();
+
+        assert_eq!(
+            activation_env.get("SEPARATOR").map(String::as_str),
+            Some("<=== RATTLER ENV START ===>")
+        );
+        assert_eq!(
+            activation_env.get("OTHER").map(String::as_str),
+            Some("value")
+        );
+    }
+
     #[test]
     #[cfg(windows)]
     fn test_run_activation_powershell() {
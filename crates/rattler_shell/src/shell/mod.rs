@@ -5,7 +5,7 @@ use enum_dispatch::enum_dispatch;
 use itertools::Itertools;
 use rattler_conda_types::Platform;
 use std::collections::HashMap;
-use std::ffi::OsStr;
+use std::ffi::{OsStr, OsString};
 use std::process::Command;
 use std::str::FromStr;
 use std::{
@@ -35,12 +35,44 @@ pub trait Shell {
     /// Set an env var by `export`-ing it.
     fn set_env_var(&self, f: &mut impl Write, env_var: &str, value: &str) -> std::fmt::Result;
 
+    /// Set an env var only if it is not already set, e.g. to provide a default value that the
+    /// user can override.
+    ///
+    /// The default implementation uses parameter expansion which is correct for POSIX compatible
+    /// shells.
+    fn set_env_var_if_unset(
+        &self,
+        f: &mut impl Write,
+        env_var: &str,
+        value: &str,
+    ) -> std::fmt::Result {
+        // `: "${KEY:=value}"` would not export the variable, so use `:-` and export the result.
+        writeln!(f, "export {env_var}=\"${{{env_var}:-{value}}}\"")
+    }
+
     /// Unset an env var by `unset`-ing it.
     fn unset_env_var(&self, f: &mut impl Write, env_var: &str) -> std::fmt::Result;
 
+    /// Writes a single line comment.
+    ///
+    /// The default implementation uses `#` which is correct for most shells.
+    fn write_comment(&self, f: &mut impl Write, comment: &str) -> std::fmt::Result {
+        writeln!(f, "# {comment}")
+    }
+
     /// Run a script in the current shell.
     fn run_script(&self, f: &mut impl Write, path: &Path) -> std::fmt::Result;
 
+    /// Quotes a filesystem path so it can be embedded in a script for this shell as a single
+    /// argument, even if it contains spaces or other characters that have a special meaning to the
+    /// shell.
+    ///
+    /// The default implementation wraps the path in single quotes which is correct for POSIX
+    /// compatible shells.
+    fn quote_path(&self, path: &Path) -> String {
+        format!("'{}'", path.to_string_lossy().replace('\'', r"'\''"))
+    }
+
     /// Test to see if the path can be executed by the shell, based on the extension of the path.
     fn can_run_script(&self, path: &Path) -> bool {
         path.is_file()
@@ -84,6 +116,20 @@ pub trait Shell {
         self.set_env_var(f, "PATH", paths_string.as_str())
     }
 
+    /// Removes every occurrence of `dir` from the PATH variable of the running shell while keeping
+    /// all other entries in order.
+    ///
+    /// The default implementation uses parameter expansion which is correct for POSIX compatible
+    /// shells.
+    fn remove_path_entry(
+        &self,
+        f: &mut impl Write,
+        dir: &Path,
+        platform: &Platform,
+    ) -> std::fmt::Result {
+        write_posix_remove_path_entry(f, &self.quote_path(dir), self.path_seperator(platform))
+    }
+
     /// The extension that shell scripts for this interpreter usually use.
     fn extension(&self) -> &str;
 
@@ -91,14 +137,25 @@ pub trait Shell {
     fn executable(&self) -> &str;
 
     /// Constructs a [`Command`] that will execute the specified script by this shell.
+    ///
+    /// If the shell supports it, the command does not read the startup files of the user (e.g.
+    /// `.bashrc` or the PowerShell profile) so the environment of the script is not affected by
+    /// them. Use [`Self::create_run_script_command_with_startup_files`] to read them.
     fn create_run_script_command(&self, path: &Path) -> Command;
 
-    /// Path seperator
+    /// Same as [`Self::create_run_script_command`] but the shell reads the startup files of the
+    /// user as it normally would.
+    fn create_run_script_command_with_startup_files(&self, path: &Path) -> Command {
+        self.create_run_script_command(path)
+    }
+
+    /// The separator used to join the entries of the `PATH` variable. This is derived from the
+    /// target `platform` and not from the host so scripts can be generated for other platforms.
     fn path_seperator(&self, platform: &Platform) -> &str {
-        if platform.is_unix() {
-            ":"
-        } else {
+        if platform.is_windows() {
             ";"
+        } else {
+            ":"
         }
     }
 
@@ -107,9 +164,53 @@ pub trait Shell {
         format!("${{{var_name}}}")
     }
 
+    /// Formats the name of an environment for the `CONDA_PROMPT_MODIFIER` environment variable
+    /// which is prepended to the prompt of the shell.
+    fn prompt_modifier(&self, name: &str) -> String {
+        format!("({name}) ")
+    }
+
+    /// Escapes `text` so that it is printed verbatim when passed as the argument of [`Shell::echo`],
+    /// even if it contains spaces, quotes or characters that would otherwise be expanded.
+    ///
+    /// The default implementation wraps the text in single quotes which is correct for POSIX
+    /// compatible shells.
+    fn escape_for_echo(&self, text: &str) -> String {
+        format!("'{}'", text.replace('\'', r"'\''"))
+    }
+
     /// Emits echoing certain text to stdout.
     fn echo(&self, f: &mut impl Write, text: &str) -> std::fmt::Result {
-        writeln!(f, "echo {}", shlex::quote(text))
+        writeln!(f, "echo {}", self.escape_for_echo(text))
+    }
+
+    /// Emits a check that stops the script with an error if the `prefix` directory does not
+    /// exist.
+    ///
+    /// The default implementation is correct for POSIX compatible shells. It returns from a
+    /// sourced script and exits a script that is executed directly.
+    fn guard_prefix_exists(&self, f: &mut impl Write, prefix: &Path) -> std::fmt::Result {
+        writeln!(
+            f,
+            "[ -d {} ] || {{ echo {} >&2; return 1 2>/dev/null || exit 1; }}",
+            self.quote_path(prefix),
+            self.escape_for_echo(&missing_prefix_message(prefix))
+        )
+    }
+
+    /// Emits the (already generated) `body` so that it only runs if the environment variable
+    /// `marker` is not set. The marker is set at the end of the body, sourcing the script a second
+    /// time in the same shell is therefore a no-op.
+    ///
+    /// The emitted code spans multiple lines and cannot be joined with
+    /// [`Self::chain_commands`].
+    ///
+    /// The default implementation is correct for POSIX compatible shells.
+    fn guard_once(&self, f: &mut impl Write, marker: &str, body: &str) -> std::fmt::Result {
+        writeln!(f, "if [ -z \"${{{marker}:-}}\" ]; then")?;
+        write!(f, "{body}")?;
+        self.set_env_var(f, marker, "1")?;
+        writeln!(f, "fi")
     }
 
     /// Emits writing all current environment variables to stdout.
@@ -117,6 +218,21 @@ pub trait Shell {
         writeln!(f, "/usr/bin/env")
     }
 
+    /// The separator that is used to chain multiple statements on a single line.
+    fn statement_separator(&self) -> &str {
+        ";"
+    }
+
+    /// Joins multiple statements into a single line using the [`Self::statement_separator`].
+    /// Empty statements are skipped.
+    fn chain_commands<'a>(&self, commands: impl IntoIterator<Item = &'a str>) -> String {
+        commands
+            .into_iter()
+            .map(str::trim)
+            .filter(|command| !command.is_empty())
+            .join(&format!("{} ", self.statement_separator()))
+    }
+
     /// Parses environment variables emitted by the `Shell::env` command.
     fn parse_env<'i>(&self, env: &'i str) -> HashMap<&'i str, &'i str> {
         env.lines()
@@ -160,6 +276,49 @@ fn native_path_to_unix(path: &str) -> Result<String, std::io::Error> {
     }
 }
 
+/// Converts `path` to the form that Bash expects. When targeting Windows the native path is
+/// converted to a unix style path for (Git) Bash.
+fn bash_path(path: &Path, platform: &Platform) -> String {
+    if platform.is_windows() {
+        match native_path_to_unix(path.to_string_lossy().as_ref()) {
+            Ok(path) => path,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                // This indicates that the cypath executable could not be found. In that
+                // case we just ignore any conversion and use the windows path directly.
+                path.to_string_lossy().to_string()
+            }
+            Err(e) => panic!("{e}"),
+        }
+    } else {
+        path.to_string_lossy().into_owned()
+    }
+}
+
+/// The message that is shown by [`Shell::guard_prefix_exists`] if the prefix does not exist.
+fn missing_prefix_message(prefix: &Path) -> String {
+    format!("The prefix {} does not exist", prefix.display())
+}
+
+/// Writes a POSIX compatible snippet that removes every occurrence of the already quoted `dir`
+/// from `PATH`. The entries are surrounded with separators so the first, last and middle entries
+/// can all be matched by the same pattern.
+fn write_posix_remove_path_entry(
+    f: &mut impl Write,
+    quoted_dir: &str,
+    separator: &str,
+) -> std::fmt::Result {
+    let entry = format!("{separator}{quoted_dir}{separator}");
+    writeln!(f, "__rattler_path=\"{separator}${{PATH}}{separator}\"")?;
+    writeln!(
+        f,
+        "while case \"${{__rattler_path}}\" in *{entry}*) true ;; *) false ;; esac; do \
+         __rattler_path=\"${{__rattler_path%%{entry}*}}{separator}${{__rattler_path#*{entry}}}\"; done"
+    )?;
+    writeln!(f, "__rattler_path=\"${{__rattler_path#{separator}}}\"")?;
+    writeln!(f, "export PATH=\"${{__rattler_path%{separator}}}\"")?;
+    writeln!(f, "unset __rattler_path")
+}
+
 /// A [`Shell`] implementation for the Bash shell.
 #[derive(Debug, Clone, Copy, Default)]
 pub struct Bash;
@@ -174,7 +333,7 @@ impl Shell for Bash {
     }
 
     fn run_script(&self, f: &mut impl Write, path: &Path) -> std::fmt::Result {
-        writeln!(f, ". \"{}\"", path.to_string_lossy())
+        writeln!(f, ". {}", self.quote_path(path))
     }
 
     fn set_path(
@@ -187,22 +346,7 @@ impl Shell for Bash {
         // Put paths in a vector of the correct format.
         let mut paths_vec = paths
             .iter()
-            .map(|path| {
-                // check if we are on Windows, and if yes, convert native path to unix for (Git) Bash
-                if cfg!(windows) {
-                    match native_path_to_unix(path.to_string_lossy().as_ref()) {
-                        Ok(path) => path,
-                        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
-                            // This indicates that the cypath executable could not be found. In that
-                            // case we just ignore any conversion and use the windows path directly.
-                            path.to_string_lossy().to_string()
-                        }
-                        Err(e) => panic!("{e}"),
-                    }
-                } else {
-                    path.to_string_lossy().into_owned()
-                }
-            })
+            .map(|path| bash_path(path, platform))
             .collect_vec();
 
         // Replace, Append, or Prepend the path variable to the paths.
@@ -217,6 +361,20 @@ impl Shell for Bash {
         self.set_env_var(f, "PATH", paths_string.as_str())
     }
 
+    fn remove_path_entry(
+        &self,
+        f: &mut impl Write,
+        dir: &Path,
+        platform: &Platform,
+    ) -> std::fmt::Result {
+        let dir = bash_path(dir, platform);
+        write_posix_remove_path_entry(
+            f,
+            &self.quote_path(Path::new(&dir)),
+            self.path_seperator(platform),
+        )
+    }
+
     fn extension(&self) -> &str {
         "sh"
     }
@@ -226,7 +384,20 @@ impl Shell for Bash {
     }
 
     fn create_run_script_command(&self, path: &Path) -> Command {
+        self.run_script_command(path, false)
+    }
+
+    fn create_run_script_command_with_startup_files(&self, path: &Path) -> Command {
+        self.run_script_command(path, true)
+    }
+}
+
+impl Bash {
+    fn run_script_command(&self, path: &Path, read_startup_files: bool) -> Command {
         let mut cmd = Command::new(self.executable());
+        if !read_startup_files {
+            cmd.arg("--norc").arg("--noprofile");
+        }
 
         // check if we are on Windows, and if yes, convert native path to unix for (Git) Bash
         if cfg!(windows) {
@@ -253,7 +424,7 @@ impl Shell for Zsh {
     }
 
     fn run_script(&self, f: &mut impl Write, path: &Path) -> std::fmt::Result {
-        writeln!(f, ". \"{}\"", path.to_string_lossy())
+        writeln!(f, ". {}", self.quote_path(path))
     }
 
     fn extension(&self) -> &str {
@@ -265,6 +436,12 @@ impl Shell for Zsh {
     }
 
     fn create_run_script_command(&self, path: &Path) -> Command {
+        let mut cmd = Command::new(self.executable());
+        cmd.arg("--no-rcs").arg(path);
+        cmd
+    }
+
+    fn create_run_script_command_with_startup_files(&self, path: &Path) -> Command {
         let mut cmd = Command::new(self.executable());
         cmd.arg(path);
         cmd
@@ -276,10 +453,38 @@ impl Shell for Zsh {
 pub struct Xonsh;
 
 impl Shell for Xonsh {
+    fn guard_prefix_exists(&self, f: &mut impl Write, prefix: &Path) -> std::fmt::Result {
+        writeln!(
+            f,
+            "if not __import__('os').path.isdir({}): raise RuntimeError({})",
+            self.quote_path(prefix),
+            self.escape_for_echo(&missing_prefix_message(prefix))
+        )
+    }
+
+    fn guard_once(&self, f: &mut impl Write, marker: &str, body: &str) -> std::fmt::Result {
+        writeln!(f, "if not ${{...}}.get(\"{marker}\"):")?;
+        let mut body = body.to_owned();
+        self.set_env_var(&mut body, marker, "1")?;
+        for line in body.lines() {
+            writeln!(f, "    {line}")?;
+        }
+        Ok(())
+    }
+
     fn set_env_var(&self, f: &mut impl Write, env_var: &str, value: &str) -> std::fmt::Result {
         writeln!(f, "${} = \"{}\"", env_var, value)
     }
 
+    fn set_env_var_if_unset(
+        &self,
+        f: &mut impl Write,
+        env_var: &str,
+        value: &str,
+    ) -> std::fmt::Result {
+        writeln!(f, "${{...}}.setdefault(\"{}\", \"{}\")", env_var, value)
+    }
+
     fn unset_env_var(&self, f: &mut impl Write, env_var: &str) -> std::fmt::Result {
         writeln!(f, "del ${}", env_var)
     }
@@ -290,7 +495,21 @@ impl Shell for Xonsh {
             Some("sh") => "source-bash",
             _ => "source",
         };
-        writeln!(f, "{} \"{}\"", cmd, path.to_string_lossy())
+        writeln!(f, "{} {}", cmd, self.quote_path(path))
+    }
+
+    fn quote_path(&self, path: &Path) -> String {
+        // Xonsh uses python string literals
+        format!(
+            "'{}'",
+            path.to_string_lossy()
+                .replace('\\', r"\\")
+                .replace('\'', r"\'")
+        )
+    }
+
+    fn escape_for_echo(&self, text: &str) -> String {
+        format!("'{}'", text.replace('\\', r"\\").replace('\'', r"\'"))
     }
 
     fn can_run_script(&self, path: &Path) -> bool {
@@ -301,6 +520,19 @@ impl Shell for Xonsh {
                 .map_or(false, |ext| ext == "xsh" || ext == "sh")
     }
 
+    fn remove_path_entry(
+        &self,
+        f: &mut impl Write,
+        dir: &Path,
+        _platform: &Platform,
+    ) -> std::fmt::Result {
+        writeln!(
+            f,
+            "$PATH = [p for p in $PATH if p != {}]",
+            self.quote_path(dir)
+        )
+    }
+
     fn extension(&self) -> &str {
         "xsh"
     }
@@ -310,6 +542,12 @@ impl Shell for Xonsh {
     }
 
     fn create_run_script_command(&self, path: &Path) -> Command {
+        let mut cmd = Command::new(self.executable());
+        cmd.arg("--no-rc").arg(path);
+        cmd
+    }
+
+    fn create_run_script_command_with_startup_files(&self, path: &Path) -> Command {
         let mut cmd = Command::new(self.executable());
         cmd.arg(path);
         cmd
@@ -321,16 +559,53 @@ impl Shell for Xonsh {
 pub struct CmdExe;
 
 impl Shell for CmdExe {
+    fn guard_prefix_exists(&self, f: &mut impl Write, prefix: &Path) -> std::fmt::Result {
+        writeln!(
+            f,
+            "@IF NOT EXIST {} (@ECHO {} 1>&2 & @EXIT /B 1)",
+            self.quote_path(prefix),
+            self.escape_for_echo(&missing_prefix_message(prefix))
+        )
+    }
+
+    fn guard_once(&self, f: &mut impl Write, marker: &str, body: &str) -> std::fmt::Result {
+        // Wrapping the body in a parenthesized block would expand all variables of the block at
+        // once, so jump over the body instead.
+        writeln!(f, "@IF DEFINED {marker} @GOTO {marker}_DONE")?;
+        write!(f, "{body}")?;
+        self.set_env_var(f, marker, "1")?;
+        writeln!(f, ":{marker}_DONE")
+    }
+
     fn set_env_var(&self, f: &mut impl Write, env_var: &str, value: &str) -> std::fmt::Result {
         writeln!(f, "@SET \"{}={}\"", env_var, value)
     }
 
+    fn set_env_var_if_unset(
+        &self,
+        f: &mut impl Write,
+        env_var: &str,
+        value: &str,
+    ) -> std::fmt::Result {
+        writeln!(f, "@IF NOT DEFINED {0} @SET \"{0}={1}\"", env_var, value)
+    }
+
     fn unset_env_var(&self, f: &mut impl Write, env_var: &str) -> std::fmt::Result {
         writeln!(f, "@SET {}=", env_var)
     }
 
+    fn write_comment(&self, f: &mut impl Write, comment: &str) -> std::fmt::Result {
+        writeln!(f, "@REM {comment}")
+    }
+
     fn run_script(&self, f: &mut impl Write, path: &Path) -> std::fmt::Result {
-        writeln!(f, "@CALL \"{}\"", path.to_string_lossy())
+        writeln!(f, "@CALL {}", self.quote_path(path))
+    }
+
+    fn quote_path(&self, path: &Path) -> String {
+        // Inside double quotes `&`, `^` and spaces lose their special meaning. Only `%` still
+        // triggers variable expansion in a batch file and has to be escaped by doubling it.
+        format!("\"{}\"", path.to_string_lossy().replace('%', "%%"))
     }
 
     fn run_command<'a>(
@@ -341,6 +616,35 @@ impl Shell for CmdExe {
         writeln!(f, "@{}", command.into_iter().join(" "))
     }
 
+    /// Removes every occurrence of `dir` from the PATH with the string substitution of cmd.exe.
+    ///
+    /// The substitution syntax (`%PATH:old=new%`) cannot express an `old` value that contains a
+    /// `=`, so a `dir` that contains a `=` is not removed.
+    fn remove_path_entry(
+        &self,
+        f: &mut impl Write,
+        dir: &Path,
+        platform: &Platform,
+    ) -> std::fmt::Result {
+        // A substitution replaces matches in a single pass and matches cannot overlap, so two
+        // adjacent entries (`;dir;dir;`) cannot both be matched when they share a separator. To
+        // avoid this every separator is doubled and the PATH is surrounded with separators, which
+        // gives every entry its own pair of separators. Then all entries are removed, the doubled
+        // separators are collapsed again and the surrounding separators are stripped.
+        let separator = self.path_seperator(platform);
+        let dir = dir.to_string_lossy().replace('%', "%%");
+        writeln!(
+            f,
+            "@SET \"PATH={separator}%PATH:{separator}={separator}{separator}%{separator}\""
+        )?;
+        writeln!(f, "@SET \"PATH=%PATH:{separator}{dir}{separator}=%\"")?;
+        writeln!(
+            f,
+            "@IF DEFINED PATH SET \"PATH=%PATH:{separator}{separator}={separator}%\""
+        )?;
+        writeln!(f, "@IF DEFINED PATH SET \"PATH=%PATH:~1,-1%\"")
+    }
+
     fn extension(&self) -> &str {
         "bat"
     }
@@ -351,7 +655,18 @@ impl Shell for CmdExe {
 
     fn create_run_script_command(&self, path: &Path) -> Command {
         let mut cmd = Command::new(self.executable());
-        cmd.arg("/D").arg("/C").arg(path);
+        cmd.arg("/D").arg("/C");
+
+        // cmd.exe parses its command line itself so the path has to be quoted the way cmd.exe
+        // expects it instead of relying on the default argument escaping.
+        #[cfg(windows)]
+        {
+            use std::os::windows::process::CommandExt;
+            cmd.raw_arg(self.quote_command_line_path(path));
+        }
+        #[cfg(not(windows))]
+        cmd.arg(path);
+
         cmd
     }
 
@@ -359,8 +674,29 @@ impl Shell for CmdExe {
         format!("%{var_name}%")
     }
 
+    fn escape_for_echo(&self, text: &str) -> String {
+        // `ECHO` prints quotes literally, so instead of quoting the text every character that has a
+        // special meaning is escaped with a caret. `%` can only be escaped by doubling it.
+        let mut escaped = String::with_capacity(text.len());
+        for c in text.chars() {
+            match c {
+                '^' | '&' | '|' | '<' | '>' | '(' | ')' => {
+                    escaped.push('^');
+                    escaped.push(c);
+                }
+                '%' => escaped.push_str("%%"),
+                _ => escaped.push(c),
+            }
+        }
+        escaped
+    }
+
     fn echo(&self, f: &mut impl Write, text: &str) -> std::fmt::Result {
-        writeln!(f, "@ECHO {}", shlex::quote(text))
+        writeln!(f, "@ECHO {}", self.escape_for_echo(text))
+    }
+
+    fn statement_separator(&self) -> &str {
+        "&"
     }
 
     /// Emits writing all current environment variables to stdout.
@@ -369,6 +705,16 @@ impl Shell for CmdExe {
     }
 }
 
+impl CmdExe {
+    /// Quotes a path for the command line of `cmd.exe /C`. Unlike in a batch file (see
+    /// [`Shell::quote_path`]) a `%` cannot be escaped by doubling it on the command line, so it is
+    /// left as is.
+    #[cfg_attr(not(windows), allow(dead_code))]
+    fn quote_command_line_path(&self, path: &Path) -> String {
+        format!("\"{}\"", path.to_string_lossy())
+    }
+}
+
 /// A [`Shell`] implementation for PowerShell.
 #[derive(Debug, Clone, Default)]
 pub struct PowerShell {
@@ -376,16 +722,67 @@ pub struct PowerShell {
 }
 
 impl Shell for PowerShell {
+    fn guard_prefix_exists(&self, f: &mut impl Write, prefix: &Path) -> std::fmt::Result {
+        writeln!(
+            f,
+            "if (-not (Test-Path -LiteralPath {} -PathType Container)) {{ throw {} }}",
+            self.quote_path(prefix),
+            self.escape_for_echo(&missing_prefix_message(prefix))
+        )
+    }
+
+    fn guard_once(&self, f: &mut impl Write, marker: &str, body: &str) -> std::fmt::Result {
+        writeln!(f, "if (-not ${{Env:{marker}}}) {{")?;
+        write!(f, "{body}")?;
+        self.set_env_var(f, marker, "1")?;
+        writeln!(f, "}}")
+    }
+
     fn set_env_var(&self, f: &mut impl Write, env_var: &str, value: &str) -> std::fmt::Result {
         writeln!(f, "${{Env:{}}} = \"{}\"", env_var, value)
     }
 
+    fn set_env_var_if_unset(
+        &self,
+        f: &mut impl Write,
+        env_var: &str,
+        value: &str,
+    ) -> std::fmt::Result {
+        writeln!(
+            f,
+            "if (-not ${{Env:{0}}}) {{ ${{Env:{0}}} = \"{1}\" }}",
+            env_var, value
+        )
+    }
+
     fn unset_env_var(&self, f: &mut impl Write, env_var: &str) -> std::fmt::Result {
         writeln!(f, "${{Env:{}}}=\"\"", env_var)
     }
 
     fn run_script(&self, f: &mut impl Write, path: &Path) -> std::fmt::Result {
-        writeln!(f, ". \"{}\"", path.to_string_lossy())
+        writeln!(f, ". {}", self.quote_path(path))
+    }
+
+    fn quote_path(&self, path: &Path) -> String {
+        format!("'{}'", path.to_string_lossy().replace('\'', "''"))
+    }
+
+    fn escape_for_echo(&self, text: &str) -> String {
+        format!("'{}'", text.replace('\'', "''"))
+    }
+
+    fn remove_path_entry(
+        &self,
+        f: &mut impl Write,
+        dir: &Path,
+        platform: &Platform,
+    ) -> std::fmt::Result {
+        let separator = self.path_seperator(platform);
+        writeln!(
+            f,
+            "${{Env:PATH}} = (${{Env:PATH}} -split '{separator}' | Where-Object {{ $_ -ne {} }}) -join '{separator}'",
+            self.quote_path(dir)
+        )
     }
 
     fn extension(&self) -> &str {
@@ -397,6 +794,12 @@ impl Shell for PowerShell {
     }
 
     fn create_run_script_command(&self, path: &Path) -> Command {
+        let mut cmd = Command::new(self.executable());
+        cmd.arg("-NoProfile").arg(path);
+        cmd
+    }
+
+    fn create_run_script_command_with_startup_files(&self, path: &Path) -> Command {
         let mut cmd = Command::new(self.executable());
         cmd.arg(path);
         cmd
@@ -417,10 +820,35 @@ impl Shell for PowerShell {
 pub struct Fish;
 
 impl Shell for Fish {
+    fn guard_prefix_exists(&self, f: &mut impl Write, prefix: &Path) -> std::fmt::Result {
+        writeln!(
+            f,
+            "if not test -d {}; echo {} >&2; exit 1; end",
+            self.quote_path(prefix),
+            self.escape_for_echo(&missing_prefix_message(prefix))
+        )
+    }
+
+    fn guard_once(&self, f: &mut impl Write, marker: &str, body: &str) -> std::fmt::Result {
+        writeln!(f, "if not set -q {marker}")?;
+        write!(f, "{body}")?;
+        self.set_env_var(f, marker, "1")?;
+        writeln!(f, "end")
+    }
+
     fn set_env_var(&self, f: &mut impl Write, env_var: &str, value: &str) -> std::fmt::Result {
         writeln!(f, "set -gx {} \"{}\"", env_var, value)
     }
 
+    fn set_env_var_if_unset(
+        &self,
+        f: &mut impl Write,
+        env_var: &str,
+        value: &str,
+    ) -> std::fmt::Result {
+        writeln!(f, "set -q {0}; or set -gx {0} \"{1}\"", env_var, value)
+    }
+
     fn format_env_var(&self, var_name: &str) -> String {
         // Fish doesnt want the extra brackets '{}'
         format!("${var_name}")
@@ -431,13 +859,40 @@ impl Shell for Fish {
     }
 
     fn run_script(&self, f: &mut impl Write, path: &Path) -> std::fmt::Result {
-        writeln!(f, "source \"{}\"", path.to_string_lossy())
+        writeln!(f, "source {}", self.quote_path(path))
+    }
+
+    fn quote_path(&self, path: &Path) -> String {
+        format!(
+            "'{}'",
+            path.to_string_lossy()
+                .replace('\\', r"\\")
+                .replace('\'', r"\'")
+        )
+    }
+
+    fn remove_path_entry(
+        &self,
+        f: &mut impl Write,
+        dir: &Path,
+        _platform: &Platform,
+    ) -> std::fmt::Result {
+        // In fish PATH is a list, `contains -i` returns the index of the first occurrence.
+        writeln!(
+            f,
+            "while set -l __rattler_index (contains -i -- {} $PATH); set -e PATH[$__rattler_index]; end",
+            self.quote_path(dir)
+        )
     }
 
     fn extension(&self) -> &str {
         "fish"
     }
 
+    fn escape_for_echo(&self, text: &str) -> String {
+        format!("'{}'", text.replace('\\', r"\\").replace('\'', r"\'"))
+    }
+
     fn executable(&self) -> &str {
         "fish"
     }
@@ -458,17 +913,64 @@ fn escape_backslashes(s: &str) -> String {
 pub struct NuShell;
 
 impl Shell for NuShell {
+    fn guard_prefix_exists(&self, f: &mut impl Write, prefix: &Path) -> std::fmt::Result {
+        writeln!(
+            f,
+            "if not ({} | path exists) {{ error make {{msg: {}}} }}",
+            self.quote_path(prefix),
+            self.escape_for_echo(&missing_prefix_message(prefix))
+        )
+    }
+
+    fn guard_once(&self, f: &mut impl Write, marker: &str, body: &str) -> std::fmt::Result {
+        // Environment changes inside of an `if` block do not persist, so the body cannot be
+        // guarded. It is emitted as is, only the marker is set.
+        write!(f, "{body}")?;
+        self.set_env_var(f, marker, "1")
+    }
+
     fn set_env_var(&self, f: &mut impl Write, env_var: &str, value: &str) -> std::fmt::Result {
         // escape backslashes for Windows (make them double backslashes)
         writeln!(f, "$env.{} = \"{}\"", env_var, escape_backslashes(value))
     }
 
+    fn set_env_var_if_unset(
+        &self,
+        f: &mut impl Write,
+        env_var: &str,
+        value: &str,
+    ) -> std::fmt::Result {
+        // Assignments inside of an `if` block do not persist, so use `default` instead.
+        writeln!(
+            f,
+            "$env.{0} = ($env.{0}? | default \"{1}\")",
+            env_var,
+            escape_backslashes(value)
+        )
+    }
+
     fn unset_env_var(&self, f: &mut impl Write, env_var: &str) -> std::fmt::Result {
         writeln!(f, "hide-env {}", env_var)
     }
 
     fn run_script(&self, f: &mut impl Write, path: &Path) -> std::fmt::Result {
-        writeln!(f, "source \"{}\"", path.to_string_lossy())
+        writeln!(f, "source {}", self.quote_path(path))
+    }
+
+    fn quote_path(&self, path: &Path) -> String {
+        format!(
+            "\"{}\"",
+            escape_backslashes(&path.to_string_lossy()).replace('"', "\\\"")
+        )
+    }
+
+    fn escape_for_echo(&self, text: &str) -> String {
+        format!("\"{}\"", escape_backslashes(text).replace('"', "\\\""))
+    }
+
+    fn echo(&self, f: &mut impl Write, text: &str) -> std::fmt::Result {
+        // `echo` only returns its argument as a value, `print` writes it to stdout.
+        writeln!(f, "print {}", self.escape_for_echo(text))
     }
 
     fn set_path(
@@ -478,10 +980,7 @@ impl Shell for NuShell {
         modification_behavior: PathModificationBehavior,
         _platform: &Platform,
     ) -> std::fmt::Result {
-        let path = paths
-            .iter()
-            .map(|path| escape_backslashes(&format!("\"{}\"", path.to_string_lossy().into_owned())))
-            .join(", ");
+        let path = paths.iter().map(|path| self.quote_path(path)).join(", ");
 
         // Replace, Append, or Prepend the path variable to the paths.
         match modification_behavior {
@@ -497,6 +996,19 @@ impl Shell for NuShell {
         }
     }
 
+    fn remove_path_entry(
+        &self,
+        f: &mut impl Write,
+        dir: &Path,
+        _platform: &Platform,
+    ) -> std::fmt::Result {
+        writeln!(
+            f,
+            "$env.PATH = ($env.PATH | where {{|p| $p != {} }})",
+            self.quote_path(dir)
+        )
+    }
+
     fn extension(&self) -> &str {
         "nu"
     }
@@ -513,6 +1025,10 @@ impl Shell for NuShell {
 }
 
 /// A generic [`Shell`] implementation for concrete shell types.
+///
+/// Every method of [`Shell`] is delegated to the wrapped shell, so an `Activator<ShellEnum>` or
+/// `ShellScript<ShellEnum>` behaves exactly like one for the concrete shell. This allows selecting
+/// the shell at runtime.
 #[enum_dispatch]
 #[allow(missing_docs)]
 #[derive(Clone, Debug)]
@@ -543,6 +1059,22 @@ impl ShellEnum {
         parse_shell_from_path(path.as_ref())
     }
 
+    /// Determine the shell that can run a script with the given file extension (without the
+    /// leading dot). This is the inverse of [`Shell::extension`]. The comparison is case
+    /// insensitive. Since both bash and zsh scripts use the `sh` extension, `sh` maps to bash.
+    pub fn from_extension(ext: &str) -> Option<Self> {
+        match ext.to_ascii_lowercase().as_str() {
+            "sh" | "bash" => Some(Bash.into()),
+            "zsh" => Some(Zsh.into()),
+            "xsh" => Some(Xonsh.into()),
+            "bat" | "cmd" => Some(CmdExe.into()),
+            "ps1" => Some(PowerShell::default().into()),
+            "fish" => Some(Fish.into()),
+            "nu" => Some(NuShell.into()),
+            _ => None,
+        }
+    }
+
     /// Determine the user's current shell from the environment
     ///
     /// This will read the SHELL environment variable and try to determine which shell is in use
@@ -551,7 +1083,23 @@ impl ShellEnum {
     /// If SHELL is set, but contains a value that doesn't correspond to one of the supported shell
     /// types, then return `None`.
     pub fn from_env() -> Option<Self> {
-        if let Some(env_shell) = std::env::var_os("SHELL") {
+        Self::from_shell_var(std::env::var_os("SHELL"))
+    }
+
+    /// Determine the user's current shell from the environment or fall back to a default for the
+    /// current platform.
+    ///
+    /// This first tries [`ShellEnum::from_env`]. If the SHELL environment variable is not set or
+    /// refers to an unsupported shell, this returns [`Bash`] on unix-like systems and
+    /// [`PowerShell`] on Windows. Unlike [`ShellEnum::default`], which picks `cmd.exe` on Windows,
+    /// this prefers PowerShell because that is what a modern Windows terminal runs.
+    pub fn from_env_or_default() -> Self {
+        Self::from_shell_var_or_default(std::env::var_os("SHELL"))
+    }
+
+    /// Determines the shell from the value of the SHELL environment variable.
+    fn from_shell_var(env_shell: Option<OsString>) -> Option<Self> {
+        if let Some(env_shell) = env_shell {
             Self::from_shell_path(env_shell)
         } else if cfg!(windows) {
             Some(PowerShell::default().into())
@@ -560,27 +1108,27 @@ impl ShellEnum {
         }
     }
 
+    /// Determines the shell from the value of the SHELL environment variable or falls back to the
+    /// default for the current platform.
+    fn from_shell_var_or_default(env_shell: Option<OsString>) -> Self {
+        Self::from_shell_var(env_shell).unwrap_or_else(|| {
+            if cfg!(windows) {
+                PowerShell::default().into()
+            } else {
+                Bash.into()
+            }
+        })
+    }
+
     /// Guesses the current shell by checking the name of the parent process.
     #[cfg(feature = "sysinfo")]
     pub fn from_parent_process() -> Option<Self> {
-        use sysinfo::{get_current_pid, ProcessExt, SystemExt};
+        use sysinfo::ProcessExt;
 
-        let mut system_info = sysinfo::System::new();
-
-        // Get current process information
-        let mut current_pid = get_current_pid().ok()?;
-        system_info.refresh_process(current_pid);
-
-        while let Some(parent_process_id) = system_info
-            .process(current_pid)
-            .and_then(|process| process.parent())
-        {
-            // Get the name of the parent process
-            system_info.refresh_process(parent_process_id);
-            let parent_process = system_info.process(parent_process_id)?;
+        find_in_parent_processes(|parent_process| {
             let parent_process_name = parent_process.name().to_lowercase();
 
-            let shell: Option<ShellEnum> = if parent_process_name.contains("bash") {
+            if parent_process_name.contains("bash") {
                 Some(Bash.into())
             } else if parent_process_name.contains("zsh") {
                 Some(Zsh.into())
@@ -610,22 +1158,88 @@ impl ShellEnum {
                 Some(CmdExe.into())
             } else {
                 None
-            };
-
-            if let Some(shell) = shell {
-                tracing::debug!(
-                    "Guessing the current shell is {}. Parent process name: {} and args: {:?}",
-                    &shell.executable(),
-                    &parent_process_name,
-                    &parent_process.cmd()
-                );
-                return Some(shell);
             }
+        })
+    }
+
+    /// Determines the shell on Windows by walking up the chain of parent processes until a
+    /// `powershell.exe`, `pwsh.exe` or `cmd.exe` process is found.
+    ///
+    /// Windows does not set the SHELL environment variable so inspecting the parent processes is
+    /// the only reliable way to find out which shell launched the current process. Unlike
+    /// [`ShellEnum::from_parent_process`] the names of the processes are matched exactly, which
+    /// prevents unrelated processes from being mistaken for a shell. On other platforms this
+    /// always returns `None`.
+    #[cfg(feature = "sysinfo")]
+    pub fn from_windows_parent_process() -> Option<Self> {
+        use sysinfo::ProcessExt;
 
-            current_pid = parent_process_id;
+        if !cfg!(windows) {
+            return None;
         }
 
-        None
+        find_in_parent_processes(|parent_process| {
+            windows_shell_from_process_name(parent_process.name())
+        })
+    }
+}
+
+/// Walks up the chain of parent processes of the current process and returns the first shell
+/// returned by `classify`.
+#[cfg(feature = "sysinfo")]
+fn find_in_parent_processes(
+    mut classify: impl FnMut(&sysinfo::Process) -> Option<ShellEnum>,
+) -> Option<ShellEnum> {
+    use sysinfo::{get_current_pid, ProcessExt, SystemExt};
+
+    let mut system_info = sysinfo::System::new();
+
+    // Get current process information
+    let mut current_pid = get_current_pid().ok()?;
+    system_info.refresh_process(current_pid);
+
+    while let Some(parent_process_id) = system_info
+        .process(current_pid)
+        .and_then(|process| process.parent())
+    {
+        // Get the name of the parent process
+        system_info.refresh_process(parent_process_id);
+        let parent_process = system_info.process(parent_process_id)?;
+
+        if let Some(shell) = classify(parent_process) {
+            tracing::debug!(
+                "Guessing the current shell is {}. Parent process name: {} and args: {:?}",
+                &shell.executable(),
+                parent_process.name(),
+                parent_process.cmd()
+            );
+            return Some(shell);
+        }
+
+        current_pid = parent_process_id;
+    }
+
+    None
+}
+
+/// Maps the name of a Windows process (e.g. `pwsh.exe`) to the shell it runs. The comparison is
+/// case insensitive and the `.exe` extension is optional. Returns `None` if the process is not one
+/// of the Windows shells.
+#[cfg(any(test, feature = "sysinfo"))]
+fn windows_shell_from_process_name(process_name: &str) -> Option<ShellEnum> {
+    let process_name = process_name.to_ascii_lowercase();
+    let stem = process_name
+        .strip_suffix(".exe")
+        .unwrap_or(process_name.as_str());
+    match stem {
+        "powershell" | "pwsh" => Some(
+            PowerShell {
+                executable_path: Some(format!("{stem}.exe")),
+            }
+            .into(),
+        ),
+        "cmd" => Some(CmdExe.into()),
+        _ => None,
     }
 }
 
@@ -688,6 +1302,14 @@ impl<T: Shell> ShellScript<T> {
         self
     }
 
+    /// Set an environment variable only if it is not already set.
+    pub fn set_env_var_if_unset(&mut self, env_var: &str, value: &str) -> &mut Self {
+        self.shell
+            .set_env_var_if_unset(&mut self.contents, env_var, value)
+            .unwrap();
+        self
+    }
+
     /// Unset an environment variable.
     pub fn unset_env_var(&mut self, env_var: &str) -> &mut Self {
         self.shell
@@ -713,6 +1335,14 @@ impl<T: Shell> ShellScript<T> {
         self
     }
 
+    /// Remove every occurrence of `dir` from the PATH environment variable.
+    pub fn remove_path_entry(&mut self, dir: &Path) -> &mut Self {
+        self.shell
+            .remove_path_entry(&mut self.contents, dir, &self.platform)
+            .unwrap();
+        self
+    }
+
     /// Run a script in the generated shell script.
     pub fn run_script(&mut self, path: &Path) -> &mut Self {
         self.shell.run_script(&mut self.contents, path).unwrap();
@@ -738,6 +1368,128 @@ mod tests {
         insta::assert_snapshot!(script.contents);
     }
 
+    #[test]
+    fn test_set_env_var_if_unset() {
+        let shells: [ShellEnum; 7] = [
+            Bash.into(),
+            Zsh.into(),
+            Xonsh.into(),
+            CmdExe.into(),
+            PowerShell::default().into(),
+            Fish.into(),
+            NuShell.into(),
+        ];
+        let mut contents = String::new();
+        for shell in shells {
+            let mut script = ShellScript::new(shell.clone(), Platform::Linux64);
+            script.set_env_var_if_unset("PYTHONDONTWRITEBYTECODE", "1");
+            contents.push_str(&format!("{}: {}", shell.executable(), script.contents));
+        }
+        insta::assert_snapshot!(contents);
+    }
+
+    #[test]
+    fn test_remove_path_entry() {
+        let shells: [ShellEnum; 7] = [
+            Bash.into(),
+            Zsh.into(),
+            Xonsh.into(),
+            CmdExe.into(),
+            PowerShell::default().into(),
+            Fish.into(),
+            NuShell.into(),
+        ];
+        let mut contents = String::new();
+        for shell in shells {
+            let mut script = ShellScript::new(shell.clone(), Platform::Linux64);
+            script.remove_path_entry(Path::new("/opt/my env/bin"));
+            contents.push_str(&format!("{}:\n{}", shell.executable(), script.contents));
+        }
+        insta::assert_snapshot!(contents);
+    }
+
+    /// Evaluates the script written by [`CmdExe::remove_path_entry`] by applying the substitutions
+    /// of every line the way cmd.exe does: left to right without overlapping matches.
+    fn eval_cmd_exe_remove_path_entry(script: &str, path: &str) -> String {
+        let mut path = path.to_owned();
+        for line in script.lines() {
+            let (condition, statement) = match line.strip_prefix("@IF DEFINED PATH ") {
+                Some(statement) => (true, statement),
+                None => (false, line.trim_start_matches('@')),
+            };
+            if condition && path.is_empty() {
+                continue;
+            }
+            let expression = statement
+                .strip_prefix("SET \"PATH=")
+                .and_then(|s| s.strip_suffix('"'))
+                .unwrap();
+            path = if let Some(substitution) = expression
+                .strip_prefix("%PATH:")
+                .and_then(|s| s.strip_suffix('%'))
+            {
+                match substitution.split_once('=') {
+                    Some((old, new)) => path.replace(&old.replace("%%", "%"), new),
+                    None => {
+                        assert_eq!(substitution, "~1,-1");
+                        path[1..path.len() - 1].to_owned()
+                    }
+                }
+            } else {
+                let (prefix, rest) = expression.split_once("%PATH:").unwrap();
+                let (substitution, suffix) = rest.split_once('%').unwrap();
+                let (old, new) = substitution.split_once('=').unwrap();
+                format!("{prefix}{}{suffix}", path.replace(old, new))
+            };
+        }
+        path
+    }
+
+    #[test]
+    fn test_remove_path_entry_cmd_exe() {
+        let mut script = ShellScript::new(CmdExe, Platform::Win64);
+        script.remove_path_entry(Path::new(r"C:\my env\bin"));
+
+        for (path, expected) in [
+            (
+                r"C:\my env\bin;C:\my env\bin;C:\Windows;C:\my env\bin;C:\my env\bin",
+                r"C:\Windows",
+            ),
+            (
+                r"C:\Windows;C:\my env\bin;C:\my env\bin;C:\my env\bin;C:\Tools",
+                r"C:\Windows;C:\Tools",
+            ),
+            (r"C:\Windows;;C:\Tools", r"C:\Windows;;C:\Tools"),
+            (r"C:\my env\bin;C:\my env\bin", ""),
+        ] {
+            assert_eq!(
+                eval_cmd_exe_remove_path_entry(&script.contents, path),
+                expected,
+                "PATH={path}"
+            );
+        }
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_remove_path_entry_bash() {
+        let mut script = ShellScript::new(Bash, Platform::Linux64);
+        script.remove_path_entry(Path::new("/opt/my env/bin"));
+        let script = format!("{}printf %s \"$PATH\"", script.contents);
+
+        let output = Command::new("/usr/bin/env")
+            .arg("bash")
+            .arg("-c")
+            .arg(&script)
+            .env(
+                "PATH",
+                "/opt/my env/bin:/usr/bin:/opt/my env/bin:/opt/my env/bin:/bin:/opt/my env/bin",
+            )
+            .output()
+            .unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "/usr/bin:/bin");
+    }
+
     #[test]
     fn test_fish() {
         let mut script = ShellScript::new(Fish, Platform::Linux64);
@@ -777,12 +1529,110 @@ mod tests {
         println!("Detected shell: {:?}", shell);
     }
 
+    #[cfg(feature = "sysinfo")]
+    #[test]
+    fn test_from_windows_parent_process_doesnt_crash() {
+        let shell = ShellEnum::from_windows_parent_process();
+        println!("Detected shell: {:?}", shell);
+    }
+
+    #[test]
+    fn test_windows_shell_from_process_name() {
+        for (name, executable) in [
+            ("pwsh.exe", "pwsh.exe"),
+            ("PowerShell.exe", "powershell.exe"),
+            ("pwsh", "pwsh.exe"),
+        ] {
+            let shell = windows_shell_from_process_name(name);
+            assert!(
+                matches!(shell, Some(ShellEnum::PowerShell(_))),
+                "{name} should be powershell"
+            );
+            assert_eq!(shell.unwrap().executable(), executable);
+        }
+
+        assert!(matches!(
+            windows_shell_from_process_name("CMD.EXE"),
+            Some(ShellEnum::CmdExe(_))
+        ));
+
+        for name in ["explorer.exe", "nu.exe", "bash.exe", "cmdtool.exe", ""] {
+            assert!(
+                windows_shell_from_process_name(name).is_none(),
+                "{name} should not be a windows shell"
+            );
+        }
+    }
+
     #[test]
     fn test_from_env() {
         let shell = ShellEnum::from_env();
         println!("Detected shell: {:?}", shell);
     }
 
+    #[test]
+    fn test_from_extension() {
+        let shells: [ShellEnum; 6] = [
+            Bash.into(),
+            Xonsh.into(),
+            CmdExe.into(),
+            PowerShell::default().into(),
+            Fish.into(),
+            NuShell.into(),
+        ];
+        for shell in shells {
+            for ext in [
+                shell.extension().to_owned(),
+                shell.extension().to_uppercase(),
+            ] {
+                let parsed = ShellEnum::from_extension(&ext).unwrap();
+                assert_eq!(
+                    std::mem::discriminant(&parsed),
+                    std::mem::discriminant(&shell)
+                );
+            }
+        }
+
+        // Zsh shares the `sh` extension with bash
+        assert!(matches!(
+            ShellEnum::from_extension("zsh"),
+            Some(ShellEnum::Zsh(_))
+        ));
+        assert!(matches!(
+            ShellEnum::from_extension("cmd"),
+            Some(ShellEnum::CmdExe(_))
+        ));
+        assert!(ShellEnum::from_extension("exe").is_none());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_run_script_command_skips_startup_files() {
+        let path = Path::new("/tmp/activate.sh");
+        let args = |cmd: Command| {
+            cmd.get_args()
+                .map(|arg| arg.to_string_lossy().into_owned())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            args(Bash.create_run_script_command(path)),
+            vec!["--norc", "--noprofile", "/tmp/activate.sh"]
+        );
+        assert_eq!(
+            args(Bash.create_run_script_command_with_startup_files(path)),
+            vec!["/tmp/activate.sh"]
+        );
+        assert_eq!(
+            args(PowerShell::default().create_run_script_command(path)),
+            vec!["-NoProfile", "/tmp/activate.sh"]
+        );
+        assert_eq!(
+            args(ShellEnum::from(Zsh).create_run_script_command(path)),
+            vec!["--no-rcs", "/tmp/activate.sh"]
+        );
+    }
+
     #[test]
     fn test_path_seperator() {
         let mut script = ShellScript::new(Bash, Platform::Linux64);
@@ -798,6 +1648,141 @@ mod tests {
             PathModificationBehavior::Prepend,
         );
         assert!(script.contents.contains("/foo;/bar"));
+
+        // The separator only depends on the target platform
+        for platform in [Platform::NoArch, Platform::WasiWasm32, Platform::Osx64] {
+            let mut script = ShellScript::new(Bash, platform);
+            script.set_path(
+                &[PathBuf::from("/foo"), PathBuf::from("/bar")],
+                PathModificationBehavior::Replace,
+            );
+            assert_eq!(script.contents, "export PATH=\"/foo:/bar\"\n");
+        }
+        for platform in [Platform::Win64, Platform::WinArm64] {
+            let mut script = ShellScript::new(Bash, platform);
+            script.set_path(
+                &[PathBuf::from("/foo"), PathBuf::from("/bar")],
+                PathModificationBehavior::Replace,
+            );
+            assert_eq!(script.contents, "export PATH=\"/foo;/bar\"\n");
+
+            let mut script = ShellScript::new(PowerShell::default(), platform);
+            script.set_path(
+                &[PathBuf::from("C:\\foo"), PathBuf::from("C:\\bar")],
+                PathModificationBehavior::Replace,
+            );
+            assert!(script.contents.contains("C:\\foo;C:\\bar"));
+        }
+    }
+
+    #[test]
+    fn test_quote_path() {
+        let path = Path::new(r"C:\Program Files\env\it's & ^ 100%");
+        assert_eq!(
+            CmdExe.quote_path(path),
+            r#""C:\Program Files\env\it's & ^ 100%%""#
+        );
+        assert_eq!(
+            PowerShell::default().quote_path(path),
+            r"'C:\Program Files\env\it''s & ^ 100%'"
+        );
+        assert_eq!(
+            Bash.quote_path(path),
+            r"'C:\Program Files\env\it'\''s & ^ 100%'"
+        );
+        assert_eq!(
+            Fish.quote_path(path),
+            r"'C:\\Program Files\\env\\it\'s & ^ 100%'"
+        );
+        assert_eq!(
+            NuShell.quote_path(path),
+            r#""C:\\Program Files\\env\\it's & ^ 100%""#
+        );
+    }
+
+    #[test]
+    fn test_cmd_exe_quote_command_line_path() {
+        let path = Path::new(r"C:\Program Files\env\it's & ^ 100%");
+        assert_eq!(
+            CmdExe.quote_command_line_path(path),
+            r#""C:\Program Files\env\it's & ^ 100%""#
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_quote_path_roundtrip_bash() {
+        let path = Path::new("/opt/my env/it's & \"quoted\" $HOME");
+        let output = Command::new("bash")
+            .arg("-c")
+            .arg(format!("printf %s {}", Bash.quote_path(path)))
+            .output()
+            .unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            path.to_str().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_escape_for_echo() {
+        let text = r#"it's "$HOME" & 100% \o/"#;
+        assert_eq!(CmdExe.escape_for_echo(text), r#"it's "$HOME" ^& 100%% \o/"#);
+        assert_eq!(
+            PowerShell::default().escape_for_echo(text),
+            r#"'it''s "$HOME" & 100% \o/'"#
+        );
+        assert_eq!(
+            Bash.escape_for_echo(text),
+            r#"'it'\''s "$HOME" & 100% \o/'"#
+        );
+        assert_eq!(Fish.escape_for_echo(text), r#"'it\'s "$HOME" & 100% \\o/'"#);
+        assert_eq!(
+            NuShell.escape_for_echo(text),
+            r#""it's \"$HOME\" & 100% \\o/""#
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_echo_roundtrip_bash() {
+        let text = "<=== it's \"$HOME\" `pwd` \\n * ===>";
+        let mut script = String::new();
+        Bash.echo(&mut script, text).unwrap();
+        let output = Command::new("bash")
+            .arg("-c")
+            .arg(&script)
+            .output()
+            .unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout), format!("{text}\n"));
+    }
+
+    #[test]
+    fn test_from_env_or_default() {
+        // Without a (supported) SHELL the platform default is used
+        for env_shell in [None, Some("/usr/bin/unknown-shell".into())] {
+            let shell = ShellEnum::from_shell_var_or_default(env_shell);
+            if cfg!(windows) {
+                assert!(matches!(shell, ShellEnum::PowerShell(_)));
+            } else {
+                assert!(matches!(shell, ShellEnum::Bash(_)));
+            }
+        }
+
+        let shell = ShellEnum::from_shell_var_or_default(Some("/usr/bin/zsh".into()));
+        assert!(matches!(shell, ShellEnum::Zsh(_)));
+    }
+
+    #[test]
+    fn test_chain_commands() {
+        assert_eq!(
+            Bash.chain_commands(["export FOO=\"bar\"\n", "", "unset BAZ"]),
+            "export FOO=\"bar\"; unset BAZ"
+        );
+        assert_eq!(
+            CmdExe.chain_commands(["@SET \"FOO=bar\"", "@SET BAZ="]),
+            "@SET \"FOO=bar\"& @SET BAZ="
+        );
     }
 
     #[test]
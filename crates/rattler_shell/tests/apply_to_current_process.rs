@@ -0,0 +1,47 @@
+//! This test modifies the environment of the process, it lives in its own test binary so it cannot
+//! interfere with other tests that read the environment.
+
+use rattler_conda_types::Platform;
+use rattler_shell::activation::{ActivationVariables, Activator, PathModificationBehavior};
+use rattler_shell::shell::ShellEnum;
+use std::fs;
+use std::path::PathBuf;
+
+#[test]
+fn test_apply_to_current_process() {
+    let prefix = tempfile::TempDir::new().unwrap();
+    let env_vars_d = prefix.path().join("etc/conda/env_vars.d");
+    fs::create_dir_all(&env_vars_d).unwrap();
+    fs::write(
+        env_vars_d.join("pkg1.json"),
+        r#"{"RATTLER_TEST_APPLIED": "yes"}"#,
+    )
+    .unwrap();
+
+    let activator =
+        Activator::from_path(prefix.path(), ShellEnum::default(), Platform::current()).unwrap();
+    let current_path = std::env::var_os("PATH")
+        .map(|path| std::env::split_paths(&path).collect::<Vec<_>>())
+        .unwrap_or_default();
+    let result = activator
+        .activation(ActivationVariables {
+            path: Some(current_path.clone()),
+            path_modification_behavior: PathModificationBehavior::Prepend,
+            ..ActivationVariables::default()
+        })
+        .unwrap();
+
+    result.apply_to_current_process().unwrap();
+
+    assert_eq!(
+        std::env::var_os("CONDA_PREFIX")
+            .map(PathBuf::from)
+            .as_deref(),
+        Some(prefix.path())
+    );
+    assert_eq!(std::env::var("RATTLER_TEST_APPLIED").as_deref(), Ok("yes"));
+    let path = std::env::split_paths(&std::env::var_os("PATH").unwrap()).collect::<Vec<_>>();
+    assert_eq!(path, result.path);
+    assert_eq!(path[..activator.paths.len()], activator.paths[..]);
+    assert!(path.ends_with(&current_path));
+}
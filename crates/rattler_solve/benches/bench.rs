@@ -1,5 +1,5 @@
 use criterion::{black_box, criterion_group, criterion_main, Criterion, SamplingMode};
-use rattler_conda_types::{Channel, ChannelConfig, MatchSpec};
+use rattler_conda_types::{Channel, ChannelConfig, MatchSpec, Subdir};
 use rattler_repodata_gateway::sparse::SparseRepoData;
 use rattler_solve::{SolverImpl, SolverTask};
 use std::str::FromStr;
@@ -21,9 +21,15 @@ fn conda_json_path_noarch() -> String {
 }
 
 fn read_sparse_repodata(path: &str) -> SparseRepoData {
+    // The subdir is the name of the directory that contains the repodata
+    let subdir = std::path::Path::new(path)
+        .parent()
+        .and_then(|dir| dir.file_name())
+        .and_then(|name| name.to_str())
+        .unwrap();
     SparseRepoData::new(
         Channel::from_str("dummy", &ChannelConfig::default()).unwrap(),
-        "dummy".to_string(),
+        Subdir::from_str(subdir).unwrap(),
         path,
         None,
     )
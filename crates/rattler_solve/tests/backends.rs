@@ -1,7 +1,7 @@
 use once_cell::sync::Lazy;
 use rattler_conda_types::{
-    Channel, ChannelConfig, GenericVirtualPackage, MatchSpec, NoArchType, PackageRecord, RepoData,
-    RepoDataRecord, Version,
+    Channel, ChannelConfig, GenericVirtualPackage, MatchSpec, NoArchType, PackageRecord, Platform,
+    RepoData, RepoDataRecord, Subdir, Version,
 };
 use rattler_repodata_gateway::sparse::SparseRepoData;
 use rattler_solve::{SolveError, SolverImpl, SolverTask};
@@ -62,9 +62,15 @@ fn read_repodata(path: &str) -> Vec<RepoDataRecord> {
 }
 
 fn read_sparse_repodata(path: &str) -> SparseRepoData {
+    // The subdir is the name of the directory that contains the repodata
+    let subdir = std::path::Path::new(path)
+        .parent()
+        .and_then(|dir| dir.file_name())
+        .and_then(|name| name.to_str())
+        .unwrap();
     SparseRepoData::new(
         Channel::from_str("dummy", &ChannelConfig::default()).unwrap(),
-        "dummy".to_string(),
+        Subdir::from_str(subdir).unwrap(),
         path,
         None,
     )
@@ -175,7 +181,7 @@ fn read_pytorch_sparse_repo_data() -> &'static SparseRepoData {
         let pytorch = pytorch_json_path();
         SparseRepoData::new(
             Channel::from_str("pytorch", &ChannelConfig::default()).unwrap(),
-            "pytorch".to_string(),
+            Platform::Linux64,
             pytorch,
             None,
         )
@@ -190,7 +196,7 @@ fn read_conda_forge_sparse_repo_data() -> &'static SparseRepoData {
         let conda_forge = conda_json_path();
         SparseRepoData::new(
             Channel::from_str("conda-forge", &ChannelConfig::default()).unwrap(),
-            "conda-forge".to_string(),
+            Platform::Linux64,
             conda_forge,
             None,
         )
@@ -5,7 +5,7 @@ use pyo3::{create_exception, PyErr};
 use rattler::install::TransactionError;
 use rattler_conda_types::{
     ConvertSubdirError, InvalidPackageNameError, ParseArchError, ParseChannelError,
-    ParseMatchSpecError, ParsePlatformError, ParseVersionError,
+    ParseMatchSpecError, ParsePlatformError, ParseSubdirError, ParseVersionError,
 };
 use rattler_repodata_gateway::fetch::FetchRepoDataError;
 use rattler_shell::activation::ActivationError;
@@ -31,6 +31,8 @@ pub enum PyRattlerError {
     #[error(transparent)]
     ParsePlatformError(#[from] ParsePlatformError),
     #[error(transparent)]
+    ParseSubdirError(#[from] ParseSubdirError),
+    #[error(transparent)]
     ParseArchError(#[from] ParseArchError),
     #[error(transparent)]
     FetchRepoDataError(#[from] FetchRepoDataError),
@@ -70,6 +72,9 @@ impl From<PyRattlerError> for PyErr {
             PyRattlerError::ParsePlatformError(err) => {
                 ParsePlatformException::new_err(err.to_string())
             }
+            PyRattlerError::ParseSubdirError(err) => {
+                ParsePlatformException::new_err(err.to_string())
+            }
             PyRattlerError::ParseArchError(err) => ParseArchException::new_err(err.to_string()),
             PyRattlerError::FetchRepoDataError(err) => {
                 FetchRepoDataException::new_err(err.to_string())
@@ -31,7 +31,7 @@ pub fn py_fetch_repo_data<'a>(
     let mut meta_futures = Vec::new();
     let client = PyAuthenticatedClient::new();
 
-    for (subdir, chan) in get_subdir_urls(channels, platforms)? {
+    for (subdir, chan, platform) in get_subdir_urls(channels, platforms)? {
         let progress = if let Some(callback) = callback {
             let callback = callback.to_object(py);
             Some(get_progress_func(callback))
@@ -53,7 +53,8 @@ pub fn py_fetch_repo_data<'a>(
                 )
                 .await?,
                 chan,
-            )) as Result<(CachedRepoData, PyChannel), FetchRepoDataError>
+                platform,
+            )) as Result<(CachedRepoData, PyChannel, PyPlatform), FetchRepoDataError>
         });
     }
 
@@ -62,9 +63,12 @@ pub fn py_fetch_repo_data<'a>(
         match try_join_all(meta_futures).await {
             Ok(res) => res
                 .into_iter()
-                .map(|(cache, chan)| {
-                    let path = cache_path.to_string_lossy().into_owned();
-                    PySparseRepoData::new(chan, path, cache.repo_data_json_path)
+                .map(|(cache, chan, platform)| {
+                    PySparseRepoData::new(
+                        chan,
+                        platform.inner.to_string(),
+                        cache.repo_data_json_path,
+                    )
                 })
                 .collect::<Result<Vec<_>, _>>(),
             Err(e) => Err(PyRattlerError::from(e).into()),
@@ -86,7 +90,7 @@ fn get_progress_func(callback: Py<PyAny>) -> Box<dyn FnMut(DownloadProgress) + S
 fn get_subdir_urls(
     channels: Vec<PyChannel>,
     platforms: Vec<PyPlatform>,
-) -> PyResult<Vec<(Url, PyChannel)>> {
+) -> PyResult<Vec<(Url, PyChannel, PyPlatform)>> {
     let mut urls = Vec::new();
 
     for c in channels {
@@ -95,6 +99,7 @@ fn get_subdir_urls(
             urls.push((
                 Url::from_str(r.as_str()).map_err(PyRattlerError::from)?,
                 c.clone(),
+                p.clone(),
             ));
         }
     }
@@ -1,10 +1,12 @@
-use std::{path::PathBuf, sync::Arc};
+use std::{path::PathBuf, str::FromStr, sync::Arc};
 
 use pyo3::{pyclass, pymethods, PyResult, Python};
 
+use rattler_conda_types::Subdir;
 use rattler_repodata_gateway::sparse::SparseRepoData;
 
 use crate::channel::PyChannel;
+use crate::error::PyRattlerError;
 use crate::package_name::PyPackageName;
 use crate::record::PyRecord;
 
@@ -33,6 +35,7 @@ impl<'a> From<&'a PySparseRepoData> for &'a SparseRepoData {
 impl PySparseRepoData {
     #[new]
     pub fn new(channel: PyChannel, subdir: String, path: PathBuf) -> PyResult<Self> {
+        let subdir = Subdir::from_str(&subdir).map_err(PyRattlerError::from)?;
         Ok(SparseRepoData::new(channel.into(), subdir, path, None)?.into())
     }
 
@@ -52,6 +52,9 @@ impl PyActivationVariables {
             conda_prefix,
             path,
             path_modification_behavior: path_modification_behavior.0,
+            prompt_name: None,
+            additive_env_vars: Default::default(),
+            annotate_env_vars: false,
         };
         activation_vars.into()
     }